@@ -0,0 +1,73 @@
+//! Checks the storage directory of a stopped process, and optionally repairs
+//! it. Exits with 0 if no problems remain, 1 if some do, and 2 on errors.
+use assignment_2_solution::{check_storage, SectorState, StorageReport};
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+const USAGE: &str = "\
+Usage:
+    atdd-fsck <storage_dir> [--repair]
+
+Options:
+    --repair  remove leftovers of interrupted writes and inconsistent indexes";
+
+fn print_report(report: &StorageReport) {
+    println!("sectors:");
+    for sector in &report.sectors {
+        let state = match sector.state {
+            SectorState::Ok => "ok",
+            SectorState::Unverified => "unverified",
+            SectorState::Corrupted => "CORRUPTED",
+        };
+        println!(
+            "    {:>10}  ts {:>10}  rank {:>3}  {}",
+            sector.idx, sector.logical_timestamp, sector.write_rank, state
+        );
+    }
+    println!("rids:");
+    for (worker_id, rid) in &report.rids {
+        match rid {
+            Some(rid) => println!("    worker {:#04x}  {}", worker_id, rid),
+            None => println!("    worker {:#04x}  -", worker_id),
+        }
+    }
+    for problem in &report.repaired {
+        println!("repaired: {}", problem);
+    }
+    for problem in &report.problems {
+        println!("problem: {}", problem);
+    }
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let mut paths = vec![];
+    let mut repair = false;
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "--repair" => repair = true,
+            _ => paths.push(PathBuf::from(arg)),
+        }
+    }
+    let storage_dir = match paths.pop() {
+        Some(storage_dir) if paths.is_empty() && storage_dir.is_dir() => storage_dir,
+        _ => {
+            eprintln!("{}", USAGE);
+            return ExitCode::from(2);
+        }
+    };
+    match check_storage(storage_dir, repair).await {
+        Ok(report) => {
+            print_report(&report);
+            if report.problems.is_empty() {
+                ExitCode::SUCCESS
+            } else {
+                ExitCode::FAILURE
+            }
+        }
+        Err(err) => {
+            eprintln!("atdd-fsck: {}", err);
+            ExitCode::from(2)
+        }
+    }
+}
@@ -0,0 +1,112 @@
+//! Exports the sectors of a stopped process to an image, or imports an image
+//! into the storage directory of a new process.
+use assignment_2_solution::{
+    build_sectors_manager_with_config, export_image, import_image, ImageFormat, SectorsBackend,
+    StorageConfiguration,
+};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use tokio::fs::File;
+use tokio::io::{BufReader, BufWriter};
+
+const USAGE: &str = "\
+Usage:
+    atdd-image export <storage_dir> <n_sectors> <image> [--raw] [--data-file]
+    atdd-image import <image> <storage_dir> <n_sectors> [--raw] [--data-file]
+
+Options:
+    --raw        the image holds plain sector contents, without metadata
+    --data-file  the storage uses the data file backend";
+
+struct Args {
+    command: String,
+    paths: Vec<String>,
+    format: ImageFormat,
+    config: StorageConfiguration,
+}
+
+fn parse_args() -> Option<Args> {
+    let mut args = Args {
+        command: String::new(),
+        paths: vec![],
+        format: ImageFormat::Archive,
+        config: StorageConfiguration::default(),
+    };
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "--raw" => args.format = ImageFormat::Raw,
+            "--data-file" => args.config.sectors_backend = SectorsBackend::DataFile,
+            _ if arg.starts_with("--") => return None,
+            _ if args.command.is_empty() => args.command = arg,
+            _ => args.paths.push(arg),
+        }
+    }
+    (args.paths.len() == 3).then_some(args)
+}
+
+async fn run(args: Args) -> Result<u64, String> {
+    match args.command.as_str() {
+        "export" => {
+            let storage_dir = PathBuf::from(&args.paths[0]);
+            let n_sectors = args.paths[1].parse().map_err(|_| "invalid n_sectors")?;
+            let sectors_dir = storage_dir.join("sectors_manager");
+            if !sectors_dir.is_dir() {
+                return Err(format!("{} doesn't exist", sectors_dir.display()));
+            }
+            let sectors_manager =
+                build_sectors_manager_with_config(sectors_dir, n_sectors, &args.config)
+                    .await
+                    .map_err(|err| err.to_string())?;
+            let file = File::create(&args.paths[2])
+                .await
+                .map_err(|err| err.to_string())?;
+            let mut writer = BufWriter::new(file);
+            export_image(
+                sectors_manager.as_ref(),
+                n_sectors,
+                args.format,
+                &mut writer,
+            )
+            .await
+            .map_err(|err| err.to_string())
+        }
+        "import" => {
+            let file = File::open(&args.paths[0])
+                .await
+                .map_err(|err| err.to_string())?;
+            let n_sectors = args.paths[2].parse().map_err(|_| "invalid n_sectors")?;
+            import_image(
+                PathBuf::from(&args.paths[1]),
+                n_sectors,
+                &args.config,
+                args.format,
+                &mut BufReader::new(file),
+            )
+            .await
+            .map_err(|err| err.to_string())
+        }
+        _ => Err(USAGE.to_string()),
+    }
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let args = match parse_args() {
+        Some(args) => args,
+        None => {
+            eprintln!("{}", USAGE);
+            return ExitCode::FAILURE;
+        }
+    };
+    let command = args.command.clone();
+    match run(args).await {
+        Ok(sectors) => {
+            eprintln!("{}ed {} sectors", command, sectors);
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            eprintln!("atdd-image: {}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
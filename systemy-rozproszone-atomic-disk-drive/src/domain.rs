@@ -1,5 +1,7 @@
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 use uuid::Uuid;
 
 pub static MAGIC_NUMBER: [u8; 4] = [0x61, 0x74, 0x64, 0x64];
@@ -10,6 +12,10 @@ pub struct Configuration {
     pub hmac_system_key: [u8; 64],
     /// Hmac key to verify client requests.
     pub hmac_client_key: [u8; 32],
+    /// Key to encrypt sectors at rest with, `None` keeps them in plaintext.
+    /// Needs `SectorsBackend::FilePerSector`, and can't be changed once
+    /// sectors are stored. Kept history reveals when sectors were written.
+    pub sectors_key: Option<[u8; 32]>,
     /// Part of configuration which is safe to share with external world.
     pub public: PublicConfiguration,
 }
@@ -18,14 +24,252 @@ pub struct Configuration {
 pub struct PublicConfiguration {
     /// Storage for durable data.
     pub storage_dir: PathBuf,
-    /// Host and port, indexed by identifiers, of every process, including itself
-    /// (subtract 1 from self_rank to obtain index in this array).
+    /// Address, indexed by identifiers, of every process, including itself
+    /// (subtract 1 from self_rank to obtain index in this array). Despite
+    /// the name, an address can also be a Unix socket path, host and port
+    /// pairs convert into `Location` with `From`.
     /// You can assume that `tcp_locations.len() < 255`.
-    pub tcp_locations: Vec<(String, u16)>,
+    pub tcp_locations: Vec<Location>,
     /// Identifier of this process. Identifiers start at 1.
     pub self_rank: u8,
     /// The number of sectors. The range of supported sectors is <0, `n_sectors`).
     pub n_sectors: u64,
+    /// Tuning of the communication with other processes. Breaking change:
+    /// configurations written before the field existed need
+    /// `network: NetworkConfiguration::default()` to keep their behaviour.
+    pub network: NetworkConfiguration,
+    /// How sectors are laid out in `storage_dir`.
+    pub storage: StorageConfiguration,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct StorageConfiguration {
+    pub sectors_backend: SectorsBackend,
+    /// With `SectorsBackend::FilePerSector`, writes are made durable in
+    /// batches. After a write starts a batch, others are awaited that long.
+    /// Even without waiting, writes started while a batch is being made
+    /// durable form the next one.
+    pub group_commit_window: Duration,
+    /// Maximal number of sectors whose content is cached in memory, the
+    /// least recently used are evicted first. Zero disables the cache.
+    pub read_cache_sectors: usize,
+    /// With `SectorsBackend::FilePerSector`, how often all directories are
+    /// searched for files left by failed writes, which are removed. Writes
+    /// wait while it happens. Zero disables the periodic collection, startup
+    /// then removes only leftovers of writes logged in the sectors index.
+    pub gc_interval: Duration,
+    /// With `SectorsBackend::FilePerSector`, directories, typically on
+    /// separate disks, across which sector files are striped. Empty means
+    /// `storage_dir` only. Stable storage and the sectors index stay in
+    /// `storage_dir` either way. The list can't change once sectors are stored.
+    pub data_dirs: Vec<PathBuf>,
+    /// With `SectorsBackend::FilePerSector`, how sectors are compressed when
+    /// written. Can't be combined with encryption.
+    pub compression: SectorsCompression,
+    /// With `SectorsBackend::FilePerSector`, how many superseded versions of
+    /// every sector are kept, so they can be read with `ReadAt`.
+    pub history_versions: usize,
+    /// With `SectorsBackend::FilePerSector`, superseded versions written
+    /// within that long are kept as well, even beyond `history_versions`.
+    /// Older ones are removed when the sector is written again, or garbage
+    /// is collected.
+    pub history_retention: Duration,
+}
+
+/// Compression of sector files. Sectors which don't compress below a sector
+/// are stored as they are, and any sector can be read whatever is chosen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SectorsCompression {
+    #[default]
+    None,
+    Lz4,
+}
+
+/// On-disk layout of sectors. A process must be restarted with the backend
+/// which has written its data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SectorsBackend {
+    /// Every sector is a separate file, named after its index and metadata.
+    #[default]
+    FilePerSector,
+    /// All sectors live at fixed offsets of a single data file, sized for
+    /// `n_sectors` up front. Metadata is kept in a separate region, and a
+    /// write-ahead log makes writes safe against torn writes.
+    DataFile,
+}
+
+/// Address at which a process accepts connections, from both clients and
+/// other processes.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Location {
+    /// Host and port.
+    Tcp(String, u16),
+    /// Path of a Unix domain socket, for processes sharing a host.
+    Unix(PathBuf),
+}
+
+impl From<(String, u16)> for Location {
+    fn from((host, port): (String, u16)) -> Self {
+        Location::Tcp(host, port)
+    }
+}
+
+impl From<(&str, u16)> for Location {
+    fn from((host, port): (&str, u16)) -> Self {
+        Location::Tcp(host.to_string(), port)
+    }
+}
+
+impl From<PathBuf> for Location {
+    fn from(path: PathBuf) -> Self {
+        Location::Unix(path)
+    }
+}
+
+impl std::fmt::Display for Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Location::Tcp(host, port) => write!(f, "{}:{}", host, port),
+            Location::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct NetworkConfiguration {
+    /// Maximal number of messages waiting to be sent to a single process,
+    /// positive. When the queue is full, messages are shed rather than
+    /// senders made to wait (see `PeerQueueStats`).
+    pub peer_queue_capacity: usize,
+    /// Delay before the first retransmission of an unanswered proc message.
+    pub resend_interval: Duration,
+    /// Upper bound of the delay between retransmissions.
+    pub max_resend_interval: Duration,
+    /// Factor by which the delay grows after every retransmission, finite
+    /// and at least 1.
+    pub resend_backoff: f64,
+    /// How often a heartbeat is sent to every other process.
+    pub heartbeat_interval: Duration,
+    /// Suspicion level (phi) above which a process is considered crashed.
+    pub phi_threshold: f64,
+    /// How often a digest of a range of sectors is sent to another process,
+    /// so that sectors which missed writes get updated in the background.
+    /// `None` disables it, digests of other processes are answered anyway.
+    pub anti_entropy_interval: Option<Duration>,
+    /// Number of sectors covered by a single digest.
+    pub anti_entropy_range_len: u64,
+    /// Maximal number of sectors being fetched from other processes at once.
+    pub anti_entropy_max_pulls: usize,
+}
+
+impl Default for NetworkConfiguration {
+    fn default() -> Self {
+        NetworkConfiguration {
+            peer_queue_capacity: 1024,
+            resend_interval: Duration::from_millis(500),
+            max_resend_interval: Duration::from_secs(5),
+            resend_backoff: 2.0,
+            heartbeat_interval: Duration::from_millis(100),
+            phi_threshold: 8.0,
+            anti_entropy_interval: Some(Duration::from_secs(1)),
+            anti_entropy_range_len: 256,
+            anti_entropy_max_pulls: 16,
+        }
+    }
+}
+
+/// Format of an image of the sectors of a process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ImageFormat {
+    /// Written sectors along with their metadata, so the image can be
+    /// imported into a process which takes part in the same register.
+    #[default]
+    Archive,
+    /// Content of every sector, one after another, like a block device.
+    /// Metadata is lost, imported sectors replace nothing written later.
+    Raw,
+}
+
+/// What `check_storage` has found in the storage directory of a process.
+#[derive(Debug, Clone, Default)]
+pub struct StorageReport {
+    /// Current version of every stored sector, by index.
+    pub sectors: Vec<SectorReport>,
+    /// Last read identifier of every worker with stable storage, by worker,
+    /// `None` if the worker has none stored.
+    pub rids: Vec<(u8, Option<u64>)>,
+    /// Problems left in the storage.
+    pub problems: Vec<StorageProblem>,
+    /// Problems which have been repaired.
+    pub repaired: Vec<StorageProblem>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SectorReport {
+    pub idx: SectorIdx,
+    pub logical_timestamp: u64,
+    pub write_rank: u8,
+    pub state: SectorState,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SectorState {
+    /// Data matches its checksum.
+    Ok,
+    /// Written before checksums were introduced, so it can't be verified.
+    /// Only found in storage which hasn't been upgraded yet.
+    Unverified,
+    /// Data doesn't match its checksum, or has a wrong size.
+    Corrupted,
+}
+
+/// Inconsistency of the storage directory of a process. Other than
+/// `CorruptedSector` and `UnknownFile`, those are fixed by the process on
+/// startup as well.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StorageProblem {
+    /// An older version of a sector, left by an interrupted write.
+    StaleVersion {
+        idx: SectorIdx,
+        logical_timestamp: u64,
+        write_rank: u8,
+    },
+    /// A temporary file left by an interrupted write.
+    LeftoverTmpfile(PathBuf),
+    /// The index of sectors can't be read, or doesn't match sector files.
+    InconsistentIndex,
+    /// The last read identifier of the worker can't be read. The worker
+    /// starts over from zero.
+    CorruptedRid(u8),
+    /// A sector whose data can be repaired only from other processes.
+    CorruptedSector(SectorIdx),
+    /// A file which doesn't belong to the storage.
+    UnknownFile(PathBuf),
+}
+
+impl std::fmt::Display for StorageProblem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StorageProblem::StaleVersion {
+                idx,
+                logical_timestamp,
+                write_rank,
+            } => write!(
+                f,
+                "stale version ({}, {}) of sector {}",
+                logical_timestamp, write_rank, idx
+            ),
+            StorageProblem::LeftoverTmpfile(path) => {
+                write!(f, "leftover temporary file {}", path.display())
+            }
+            StorageProblem::InconsistentIndex => write!(f, "inconsistent sectors index"),
+            StorageProblem::CorruptedRid(worker_id) => {
+                write!(f, "corrupted rid of worker {:#04x}", worker_id)
+            }
+            StorageProblem::CorruptedSector(idx) => write!(f, "sector {} is corrupted", idx),
+            StorageProblem::UnknownFile(path) => write!(f, "unknown file {}", path.display()),
+        }
+    }
 }
 
 #[derive(Clone, Eq, PartialEq, Debug)]
@@ -50,6 +294,58 @@ pub enum StatusCode {
     AuthFailure,
     /// Sector index is out of range <0, Configuration.n_sectors)
     InvalidSectorIndex,
+    /// The storage of the process failed, see `StorageError`
+    StorageError,
+    /// `ReadAt` asked for a version the process no longer keeps
+    VersionNotKept,
+}
+
+/// Failure of an operation of `SectorsManager` or `StableStorage`.
+#[derive(Debug, Clone)]
+pub enum StorageError {
+    /// Stored data doesn't match its checksum, e.g. because of bit rot.
+    Corrupted(SectorIdx),
+    /// The underlying I/O failed, e.g. because the disk is full.
+    Io(Arc<std::io::Error>),
+    /// The arguments can't be stored, e.g. a key of stable storage is too long.
+    InvalidInput(&'static str),
+    /// The storage directory was written in a layout this version doesn't
+    /// know, e.g. by a newer one.
+    UnsupportedFormat(u32),
+    /// The requested version of the sector was superseded and is no longer
+    /// kept, see `StorageConfiguration::history_versions`.
+    VersionNotKept(SectorIdx),
+}
+
+impl From<std::io::Error> for StorageError {
+    fn from(err: std::io::Error) -> Self {
+        StorageError::Io(Arc::new(err))
+    }
+}
+
+impl std::fmt::Display for StorageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StorageError::Corrupted(idx) => write!(f, "sector {} is corrupted", idx),
+            StorageError::Io(err) => write!(f, "storage I/O failed: {}", err),
+            StorageError::InvalidInput(reason) => write!(f, "invalid input: {}", reason),
+            StorageError::UnsupportedFormat(version) => {
+                write!(f, "unsupported storage format version {}", version)
+            }
+            StorageError::VersionNotKept(idx) => {
+                write!(f, "version of sector {} is no longer kept", idx)
+            }
+        }
+    }
+}
+
+impl std::error::Error for StorageError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            StorageError::Io(err) => Some(&**err),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
@@ -64,7 +360,11 @@ pub struct SystemRegisterCommand {
     pub content: SystemRegisterCommandContent,
 }
 
+/// Besides the messages of the register protocol, processes exchange
+/// messages of their own, which may be extended, so matching on the content
+/// needs a wildcard arm.
 #[derive(Debug, Clone, Eq, PartialEq)]
+#[non_exhaustive]
 pub enum SystemRegisterCommandContent {
     ReadProc,
     Value {
@@ -78,12 +378,41 @@ pub enum SystemRegisterCommandContent {
         data_to_write: SectorVec,
     },
     Ack,
+    /// Sent periodically to every other process, so it can tell we are alive,
+    /// with a nil `msg_ident`. Never delivered to the register.
+    Heartbeat,
+    /// Digest of metadata of the sender's sectors from `header.sector_idx`
+    /// on, used by anti-entropy. Never delivered to the register.
+    RangeDigest {
+        range_len: u64,
+        digest: u64,
+    },
+    /// Answer to a `RangeDigest` differing from the local one: `(sector_idx,
+    /// timestamp, write_rank)` of every written sector of the range.
+    /// Never delivered to the register.
+    RangeVersions {
+        versions: Vec<(SectorIdx, u64, u8)>,
+    },
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub enum ClientRegisterCommandContent {
     Read,
-    Write { data: SectorVec },
+    Write {
+        data: SectorVec,
+    },
+    /// Reads the newest version of the sector with a logical timestamp not
+    /// greater than `timestamp`, among the versions kept by the process
+    /// which receives it. Fails with `StatusCode::VersionNotKept` if that
+    /// version was already removed.
+    ///
+    /// Unlike `Read`, it is served from local storage only, without a quorum,
+    /// so it isn't linearizable. The result may be stale, as the process may
+    /// have missed later writes, or hold a version of a write which never
+    /// completed and isn't stored by a majority.
+    ReadAt {
+        timestamp: u64,
+    },
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Copy, Serialize, Deserialize)]
@@ -110,9 +439,61 @@ pub struct OperationSuccess {
 pub enum OperationReturn {
     Read(ReadReturn),
     Write,
+    ReadAt(ReadReturn),
 }
 
 #[derive(Debug, Clone)]
 pub struct ReadReturn {
     pub read_data: SectorVec,
 }
+
+#[derive(Debug, Clone, PartialEq)]
+/// What the failure detector thinks about a single process.
+pub struct PeerLiveness {
+    /// Identifier of the process.
+    pub process_identifier: u8,
+    /// Current suspicion level, grows the longer the process is silent.
+    pub phi: f64,
+    /// Whether the process is considered crashed.
+    pub suspected: bool,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+/// Snapshot of the queue of messages waiting to be sent to a single process.
+pub struct PeerQueueStats {
+    /// Identifier of the target process.
+    pub process_identifier: u8,
+    /// Number of messages currently waiting in the queue.
+    pub queued: usize,
+    /// Maximal number of messages the queue can hold.
+    pub capacity: usize,
+    /// Number of messages replaced by a newer message with the same `msg_ident`.
+    pub collapsed: u64,
+    /// Number of messages dropped because the queue was full.
+    pub shed: u64,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+/// Counters of the removal of files no longer referenced by a sectors manager,
+/// at startup and periodically.
+pub struct SectorsGcStats {
+    /// Number of completed collections.
+    pub runs: u64,
+    /// Number of removed files.
+    pub removed_files: u64,
+    /// Total size of the removed files.
+    pub reclaimed_bytes: u64,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+/// Counters of the cache of sector contents of a sectors manager.
+pub struct SectorCacheStats {
+    /// Number of reads served from the cache.
+    pub hits: u64,
+    /// Number of reads of written sectors which had to go to the storage.
+    pub misses: u64,
+    /// Number of sectors currently cached.
+    pub cached: usize,
+    /// Maximal number of sectors the cache can hold.
+    pub capacity: usize,
+}
@@ -3,20 +3,44 @@ mod domain;
 mod solution;
 
 pub use crate::domain::*;
+pub use crate::solution::running::{RegisterProcessHandle, RegisterProcessOptions};
 pub use atomic_register_public::*;
+pub use format_public::*;
+pub use fsck_public::*;
+pub use image_public::*;
 pub use register_client_public::*;
 pub use sectors_manager_public::*;
 pub use stable_storage_public::*;
 pub use transfer_public::*;
+pub use transport_public::*;
 
 pub async fn run_register_process(config: Configuration) {
     crate::solution::running::run_register_process(config).await
 }
 
+/// Like `run_register_process`, but returns as soon as the process is ready to
+/// accept connections. The returned handle can be used to inspect the process.
+/// Fails if the configuration is invalid, or the location of the process
+/// can't be bound or its storage opened.
+pub async fn start_register_process(
+    config: Configuration,
+) -> Result<RegisterProcessHandle, StorageError> {
+    crate::solution::running::start_register_process(config).await
+}
+
+/// Like `start_register_process`, but lets the caller replace parts of the
+/// process, e.g. the transport used to communicate.
+pub async fn start_register_process_with_options(
+    config: Configuration,
+    options: RegisterProcessOptions,
+) -> Result<RegisterProcessHandle, StorageError> {
+    crate::solution::running::start_register_process_with_options(config, options).await
+}
+
 pub mod atomic_register_public {
     use crate::{
         ClientRegisterCommand, OperationSuccess, RegisterClient, SectorsManager, StableStorage,
-        SystemRegisterCommand,
+        StorageError, SystemRegisterCommand,
     };
     use std::future::Future;
     use std::pin::Pin;
@@ -27,14 +51,17 @@ pub mod atomic_register_public {
         /// Handle a client command. After the command is completed, we expect
         /// callback to be called. Note that completion of client command happens after
         /// delivery of multiple system commands to the register, as the algorithm specifies.
+        /// If the storage of the process fails, the callback gets the error instead.
         ///
         /// This function corresponds to the handlers of Read and Write events in the
         /// (N,N)-AtomicRegister algorithm.
         async fn client_command(
             &mut self,
             cmd: ClientRegisterCommand,
-            success_callback: Box<
-                dyn FnOnce(OperationSuccess) -> Pin<Box<dyn Future<Output = ()> + Send>>
+            callback: Box<
+                dyn FnOnce(
+                        Result<OperationSuccess, StorageError>,
+                    ) -> Pin<Box<dyn Future<Output = ()> + Send>>
                     + Send
                     + Sync,
             >,
@@ -67,34 +94,234 @@ pub mod atomic_register_public {
             register_client,
             sectors_manager,
             processes_count,
+            false,
         )
         .await
     }
+
+    #[async_trait::async_trait]
+    /// Creates the registers hosted by a register process, one for every worker.
+    /// The register client passed to them delivers messages to self, as
+    /// described in `RegisterClient`.
+    pub trait AtomicRegisterBuilder: Send + Sync {
+        /// Arguments have the same meaning as in `build_atomic_register`.
+        async fn build(
+            &self,
+            self_ident: u8,
+            metadata: Box<dyn StableStorage>,
+            register_client: Arc<dyn RegisterClient>,
+            sectors_manager: Arc<dyn SectorsManager>,
+            processes_count: u8,
+        ) -> Box<dyn AtomicRegister>;
+    }
 }
 
 pub mod sectors_manager_public {
-    use crate::{SectorIdx, SectorVec};
+    use crate::solution::sectors_manager::merkle::MerkleTree;
+    use crate::{
+        SectorCacheStats, SectorIdx, SectorVec, SectorsGcStats, StorageConfiguration, StorageError,
+    };
+    use std::ops::Range;
     use std::path::PathBuf;
     use std::sync::Arc;
 
     #[async_trait::async_trait]
     pub trait SectorsManager: Send + Sync {
-        /// Returns 4096 bytes of sector data by index.
-        async fn read_data(&self, idx: SectorIdx) -> SectorVec;
+        /// Returns 4096 bytes of sector data by index. Sectors which were never
+        /// written are returned as zeros. Stored data which turned out to be
+        /// corrupted is reported as `StorageError::Corrupted`, instead of returned.
+        async fn read_data(&self, idx: SectorIdx) -> Result<SectorVec, StorageError>;
 
         /// Returns timestamp and write rank of the process which has saved this data.
         /// Timestamps and ranks are relevant for atomic register algorithm, and are described
         /// there.
-        async fn read_metadata(&self, idx: SectorIdx) -> (u64, u8);
+        async fn read_metadata(&self, idx: SectorIdx) -> Result<(u64, u8), StorageError>;
+
+        /// Digest of metadata of the written sectors of `range`, equal for any
+        /// two sectors managers storing the same versions of them, and zero if
+        /// none of them is written. Used to compare replicas, so implementations
+        /// should answer it without going through every sector of the range.
+        async fn range_digest(&self, range: Range<SectorIdx>) -> Result<u64, StorageError> {
+            let mut entries = vec![];
+            for idx in range.clone() {
+                let meta = self.read_metadata(idx).await?;
+                if meta != (0, 0) {
+                    entries.push((idx, meta));
+                }
+            }
+            Ok(MerkleTree::from_entries(entries.into_iter()).range_digest(range))
+        }
 
         /// Writes a new data, along with timestamp and write rank to some sector.
-        async fn write(&self, idx: SectorIdx, sector: &(SectorVec, u64, u8));
+        /// After a failure it is unspecified whether the write took effect, and
+        /// reading the sector may fail until it is written again.
+        async fn write(
+            &self,
+            idx: SectorIdx,
+            sector: &(SectorVec, u64, u8),
+        ) -> Result<(), StorageError>;
+
+        /// Counters of the cache of sector contents, `None` if there is no cache.
+        fn cache_stats(&self) -> Option<SectorCacheStats> {
+            None
+        }
+
+        /// Counters of the removal of unreferenced files, `None` if the
+        /// layout leaves none behind.
+        fn gc_stats(&self) -> Option<SectorsGcStats> {
+            None
+        }
+
+        /// Timestamps and write ranks of the kept versions of the sector,
+        /// newest first, starting with the current one. Empty if the sector
+        /// was never written.
+        async fn list_versions(&self, idx: SectorIdx) -> Result<Vec<(u64, u8)>, StorageError> {
+            let meta = self.read_metadata(idx).await?;
+            Ok(Some(meta)
+                .filter(|meta| *meta != (0, 0))
+                .into_iter()
+                .collect())
+        }
+
+        /// Data of the version of the sector with timestamp and write rank
+        /// `meta`, `None` if it isn't kept.
+        async fn read_version(
+            &self,
+            idx: SectorIdx,
+            meta: (u64, u8),
+        ) -> Result<Option<SectorVec>, StorageError> {
+            if meta == (0, 0) || self.read_metadata(idx).await? != meta {
+                return Ok(None);
+            }
+            self.read_data(idx).await.map(Some)
+        }
+
+        /// Freezes the current versions of all sectors under `name`, which
+        /// consists of up to 64 letters, digits, '-' and '_'. Implementations
+        /// shouldn't copy sector data to do so.
+        async fn create_snapshot(&self, _name: &str) -> Result<(), StorageError> {
+            Err(StorageError::InvalidInput("snapshots are not supported"))
+        }
+
+        /// Names of the snapshots, sorted.
+        async fn list_snapshots(&self) -> Result<Vec<String>, StorageError> {
+            Ok(vec![])
+        }
+
+        /// Data, timestamp and write rank of the sector in snapshot `name`.
+        /// Sectors which weren't written by then are returned as zeros.
+        async fn read_snapshot(
+            &self,
+            _name: &str,
+            _idx: SectorIdx,
+        ) -> Result<(SectorVec, u64, u8), StorageError> {
+            Err(StorageError::InvalidInput("no such snapshot"))
+        }
+
+        async fn delete_snapshot(&self, _name: &str) -> Result<(), StorageError> {
+            Err(StorageError::InvalidInput("no such snapshot"))
+        }
     }
 
     /// Path parameter points to a directory to which this method has exclusive access.
-    pub async fn build_sectors_manager(path: PathBuf) -> Arc<dyn SectorsManager> {
+    /// Fails if sectors already stored there can't be read.
+    pub async fn build_sectors_manager(
+        path: PathBuf,
+    ) -> Result<Arc<dyn SectorsManager>, StorageError> {
         crate::solution::sectors_manager::build_sectors_manager(path).await
     }
+
+    /// Like `build_sectors_manager`, but the on-disk layout is chosen by `config`.
+    /// Sectors from `0` to `n_sectors` (exclusive) can be stored. With
+    /// `config.data_dirs`, sector files are striped across them, and `path`
+    /// holds only the index.
+    pub async fn build_sectors_manager_with_config(
+        path: PathBuf,
+        n_sectors: u64,
+        config: &StorageConfiguration,
+    ) -> Result<Arc<dyn SectorsManager>, StorageError> {
+        crate::solution::sectors_manager::build_sectors_manager_with_config(path, n_sectors, config)
+            .await
+    }
+
+    /// Like `build_sectors_manager_with_config`, but sectors are encrypted at
+    /// rest with `key`. The key can't change once sectors are stored.
+    pub async fn build_encrypted_sectors_manager(
+        path: PathBuf,
+        n_sectors: u64,
+        config: &StorageConfiguration,
+        key: &[u8; 32],
+    ) -> Result<Arc<dyn SectorsManager>, StorageError> {
+        crate::solution::sectors_manager::build_sectors_manager_with_key(
+            path,
+            n_sectors,
+            config,
+            Some(key),
+        )
+        .await
+    }
+}
+
+pub mod image_public {
+    use crate::{ImageFormat, SectorsManager, StorageConfiguration, StorageError};
+    use std::path::PathBuf;
+    use tokio::io::{AsyncRead, AsyncWrite};
+
+    /// Writes sectors from `0` to `n_sectors` (exclusive) to `writer`, and
+    /// returns the number of exported sectors. The sectors manager shouldn't
+    /// be written to meanwhile.
+    pub async fn export_image(
+        sectors_manager: &dyn SectorsManager,
+        n_sectors: u64,
+        format: ImageFormat,
+        writer: &mut (dyn AsyncWrite + Send + Unpin),
+    ) -> Result<u64, StorageError> {
+        crate::solution::image::export_image(sectors_manager, n_sectors, format, writer).await
+    }
+
+    /// Creates the storage of a process in `storage_dir`, which must be empty
+    /// or not exist, holding the sectors read from `reader`. Returns the
+    /// number of imported sectors. `n_sectors` and `config` must match the
+    /// configuration of the process which is going to use the storage.
+    pub async fn import_image(
+        storage_dir: PathBuf,
+        n_sectors: u64,
+        config: &StorageConfiguration,
+        format: ImageFormat,
+        reader: &mut (dyn AsyncRead + Send + Unpin),
+    ) -> Result<u64, StorageError> {
+        crate::solution::image::import_image(storage_dir, n_sectors, config, format, reader).await
+    }
+}
+
+pub mod format_public {
+    use crate::StorageError;
+    use std::path::PathBuf;
+
+    /// Version of the layout of storage directories written by this build.
+    pub const STORAGE_FORMAT_VERSION: u32 = crate::solution::format::CURRENT_VERSION;
+
+    /// Upgrades storage in `storage_dir` in place to `STORAGE_FORMAT_VERSION`,
+    /// and returns the version it had. Processes do it on start, this allows
+    /// doing it ahead. The process using the storage must not be running.
+    pub async fn migrate_storage(storage_dir: PathBuf) -> Result<u32, StorageError> {
+        crate::solution::format::migrate(&storage_dir).await
+    }
+}
+
+pub mod fsck_public {
+    use crate::{StorageError, StorageReport};
+    use std::path::PathBuf;
+
+    /// Checks the storage directory of a process, which must not be running.
+    /// With `repair`, fixes what can be fixed without losing data, the same
+    /// way the process would on startup.
+    pub async fn check_storage(
+        storage_dir: PathBuf,
+        repair: bool,
+    ) -> Result<StorageReport, StorageError> {
+        crate::solution::fsck::check_storage(storage_dir, repair).await
+    }
 }
 
 pub mod transfer_public {
@@ -124,6 +351,39 @@ pub mod transfer_public {
     }
 }
 
+pub mod transport_public {
+    use crate::Location;
+    use std::io;
+    use tokio::io::{AsyncRead, AsyncWrite};
+
+    pub use crate::solution::transport::SocketTransport;
+    /// Only with the `testing` feature.
+    #[cfg(any(test, feature = "testing"))]
+    pub use crate::solution::transport::{FaultConfig, MemoryNetwork, MemoryTransport};
+
+    /// A bidirectional stream of bytes between two parties.
+    pub trait TransportStream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+    impl<T: AsyncRead + AsyncWrite + Send + Unpin> TransportStream for T {}
+
+    #[async_trait::async_trait]
+    /// The way processes and clients reach each other. Both the listener for
+    /// incoming connections and the connections to other processes use it.
+    pub trait Transport: Send + Sync {
+        /// Opens a stream to whoever listens at `location`.
+        async fn connect(&self, location: &Location) -> io::Result<Box<dyn TransportStream>>;
+
+        /// Starts listening for incoming streams at `location`.
+        async fn bind(&self, location: &Location) -> io::Result<Box<dyn TransportListener>>;
+    }
+
+    #[async_trait::async_trait]
+    pub trait TransportListener: Send {
+        /// Waits for the next incoming stream.
+        async fn accept(&mut self) -> io::Result<Box<dyn TransportStream>>;
+    }
+}
+
 pub mod register_client_public {
     use crate::SystemRegisterCommand;
     use std::sync::Arc;
@@ -156,15 +416,17 @@ pub mod register_client_public {
 }
 
 pub mod stable_storage_public {
+    use crate::StorageError;
+
     #[async_trait::async_trait]
     /// A helper trait for small amount of durable metadata needed by the register algorithm
     /// itself. Again, it is only for AtomicRegister definition. StableStorage in unit tests
     /// is durable, as one could expect.
     pub trait StableStorage: Send + Sync {
-        async fn put(&mut self, key: &str, value: &[u8]) -> Result<(), String>;
+        async fn put(&mut self, key: &str, value: &[u8]) -> Result<(), StorageError>;
 
-        async fn get(&self, key: &str) -> Option<Vec<u8>>;
+        async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, StorageError>;
 
-        async fn remove(&mut self, key: &str) -> bool;
+        async fn remove(&mut self, key: &str) -> Result<bool, StorageError>;
     }
 }
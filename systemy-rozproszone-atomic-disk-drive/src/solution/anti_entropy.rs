@@ -0,0 +1,343 @@
+//! Background anti-entropy between replicas.
+//!
+//! A process which missed `WriteProc`s, e.g. while partitioned, would keep
+//! stale sectors until a client reads them. So every process periodically
+//! sends a digest of metadata of a range of its sectors to another process.
+//! If that process has a different digest, it answers with metadata of all
+//! its sectors of the range, and the first one fetches the sectors for which
+//! the other process has a newer version, with `ReadProc` sent to that process
+//! only. The received value is then applied by the register as a `WriteProc`
+//! from self, through a channel it serves after all other traffic.
+//!
+//! Only a single digest is sent per interval, and only a bounded number of
+//! sectors is fetched at once, so anti-entropy doesn't starve clients.
+use crate::solution::failure_detector::FailureDetector;
+use crate::solution::register_client::SolutionRegisterClient;
+use crate::solution::transfer::MAX_RANGE_LEN;
+use crate::*;
+use log::*;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::time::{self, Duration, Instant};
+use uuid::Uuid;
+
+struct Pull {
+    sector_idx: SectorIdx,
+    /// Version announced by the process the sector is fetched from.
+    version: (u64, u8),
+    started: Instant,
+}
+
+struct AntiEntropyState {
+    pulls: HashMap<Uuid, Pull>,
+    /// Number of rounds so far. Every range is compared with all other
+    /// processes before moving on to the next one.
+    round: u64,
+}
+
+/// Cheap to clone, all clones share the state.
+#[derive(Clone)]
+pub(crate) struct AntiEntropy {
+    self_rank: u8,
+    processes_count: u8,
+    n_sectors: u64,
+    range_len: u64,
+    max_pulls: usize,
+    /// Unanswered pulls are forgotten after that long.
+    pull_timeout: Duration,
+    sectors_manager: Arc<dyn SectorsManager>,
+    register_client: Arc<SolutionRegisterClient>,
+    state: Arc<Mutex<AntiEntropyState>>,
+}
+
+impl AntiEntropy {
+    pub(crate) fn new(
+        self_rank: u8,
+        processes_count: u8,
+        n_sectors: u64,
+        network: &NetworkConfiguration,
+        sectors_manager: Arc<dyn SectorsManager>,
+        register_client: Arc<SolutionRegisterClient>,
+    ) -> Self {
+        AntiEntropy {
+            self_rank,
+            processes_count,
+            n_sectors,
+            range_len: network.anti_entropy_range_len.clamp(1, MAX_RANGE_LEN),
+            max_pulls: network.anti_entropy_max_pulls,
+            pull_timeout: 2 * network.anti_entropy_interval.unwrap_or_default(),
+            sectors_manager,
+            register_client,
+            state: Arc::new(Mutex::new(AntiEntropyState {
+                pulls: HashMap::new(),
+                round: 0,
+            })),
+        }
+    }
+
+    pub(crate) fn is_anti_entropy_command(cmd: &SystemRegisterCommand) -> bool {
+        matches!(
+            cmd.content,
+            SystemRegisterCommandContent::RangeDigest { .. }
+                | SystemRegisterCommandContent::RangeVersions { .. }
+        )
+    }
+
+    fn range_end(&self, start: SectorIdx, range_len: u64) -> SectorIdx {
+        start
+            .saturating_add(range_len.min(MAX_RANGE_LEN))
+            .min(self.n_sectors)
+    }
+
+    /// Metadata of written sectors of the range.
+    async fn versions(
+        &self,
+        start: SectorIdx,
+        end: SectorIdx,
+    ) -> Result<Vec<(SectorIdx, u64, u8)>, StorageError> {
+        let mut versions = vec![];
+        for sector_idx in start..end {
+            let (timestamp, write_rank) = match self.sectors_manager.read_metadata(sector_idx).await
+            {
+                // Its version is unknown, so it is repaired rather than offered.
+                Err(StorageError::Corrupted(_)) => continue,
+                meta => meta?,
+            };
+            if (timestamp, write_rank) != (0, 0) {
+                versions.push((sector_idx, timestamp, write_rank));
+            }
+        }
+        Ok(versions)
+    }
+
+    fn send(&self, target: u8, header: SystemCommandHeader, content: SystemRegisterCommandContent) {
+        self.register_client.send_unreliable(Send {
+            target,
+            cmd: Arc::new(SystemRegisterCommand { header, content }),
+        });
+    }
+
+    /// Sends a digest of the next range to the next process, unless it is
+    /// suspected, and forgets pulls which have not been answered.
+    async fn round(&self, failure_detector: &FailureDetector) {
+        let others = (self.processes_count - 1) as u64;
+        if others == 0 || self.n_sectors == 0 {
+            return;
+        }
+        let round = {
+            let mut state = self.state.lock().unwrap();
+            let pull_timeout = self.pull_timeout;
+            state
+                .pulls
+                .retain(|_, pull| pull.started.elapsed() < pull_timeout);
+            state.round += 1;
+            state.round - 1
+        };
+        let mut peer = (round % others) as u8 + 1;
+        if peer >= self.self_rank {
+            peer += 1;
+        }
+        if failure_detector.is_suspected(peer) {
+            return;
+        }
+        let ranges = self.n_sectors.div_ceil(self.range_len);
+        let start = (round / others % ranges) * self.range_len;
+        let end = self.range_end(start, self.range_len);
+        let digest = match self.sectors_manager.range_digest(start..end).await {
+            Ok(digest) => digest,
+            Err(err) => {
+                error!("anti_entropy: Couldn't compute a digest: {}", err);
+                return;
+            }
+        };
+        self.send(
+            peer,
+            SystemCommandHeader {
+                process_identifier: self.self_rank,
+                msg_ident: Uuid::new_v4(),
+                read_ident: 0,
+                sector_idx: start,
+            },
+            SystemRegisterCommandContent::RangeDigest {
+                range_len: end - start,
+                digest,
+            },
+        );
+    }
+
+    /// Handles a `RangeDigest` or `RangeVersions` of another process.
+    pub(crate) async fn system(&self, cmd: SystemRegisterCommand) {
+        if let Err(err) = self.handle(cmd).await {
+            error!("anti_entropy: Couldn't handle a message: {}", err);
+        }
+    }
+
+    async fn handle(&self, cmd: SystemRegisterCommand) -> Result<(), StorageError> {
+        let start = cmd.header.sector_idx;
+        match cmd.content {
+            SystemRegisterCommandContent::RangeDigest { range_len, digest } => {
+                let end = self.range_end(start, range_len);
+                if self.sectors_manager.range_digest(start..end).await? == digest {
+                    return Ok(());
+                }
+                self.send(
+                    cmd.header.process_identifier,
+                    SystemCommandHeader {
+                        process_identifier: self.self_rank,
+                        ..cmd.header
+                    },
+                    SystemRegisterCommandContent::RangeVersions {
+                        versions: self.versions(start, end).await?,
+                    },
+                );
+            }
+            SystemRegisterCommandContent::RangeVersions { versions } => {
+                for (sector_idx, timestamp, write_rank) in versions {
+                    if sector_idx >= self.n_sectors {
+                        continue;
+                    }
+                    // A sector with corrupted metadata is older than any
+                    // version, storing the pulled one starts its repair.
+                    let stored = match self.sectors_manager.read_metadata(sector_idx).await {
+                        Err(StorageError::Corrupted(_)) => (0, 0),
+                        meta => meta?,
+                    };
+                    if (timestamp, write_rank) <= stored {
+                        continue;
+                    }
+                    if !self.pull(
+                        cmd.header.process_identifier,
+                        sector_idx,
+                        (timestamp, write_rank),
+                    ) {
+                        break;
+                    }
+                }
+            }
+            _ => {
+                error!("anti_entropy: Got a message which is not for anti-entropy");
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns false if too many sectors are being fetched already.
+    fn pull(&self, target: u8, sector_idx: SectorIdx, version: (u64, u8)) -> bool {
+        let msg_ident = Uuid::new_v4();
+        {
+            let mut state = self.state.lock().unwrap();
+            if state.pulls.len() >= self.max_pulls {
+                return false;
+            }
+            if state
+                .pulls
+                .values()
+                .any(|pull| pull.sector_idx == sector_idx)
+            {
+                return true;
+            }
+            state.pulls.insert(
+                msg_ident,
+                Pull {
+                    sector_idx,
+                    version,
+                    started: Instant::now(),
+                },
+            );
+        }
+        debug!(
+            "anti_entropy: Fetching sector {} from process {}",
+            sector_idx, target
+        );
+        self.send(
+            target,
+            SystemCommandHeader {
+                process_identifier: self.self_rank,
+                msg_ident,
+                read_ident: 0,
+                sector_idx,
+            },
+            SystemRegisterCommandContent::ReadProc,
+        );
+        true
+    }
+
+    /// If `cmd` answers a pull, returns the `WriteProc` which applies it.
+    pub(crate) fn take_pull(&self, cmd: &SystemRegisterCommand) -> Option<SystemRegisterCommand> {
+        let (timestamp, write_rank, sector_data) = match &cmd.content {
+            SystemRegisterCommandContent::Value {
+                timestamp,
+                write_rank,
+                sector_data,
+            } => (*timestamp, *write_rank, sector_data),
+            _ => return None,
+        };
+        let mut state = self.state.lock().unwrap();
+        let pull = state.pulls.get(&cmd.header.msg_ident)?;
+        if pull.sector_idx != cmd.header.sector_idx || (timestamp, write_rank) < pull.version {
+            return None;
+        }
+        state.pulls.remove(&cmd.header.msg_ident);
+        Some(SystemRegisterCommand {
+            header: SystemCommandHeader {
+                process_identifier: self.self_rank,
+                ..cmd.header
+            },
+            content: SystemRegisterCommandContent::WriteProc {
+                timestamp,
+                write_rank,
+                data_to_write: sector_data.clone(),
+            },
+        })
+    }
+}
+
+/// Periodically compares a range of sectors with another process.
+pub(crate) async fn run_anti_entropy(
+    anti_entropy: AntiEntropy,
+    failure_detector: FailureDetector,
+    anti_entropy_interval: Duration,
+) {
+    let mut interval = time::interval(anti_entropy_interval);
+    loop {
+        interval.tick().await;
+        anti_entropy.round(&failure_detector).await;
+    }
+}
+
+#[tokio::test]
+async fn test_anti_entropy_updates_replica_which_missed_writes() {
+    use crate::solution::test_cluster::*;
+
+    let cluster = Cluster::start(5, 3).await;
+    // Unlike with a partition, messages are lost rather than delivered later.
+    let lossy = FaultConfig {
+        drop_probability: 1.0,
+        ..Default::default()
+    };
+    for rank in 1..=2 {
+        (cluster.network).set_link_faults(&location(rank), &location(3), lossy.clone());
+    }
+
+    let mut stream = cluster.connect(1).await;
+    let data = SectorVec(vec![99; 4096]);
+    execute(&mut stream, write(1, 21, &data)).await;
+    assert!(cluster.sector_file(3).is_none());
+
+    // Nobody reads the sector, only anti-entropy can bring it to process 3.
+    time::sleep(Duration::from_millis(200)).await;
+    for rank in 1..=2 {
+        (cluster.network).set_link_faults(&location(rank), &location(3), FaultConfig::default());
+    }
+    let mut updated = false;
+    for _ in 0..200 {
+        if let Some(path) = cluster.sector_file(3) {
+            if std::fs::read(path).is_ok_and(|c| c[..4096] == data.0[..]) {
+                updated = true;
+                break;
+            }
+        }
+        time::sleep(Duration::from_millis(20)).await;
+    }
+    assert!(updated);
+}
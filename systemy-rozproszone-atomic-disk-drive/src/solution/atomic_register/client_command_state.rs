@@ -1,4 +1,4 @@
-use super::utils::SuccessCallback;
+use super::utils::OperationCallback;
 use crate::*;
 use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
@@ -9,7 +9,7 @@ use log::error;
 pub(crate) struct ClientCommandState {
     header: SystemCommandHeader,
     state: ClientCommandEnum,
-    callback: SuccessCallback,
+    callback: OperationCallback,
     request_identifier: u64,
 }
 
@@ -18,7 +18,7 @@ impl ClientCommandState {
         self_ident: u8,
         read_ident: u64,
         sector_idx: SectorIdx,
-        callback: SuccessCallback,
+        callback: OperationCallback,
         request_identifier: u64,
         writeval: Option<SectorVec>,
     ) -> Self {
@@ -56,7 +56,7 @@ impl ClientCommandState {
     pub(crate) fn build_message(&self, content: SystemRegisterCommandContent) -> Broadcast {
         Broadcast {
             cmd: Arc::new(SystemRegisterCommand {
-                header: self.header.clone(),
+                header: self.header,
                 content,
             }),
         }
@@ -66,7 +66,7 @@ impl ClientCommandState {
         Send {
             target: self.header.process_identifier,
             cmd: Arc::new(SystemRegisterCommand {
-                header: self.header.clone(),
+                header: self.header,
                 content,
             }),
         }
@@ -99,13 +99,18 @@ impl ClientCommandState {
         } else {
             OperationReturn::Write
         };
-        (self.callback)(OperationSuccess {
+        (self.callback)(Ok(OperationSuccess {
             request_identifier: self.request_identifier,
             op_return,
-        })
+        }))
         .await;
     }
 
+    /// Completes the command unsuccessfully.
+    pub(crate) async fn fail(self, err: StorageError) {
+        (self.callback)(Err(err)).await;
+    }
+
     pub(crate) fn get_sector_idx(&self) -> SectorIdx {
         self.header.sector_idx
     }
@@ -1,6 +1,8 @@
-use crate::{SectorIdx, SectorVec, SectorsManager, StableStorage};
+use crate::solution::transfer::SECTOR_LEN;
+use crate::{SectorIdx, SectorVec, SectorsManager, StableStorage, StorageError};
 use log::*;
 use std::collections::HashMap;
+use std::io::ErrorKind;
 use std::sync::Arc;
 
 #[derive(Clone)]
@@ -17,58 +19,101 @@ pub(crate) struct SolutionAtomicRegisterData {
 }
 
 impl SolutionAtomicRegisterData {
-    pub(crate) async fn get_meta(&mut self, sector_idx: SectorIdx) -> SectorMetadata {
+    pub(crate) async fn get_meta(
+        &mut self,
+        sector_idx: SectorIdx,
+    ) -> Result<SectorMetadata, StorageError> {
         if let Some(meta) = self.meta_cache.get(&sector_idx) {
-            return meta.clone();
+            Ok(meta.clone())
         } else {
-            let ret = self.sectors_manager.read_metadata(sector_idx).await;
+            let ret = self.sectors_manager.read_metadata(sector_idx).await?;
             let meta = SectorMetadata {
                 ts: ret.0,
                 wr: ret.1,
             };
             self.meta_cache.insert(sector_idx, meta.clone());
-            return meta;
+            Ok(meta)
         }
     }
 
-    pub(crate) async fn get_val(&mut self, sector_idx: SectorIdx) -> SectorVec {
+    pub(crate) async fn get_val(
+        &mut self,
+        sector_idx: SectorIdx,
+    ) -> Result<SectorVec, StorageError> {
         self.sectors_manager.read_data(sector_idx).await
     }
 
-    pub(crate) async fn get_rid(&mut self) -> u64 {
-        if self.rid_cache.is_none() {
-            if let Some(bytes) = self.rid_storage.get("rid").await {
-                if bytes.len() == 8 {
-                    self.rid_cache = Some(read_be_u64(&mut &bytes[..]));
-                } else {
-                    error!("Corrupted rid file, reseting rid");
-                    self.put_rid(0).await;
-                }
-            } else {
-                self.put_rid(0).await;
+    /// Newest kept version of the sector with a timestamp not greater than
+    /// `timestamp`, from local storage only. Writes have positive timestamps,
+    /// so before them the sector holds zeros.
+    pub(crate) async fn get_val_at(
+        &mut self,
+        sector_idx: SectorIdx,
+        timestamp: u64,
+    ) -> Result<SectorVec, StorageError> {
+        let versions = self.sectors_manager.list_versions(sector_idx).await?;
+        let trimmed = StorageError::VersionNotKept(sector_idx);
+        match versions.iter().find(|(ts, _)| *ts <= timestamp) {
+            Some(meta) => {
+                (self.sectors_manager.read_version(sector_idx, *meta).await?).ok_or(trimmed)
             }
+            None if timestamp == 0 || versions.is_empty() => Ok(SectorVec(vec![0; SECTOR_LEN])),
+            None => Err(trimmed),
         }
-        self.rid_cache.unwrap()
     }
 
-    pub(crate) async fn put_rid(&mut self, new_value: u64) {
-        self.rid_cache = Some(new_value);
+    pub(crate) async fn get_rid(&mut self) -> Result<u64, StorageError> {
+        if let Some(rid) = self.rid_cache {
+            return Ok(rid);
+        }
+        let corrupted = match self.rid_storage.get("rid").await {
+            Ok(Some(bytes)) if bytes.len() == 8 => {
+                let rid = read_be_u64(&mut &bytes[..]);
+                self.rid_cache = Some(rid);
+                return Ok(rid);
+            }
+            Ok(Some(_)) => true,
+            Ok(None) => false,
+            Err(StorageError::Io(err)) if err.kind() == ErrorKind::InvalidData => true,
+            Err(err) => return Err(err),
+        };
+        if corrupted {
+            error!("Corrupted rid file, reseting rid");
+        }
+        self.put_rid(0).await?;
+        Ok(0)
+    }
+
+    /// The cached value is updated only once it is stored.
+    pub(crate) async fn put_rid(&mut self, new_value: u64) -> Result<(), StorageError> {
         self.rid_storage
             .put("rid", &new_value.to_be_bytes())
-            .await
-            .unwrap();
+            .await?;
+        self.rid_cache = Some(new_value);
+        Ok(())
     }
 
+    /// On failure the cached metadata is dropped, as it is unknown whether
+    /// the write took effect.
     pub(crate) async fn put_val_and_meta(
         &mut self,
         sector_idx: SectorIdx,
         val: SectorVec,
         meta: &SectorMetadata,
-    ) {
-        self.sectors_manager
+    ) -> Result<(), StorageError> {
+        let result = self
+            .sectors_manager
             .write(sector_idx, &(val, meta.ts, meta.wr))
             .await;
-        self.meta_cache.insert(sector_idx, meta.clone());
+        match result {
+            Ok(()) => {
+                self.meta_cache.insert(sector_idx, meta.clone());
+            }
+            Err(_) => {
+                self.meta_cache.remove(&sector_idx);
+            }
+        }
+        result
     }
 
     pub(crate) fn new(
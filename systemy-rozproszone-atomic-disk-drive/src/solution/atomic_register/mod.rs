@@ -1,13 +1,16 @@
 mod client_command_state;
 mod metadata;
+mod repair;
 pub mod utils;
 
 use crate::*;
 use client_command_state::{ClientCommandEnum, ClientCommandState};
 use log::*;
 use metadata::SolutionAtomicRegisterData;
+use repair::Repair;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
-use utils::SuccessCallback;
+use utils::OperationCallback;
 
 use metadata::SectorMetadata;
 
@@ -17,35 +20,60 @@ pub(crate) struct SolutionAtomicRegister {
     register_client: Arc<dyn RegisterClient>,
     processes_count: u8,
     cmd_state: Option<ClientCommandState>,
+    /// Whether the register client delivers messages to self. If it doesn't,
+    /// the register answers its own questions directly, and stores the value
+    /// it writes back after a read as its own WriteProc would.
+    self_delivery: bool,
+    /// Sectors whose local copy is corrupted. Self doesn't take part in read
+    /// quorums for them until they are repaired or overwritten.
+    bad_sectors: HashSet<SectorIdx>,
+    repairs: HashMap<SectorIdx, Repair>,
+}
+
+/// Whether a value with metadata `new` replaces the stored one. A corrupted
+/// sector is replaced also by the value it should hold.
+fn should_store(new: (u64, u8), meta: &SectorMetadata, bad: bool) -> bool {
+    new > (meta.ts, meta.wr) || (bad && new == (meta.ts, meta.wr))
 }
 
 #[async_trait::async_trait]
 impl AtomicRegister for SolutionAtomicRegister {
-    async fn client_command(
-        &mut self,
-        cmd: ClientRegisterCommand,
-        success_callback: SuccessCallback,
-    ) {
+    async fn client_command(&mut self, cmd: ClientRegisterCommand, callback: OperationCallback) {
         assert!(self.cmd_state.is_none());
         let request_identifier = cmd.header.request_identifier;
         let sector_idx = cmd.header.sector_idx;
         let mut writeval = None;
-        if let ClientRegisterCommandContent::Write { data } = cmd.content {
-            writeval = Some(data);
+        match cmd.content {
+            ClientRegisterCommandContent::Read => {}
+            ClientRegisterCommandContent::Write { data } => writeval = Some(data),
+            ClientRegisterCommandContent::ReadAt { timestamp } => {
+                // Old versions are kept only locally, no quorum is asked.
+                let result = (self.data.get_val_at(sector_idx, timestamp).await).map(|read_data| {
+                    OperationSuccess {
+                        request_identifier,
+                        op_return: OperationReturn::ReadAt(ReadReturn { read_data }),
+                    }
+                });
+                callback(result).await;
+                return;
+            }
         }
-        let mut rid = self.data.get_rid().await;
-        self.data.put_rid(rid + 1).await;
-        rid += 1;
+        let rid = match self.next_rid().await {
+            Ok(rid) => rid,
+            Err(err) => {
+                error!("atomic_register: Couldn't store read identifier: {}", err);
+                callback(Err(err)).await;
+                return;
+            }
+        };
         self.cmd_state = Some(ClientCommandState::new_in_read_proc(
             self.self_ident,
             rid,
             sector_idx,
-            success_callback,
+            callback,
             request_identifier,
             writeval,
         ));
-        let meta = self.data.get_meta(sector_idx).await;
-        let val = self.data.get_val(sector_idx).await;
         self.register_client
             .broadcast(
                 self.cmd_state
@@ -54,17 +82,20 @@ impl AtomicRegister for SolutionAtomicRegister {
                     .build_message(SystemRegisterCommandContent::ReadProc),
             )
             .await;
+        if self.bad_sectors.contains(&sector_idx) {
+            // A repair whose answers were lost would never finish, start over.
+            self.start_repair(sector_idx).await;
+        }
+        if self.self_delivery || self.bad_sectors.contains(&sector_idx) {
+            return;
+        }
 
-        // SolutionRegisterClient doesn't broadcast self messages.
-        self.add_answer(
-            self.self_ident,
-            SystemRegisterCommandContent::Value {
-                timestamp: meta.ts,
-                write_rank: meta.wr,
-                sector_data: val,
-            },
-        )
-        .await;
+        // The register client doesn't deliver self messages.
+        match self.read_local(sector_idx).await {
+            Ok(value) => self.add_answer(self.self_ident, value).await,
+            Err(StorageError::Corrupted(_)) => self.sector_corrupted(sector_idx).await,
+            Err(err) => self.fail_command(err).await,
+        }
     }
 
     async fn system_command(&mut self, cmd: SystemRegisterCommand) {
@@ -75,33 +106,192 @@ impl AtomicRegister for SolutionAtomicRegister {
                 self.add_answer(cmd.header.process_identifier, cmd.content)
                     .await;
             } else {
-                trace!("atomic_register: Got answer incompatible with the current client command.");
+                self.add_repair_answer(cmd).await;
             }
         } else {
-            trace!(
-                "atomic_register: Got answer but there is no client command (probably finished)."
-            );
+            self.add_repair_answer(cmd).await;
         }
     }
 }
 
 impl SolutionAtomicRegister {
+    async fn next_rid(&mut self) -> Result<u64, StorageError> {
+        let rid = self.data.get_rid().await? + 1;
+        self.data.put_rid(rid).await?;
+        Ok(rid)
+    }
+
+    /// Value message with the locally stored value of the sector.
+    async fn read_local(
+        &mut self,
+        sector_idx: SectorIdx,
+    ) -> Result<SystemRegisterCommandContent, StorageError> {
+        let meta = self.data.get_meta(sector_idx).await?;
+        let sector_data = self.data.get_val(sector_idx).await?;
+        Ok(SystemRegisterCommandContent::Value {
+            timestamp: meta.ts,
+            write_rank: meta.wr,
+            sector_data,
+        })
+    }
+
+    /// Stores the value if it replaces the stored one, see `should_store`.
+    async fn store(
+        &mut self,
+        sector_idx: SectorIdx,
+        (timestamp, write_rank): (u64, u8),
+        val: SectorVec,
+    ) -> Result<(), StorageError> {
+        let meta = match self.data.get_meta(sector_idx).await {
+            Err(StorageError::Corrupted(idx)) => {
+                // Without the stored version it is unknown whether the value
+                // replaces it, the repair finds out.
+                self.sector_corrupted(sector_idx).await;
+                return Err(StorageError::Corrupted(idx));
+            }
+            meta => meta?,
+        };
+        let bad = self.bad_sectors.contains(&sector_idx);
+        if should_store((timestamp, write_rank), &meta, bad) {
+            self.data
+                .put_val_and_meta(
+                    sector_idx,
+                    val,
+                    &SectorMetadata {
+                        ts: timestamp,
+                        wr: write_rank,
+                    },
+                )
+                .await?;
+            self.bad_sectors.remove(&sector_idx);
+        }
+        Ok(())
+    }
+
+    /// Completes the client command, which can't go on because the storage
+    /// has failed.
+    async fn fail_command(&mut self, err: StorageError) {
+        error!("atomic_register: Client command failed: {}", err);
+        let state = self.cmd_state.take().unwrap();
+        let msg = state.build_self_message(SystemRegisterCommandContent::Ack);
+        state.fail(err).await;
+        if !self.self_delivery {
+            // Send self message so SolutionRegisterClient will stop resending
+            self.register_client.send(msg).await;
+        }
+    }
+
+    /// The storage has failed while answering a proc message. The process
+    /// which sent it can do without the answer, unless it is the client
+    /// command of self.
+    async fn answer_failed(&mut self, header: &SystemCommandHeader, err: StorageError) {
+        let own = self
+            .cmd_state
+            .as_ref()
+            .is_some_and(|state| state.is_compatible(header));
+        if own {
+            self.fail_command(err).await;
+        } else {
+            error!(
+                "atomic_register: Couldn't answer process {}: {}",
+                header.process_identifier, err
+            );
+        }
+    }
+
+    async fn sector_corrupted(&mut self, sector_idx: SectorIdx) {
+        error!(
+            "atomic_register: sector {} is corrupted, repairing it from other processes",
+            sector_idx
+        );
+        self.bad_sectors.insert(sector_idx);
+        if !self.repairs.contains_key(&sector_idx) {
+            self.start_repair(sector_idx).await;
+        }
+    }
+
+    async fn start_repair(&mut self, sector_idx: SectorIdx) {
+        let repair = Repair::new(self.self_ident, sector_idx);
+        self.register_client.broadcast(repair.build_message()).await;
+        self.repairs.insert(sector_idx, repair);
+    }
+
+    async fn add_repair_answer(&mut self, cmd: SystemRegisterCommand) {
+        let sector_idx = cmd.header.sector_idx;
+        let repair = match self.repairs.get_mut(&sector_idx) {
+            Some(repair) if repair.is_compatible(&cmd.header) => repair,
+            _ => {
+                trace!("atomic_register: Got answer which matches neither the client command nor a repair.");
+                return;
+            }
+        };
+        let highest = match cmd.content {
+            SystemRegisterCommandContent::Value {
+                timestamp,
+                write_rank,
+                sector_data,
+            } => repair.add_answer(
+                cmd.header.process_identifier,
+                (timestamp, write_rank, sector_data),
+                self.processes_count,
+            ),
+            _ => None,
+        };
+        if let Some((timestamp, write_rank, sector_data)) = highest {
+            let repair = self.repairs.remove(&sector_idx).unwrap();
+            // The sector may have been overwritten in the meantime.
+            if self.bad_sectors.remove(&sector_idx) {
+                let result = self
+                    .data
+                    .put_val_and_meta(
+                        sector_idx,
+                        sector_data,
+                        &SectorMetadata {
+                            ts: timestamp,
+                            wr: write_rank,
+                        },
+                    )
+                    .await;
+                match result {
+                    Ok(()) => info!("atomic_register: Repaired sector {}", sector_idx),
+                    Err(err) => {
+                        error!(
+                            "atomic_register: Couldn't repair sector {}: {}",
+                            sector_idx, err
+                        );
+                        self.bad_sectors.insert(sector_idx);
+                    }
+                }
+            }
+            if !self.self_delivery {
+                // Send self message so SolutionRegisterClient will stop resending ReadProc
+                self.register_client
+                    .send(repair.build_self_message(SystemRegisterCommandContent::Ack))
+                    .await;
+            }
+        }
+    }
+
     async fn give_answer(&mut self, cmd: SystemRegisterCommand) {
         let sector_idx = cmd.header.sector_idx;
         match cmd.content {
             SystemRegisterCommandContent::ReadProc => {
-                let meta = self.data.get_meta(sector_idx).await;
-                let data = self.data.get_val(sector_idx).await;
+                if self.bad_sectors.contains(&sector_idx) {
+                    return;
+                }
+                let value = match self.read_local(sector_idx).await {
+                    Ok(value) => value,
+                    Err(StorageError::Corrupted(_)) => {
+                        self.sector_corrupted(sector_idx).await;
+                        return;
+                    }
+                    Err(err) => {
+                        self.answer_failed(&cmd.header, err).await;
+                        return;
+                    }
+                };
                 self.register_client
-                    .send(build_answer(
-                        self.self_ident,
-                        &cmd,
-                        SystemRegisterCommandContent::Value {
-                            timestamp: meta.ts,
-                            write_rank: meta.wr,
-                            sector_data: data,
-                        },
-                    ))
+                    .send(build_answer(self.self_ident, &cmd, value))
                     .await;
             }
             SystemRegisterCommandContent::WriteProc {
@@ -109,18 +299,13 @@ impl SolutionAtomicRegister {
                 write_rank,
                 ref data_to_write,
             } => {
-                let meta = self.data.get_meta(sector_idx).await;
-                if (timestamp, write_rank) > (meta.ts, meta.wr) {
-                    self.data
-                        .put_val_and_meta(
-                            sector_idx,
-                            data_to_write.clone(),
-                            &SectorMetadata {
-                                ts: timestamp,
-                                wr: write_rank,
-                            },
-                        )
-                        .await;
+                let stored = self
+                    .store(sector_idx, (timestamp, write_rank), data_to_write.clone())
+                    .await;
+                if let Err(err) = stored {
+                    // Acknowledging would claim the value is stored.
+                    self.answer_failed(&cmd.header, err).await;
+                    return;
                 }
                 self.register_client
                     .send(build_answer(
@@ -144,8 +329,10 @@ impl SolutionAtomicRegister {
                 if 2 * acklist.len() > (self.processes_count as usize) {
                     let msg = state.build_self_message(SystemRegisterCommandContent::Ack);
                     self.cmd_state.take().unwrap().finish().await;
-                    // Send self message so SolutionRegisterClient will stop resending WriteProc
-                    self.register_client.send(msg).await;
+                    if !self.self_delivery {
+                        // Send self message so SolutionRegisterClient will stop resending WriteProc
+                        self.register_client.send(msg).await;
+                    }
                 }
             }
             _ => {
@@ -179,17 +366,20 @@ impl SolutionAtomicRegister {
                     if let Some(val) = writeval.take() {
                         readval = None;
                         highest = (highest.0 + 1, self.self_ident, val);
-                        self.data
-                            .put_val_and_meta(
-                                state.get_sector_idx(),
-                                highest.2.clone(),
-                                &SectorMetadata {
-                                    ts: highest.0,
-                                    wr: highest.1,
-                                },
-                            )
+                    }
+                    if !self.self_delivery {
+                        // Self won't get the WriteProc, so it stores the value as
+                        // it would on receiving it.
+                        let sector_idx = state.get_sector_idx();
+                        let stored = self
+                            .store(sector_idx, (highest.0, highest.1), highest.2.clone())
                             .await;
+                        if let Err(err) = stored {
+                            self.fail_command(err).await;
+                            return;
+                        }
                     }
+                    let state = self.cmd_state.as_mut().unwrap();
                     state.put_write_proc(readval);
                     self.register_client
                         .broadcast(
@@ -200,8 +390,10 @@ impl SolutionAtomicRegister {
                             }),
                         )
                         .await;
-                    // SolutionRegisterClient doesn't broadcast self messages.
-                    self.add_ack(self.self_ident).await;
+                    if !self.self_delivery {
+                        // The register client doesn't deliver self messages.
+                        self.add_ack(self.self_ident).await;
+                    }
                 }
             }
             (SystemRegisterCommandContent::Value { .. }, ClientCommandEnum::WriteProc { .. }) => {}
@@ -218,17 +410,48 @@ pub async fn build_atomic_register(
     register_client: Arc<dyn RegisterClient>,
     sectors_manager: Arc<dyn SectorsManager>,
     processes_count: u8,
+    self_delivery: bool,
 ) -> Box<dyn AtomicRegister> {
     let data = SolutionAtomicRegisterData::new(metadata, sectors_manager);
     Box::new(SolutionAtomicRegister {
         self_ident,
-        data: data,
+        data,
         register_client,
         processes_count,
         cmd_state: None,
+        self_delivery,
+        bad_sectors: HashSet::new(),
+        repairs: HashMap::new(),
     })
 }
 
+/// Builds the register of this crate, see `SolutionAtomicRegister::self_delivery`.
+pub(crate) struct SolutionAtomicRegisterBuilder {
+    pub(crate) self_delivery: bool,
+}
+
+#[async_trait::async_trait]
+impl AtomicRegisterBuilder for SolutionAtomicRegisterBuilder {
+    async fn build(
+        &self,
+        self_ident: u8,
+        metadata: Box<dyn StableStorage>,
+        register_client: Arc<dyn RegisterClient>,
+        sectors_manager: Arc<dyn SectorsManager>,
+        processes_count: u8,
+    ) -> Box<dyn AtomicRegister> {
+        build_atomic_register(
+            self_ident,
+            metadata,
+            register_client,
+            sectors_manager,
+            processes_count,
+            self.self_delivery,
+        )
+        .await
+    }
+}
+
 fn build_answer(
     self_ident: u8,
     prev: &SystemRegisterCommand,
@@ -242,8 +465,143 @@ fn build_answer(
                 read_ident: prev.header.read_ident,
                 sector_idx: prev.header.sector_idx,
             },
-            content: content,
+            content,
         }),
         target: prev.header.process_identifier,
     }
 }
+
+/// Register client which records the sent messages instead of sending them.
+#[cfg(test)]
+#[derive(Default)]
+struct RecordingClient(std::sync::Mutex<Vec<Arc<SystemRegisterCommand>>>);
+
+#[cfg(test)]
+#[async_trait::async_trait]
+impl RegisterClient for RecordingClient {
+    async fn send(&self, msg: Send) {
+        self.0.lock().unwrap().push(msg.cmd);
+    }
+
+    async fn broadcast(&self, msg: Broadcast) {
+        self.0.lock().unwrap().push(msg.cmd);
+    }
+}
+
+#[tokio::test]
+async fn test_read_stores_written_back_value_without_self_delivery() {
+    let dir = tempfile::tempdir().unwrap();
+    let sectors_manager =
+        crate::solution::sectors_manager::build_sectors_manager(dir.path().join("sectors"))
+            .await
+            .unwrap();
+    std::fs::create_dir(dir.path().join("metadata")).unwrap();
+    let metadata =
+        crate::solution::stable_storage::build_stable_storage(dir.path().join("metadata")).await;
+    let client = Arc::new(RecordingClient::default());
+    let mut register = build_atomic_register(
+        1,
+        metadata,
+        client.clone(),
+        sectors_manager.clone(),
+        3,
+        false,
+    )
+    .await;
+
+    let read = ClientRegisterCommand {
+        header: ClientCommandHeader {
+            request_identifier: 1,
+            sector_idx: 5,
+        },
+        content: ClientRegisterCommandContent::Read,
+    };
+    register
+        .client_command(read, Box::new(|_| Box::pin(async {})))
+        .await;
+    let read_proc = client.0.lock().unwrap()[0].clone();
+    // With its own value, process 2 makes a majority, and its value is newer.
+    let data = SectorVec(vec![9; 4096]);
+    register
+        .system_command(SystemRegisterCommand {
+            header: SystemCommandHeader {
+                process_identifier: 2,
+                ..read_proc.header
+            },
+            content: SystemRegisterCommandContent::Value {
+                timestamp: 5,
+                write_rank: 2,
+                sector_data: data.clone(),
+            },
+        })
+        .await;
+
+    // The value is written back, and self stores it without a WriteProc.
+    let write_proc = client.0.lock().unwrap()[1].clone();
+    assert!(matches!(
+        write_proc.content,
+        SystemRegisterCommandContent::WriteProc { timestamp: 5, .. }
+    ));
+    assert_eq!(sectors_manager.read_metadata(5).await.unwrap(), (5, 2));
+    assert_eq!(sectors_manager.read_data(5).await.unwrap(), data);
+}
+
+#[tokio::test]
+async fn test_write_over_corrupted_metadata_starts_repair() {
+    /// Sectors manager whose metadata records are all damaged.
+    struct CorruptedMetadata;
+
+    #[async_trait::async_trait]
+    impl SectorsManager for CorruptedMetadata {
+        async fn read_data(&self, idx: SectorIdx) -> Result<SectorVec, StorageError> {
+            Err(StorageError::Corrupted(idx))
+        }
+
+        async fn read_metadata(&self, idx: SectorIdx) -> Result<(u64, u8), StorageError> {
+            Err(StorageError::Corrupted(idx))
+        }
+
+        async fn write(&self, _: SectorIdx, _: &(SectorVec, u64, u8)) -> Result<(), StorageError> {
+            Err(StorageError::InvalidInput("stored over an unknown version"))
+        }
+    }
+
+    let dir = tempfile::tempdir().unwrap();
+    let metadata = crate::solution::stable_storage::build_stable_storage(dir.path().into()).await;
+    let client = Arc::new(RecordingClient::default());
+    let mut register = build_atomic_register(
+        1,
+        metadata,
+        client.clone(),
+        Arc::new(CorruptedMetadata),
+        3,
+        true,
+    )
+    .await;
+
+    register
+        .system_command(SystemRegisterCommand {
+            header: SystemCommandHeader {
+                process_identifier: 2,
+                msg_ident: uuid::Uuid::new_v4(),
+                read_ident: 1,
+                sector_idx: 5,
+            },
+            content: SystemRegisterCommandContent::WriteProc {
+                timestamp: 1,
+                write_rank: 2,
+                data_to_write: SectorVec(vec![1; 4096]),
+            },
+        })
+        .await;
+
+    // Not acknowledged, the sector is repaired instead.
+    let sent = client.0.lock().unwrap().clone();
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0].header.process_identifier, 1);
+    assert_eq!(sent[0].header.sector_idx, 5);
+    assert!(matches!(
+        sent[0].content,
+        SystemRegisterCommandContent::ReadProc
+    ));
+}
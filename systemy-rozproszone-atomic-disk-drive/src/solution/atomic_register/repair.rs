@@ -0,0 +1,95 @@
+use crate::*;
+use std::collections::HashMap;
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// Fetching of a sector whose local copy is corrupted. Other processes are
+/// asked for their values, and once a majority of all processes answered,
+/// the highest value is what a read would return.
+pub(crate) struct Repair {
+    header: SystemCommandHeader,
+    answers: HashMap<u8, (u64, u8, SectorVec)>,
+}
+
+impl Repair {
+    pub(crate) fn new(self_ident: u8, sector_idx: SectorIdx) -> Self {
+        Self {
+            header: SystemCommandHeader {
+                process_identifier: self_ident,
+                msg_ident: Uuid::new_v4(),
+                read_ident: 0,
+                sector_idx,
+            },
+            answers: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn is_compatible(&self, header: &SystemCommandHeader) -> bool {
+        header.msg_ident == self.header.msg_ident && header.sector_idx == self.header.sector_idx
+    }
+
+    pub(crate) fn build_message(&self) -> Broadcast {
+        Broadcast {
+            cmd: Arc::new(SystemRegisterCommand {
+                header: self.header,
+                content: SystemRegisterCommandContent::ReadProc,
+            }),
+        }
+    }
+
+    pub(crate) fn build_self_message(&self, content: SystemRegisterCommandContent) -> Send {
+        Send {
+            target: self.header.process_identifier,
+            cmd: Arc::new(SystemRegisterCommand {
+                header: self.header,
+                content,
+            }),
+        }
+    }
+
+    /// Returns the highest value once a majority answered. Self never answers,
+    /// as its copy is the broken one.
+    pub(crate) fn add_answer(
+        &mut self,
+        process_id: u8,
+        answer: (u64, u8, SectorVec),
+        processes_count: u8,
+    ) -> Option<(u64, u8, SectorVec)> {
+        if process_id == self.header.process_identifier {
+            return None;
+        }
+        self.answers.insert(process_id, answer);
+        if 2 * self.answers.len() > processes_count as usize {
+            self.answers.values().max_by_key(|x| (x.0, x.1)).cloned()
+        } else {
+            None
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_corrupted_sector_is_repaired_from_peers() {
+    use crate::solution::test_cluster::*;
+    use tokio::time::{self, Duration};
+
+    let cluster = Cluster::start(11, 3).await;
+    let mut stream = cluster.connect(1).await;
+    let data = SectorVec(vec![13; 4096]);
+    execute(&mut stream, write(1, 3, &data)).await;
+
+    let sector_file = cluster.sector_file(1).unwrap();
+    let mut content = std::fs::read(&sector_file).unwrap();
+    content[100] ^= 0xff;
+    std::fs::write(&sector_file, &content).unwrap();
+
+    assert_eq!(execute(&mut stream, read(2, 3)).await, Some(data.clone()));
+    let mut repaired = false;
+    for _ in 0..100 {
+        if std::fs::read(&sector_file).is_ok_and(|c| c[..4096] == data.0[..]) {
+            repaired = true;
+            break;
+        }
+        time::sleep(Duration::from_millis(20)).await;
+    }
+    assert!(repaired);
+}
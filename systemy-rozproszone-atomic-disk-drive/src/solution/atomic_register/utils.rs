@@ -4,19 +4,20 @@ use std::future::Future;
 use std::pin::Pin;
 
 pub(crate) fn is_proc_command(cmd: &SystemRegisterCommand) -> bool {
-    match cmd.content {
-        SystemRegisterCommandContent::ReadProc { .. }
-        | SystemRegisterCommandContent::WriteProc { .. } => true,
-        _ => false,
-    }
+    matches!(
+        cmd.content,
+        SystemRegisterCommandContent::ReadProc | SystemRegisterCommandContent::WriteProc { .. }
+    )
 }
 
 pub(crate) fn is_answer_command(cmd: &SystemRegisterCommand) -> bool {
-    return !is_proc_command(cmd);
+    !is_proc_command(cmd)
 }
 
-pub(crate) type SuccessCallback = Box<
-    dyn FnOnce(OperationSuccess) -> Pin<Box<dyn Future<Output = ()> + std::marker::Send>>
+pub(crate) type OperationCallback = Box<
+    dyn FnOnce(
+            Result<OperationSuccess, StorageError>,
+        ) -> Pin<Box<dyn Future<Output = ()> + std::marker::Send>>
         + std::marker::Send
         + Sync,
 >;
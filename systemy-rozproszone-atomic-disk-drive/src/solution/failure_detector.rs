@@ -0,0 +1,249 @@
+//! Eventually perfect failure detector based on the phi accrual detector.
+//!
+//! Every process periodically sends heartbeats to all others. For each peer we
+//! keep a window of heartbeat inter-arrival times and compute phi, the
+//! suspicion level derived from how unlikely the current silence is under the
+//! observed distribution. A peer is suspected while phi exceeds the threshold.
+//! Whenever a suspected peer turns out to be alive, the pause we tolerate for
+//! it grows, so eventually correct processes are not suspected anymore. The
+//! pause is bounded, and shrinks again while heartbeats keep arriving on time,
+//! so a few hiccups don't stop the detector from suspecting that peer.
+use crate::solution::register_client::SolutionRegisterClient;
+use crate::*;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use tokio::time::{self, Duration, Instant};
+
+const WINDOW_SIZE: usize = 100;
+/// Bound of the acceptable pause, in heartbeat intervals.
+const MAX_PAUSE_INTERVALS: f64 = 10.0;
+/// Number of heartbeats in a row arriving on time after which the acceptable
+/// pause shrinks by one heartbeat interval.
+const PAUSE_DECAY_HEARTBEATS: u32 = 50;
+
+struct PeerHistory {
+    last_heartbeat: Instant,
+    /// Inter-arrival times in milliseconds.
+    intervals: VecDeque<f64>,
+    /// Added to the mean, grows on every false suspicion.
+    acceptable_pause: f64,
+    /// Heartbeats received on time since the last false suspicion or decay.
+    on_time: u32,
+    suspected: bool,
+}
+
+impl PeerHistory {
+    fn new(now: Instant, heartbeat_interval: Duration) -> Self {
+        let interval = heartbeat_interval.as_secs_f64() * 1000.0;
+        PeerHistory {
+            last_heartbeat: now,
+            // Pretend we have already seen a heartbeat on time, so that a
+            // process which never starts is eventually suspected.
+            intervals: VecDeque::from(vec![interval]),
+            acceptable_pause: 0.0,
+            on_time: 0,
+            suspected: false,
+        }
+    }
+
+    fn phi(&self, now: Instant, min_deviation: f64) -> f64 {
+        let count = self.intervals.len() as f64;
+        let mean = self.intervals.iter().sum::<f64>() / count;
+        let variance = self
+            .intervals
+            .iter()
+            .map(|x| (x - mean) * (x - mean))
+            .sum::<f64>()
+            / count;
+        let deviation = variance.sqrt().max(min_deviation);
+        let elapsed = (now - self.last_heartbeat).as_secs_f64() * 1000.0;
+        phi(elapsed, mean + self.acceptable_pause, deviation)
+    }
+}
+
+/// -log10 of the probability that a heartbeat arrives later than `elapsed`,
+/// using the logistic approximation of the normal distribution.
+fn phi(elapsed: f64, mean: f64, deviation: f64) -> f64 {
+    let y = (elapsed - mean) / deviation;
+    let e = (-y * (1.5976 + 0.070566 * y * y)).exp();
+    if elapsed > mean {
+        -(e / (1.0 + e)).log10()
+    } else {
+        -(1.0 - 1.0 / (1.0 + e)).log10()
+    }
+}
+
+struct FailureDetectorState {
+    /// Indexed by process identifier minus one, `None` for self.
+    peers: Vec<Option<PeerHistory>>,
+}
+
+/// Cheap to clone, all clones share the state.
+#[derive(Clone)]
+pub(crate) struct FailureDetector {
+    state: Arc<Mutex<FailureDetectorState>>,
+    heartbeat_interval: Duration,
+    threshold: f64,
+}
+
+impl FailureDetector {
+    pub(crate) fn new(self_rank: u8, processes_count: u8, network: &NetworkConfiguration) -> Self {
+        let now = Instant::now();
+        let peers = (1..=processes_count)
+            .map(|ident| {
+                if ident == self_rank {
+                    None
+                } else {
+                    Some(PeerHistory::new(now, network.heartbeat_interval))
+                }
+            })
+            .collect();
+        FailureDetector {
+            state: Arc::new(Mutex::new(FailureDetectorState { peers })),
+            heartbeat_interval: network.heartbeat_interval,
+            threshold: network.phi_threshold,
+        }
+    }
+
+    fn min_deviation(&self) -> f64 {
+        self.heartbeat_interval.as_secs_f64() * 1000.0 / 2.0
+    }
+
+    pub(crate) fn heartbeat(&self, process_identifier: u8) {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        let history = match state
+            .peers
+            .get_mut((process_identifier as usize).wrapping_sub(1))
+        {
+            Some(Some(history)) => history,
+            _ => return,
+        };
+        let interval = self.heartbeat_interval.as_secs_f64() * 1000.0;
+        if history.suspected || history.phi(now, self.min_deviation()) > self.threshold {
+            // We were wrong, be more patient with this process from now on.
+            history.acceptable_pause =
+                (history.acceptable_pause + interval).min(interval * MAX_PAUSE_INTERVALS);
+            history.on_time = 0;
+            history.suspected = false;
+        } else {
+            history.on_time += 1;
+            if history.on_time == PAUSE_DECAY_HEARTBEATS {
+                history.acceptable_pause = (history.acceptable_pause - interval).max(0.0);
+                history.on_time = 0;
+            }
+        }
+        let elapsed = (now - history.last_heartbeat).as_secs_f64() * 1000.0;
+        history.last_heartbeat = now;
+        if history.intervals.len() == WINDOW_SIZE {
+            history.intervals.pop_front();
+        }
+        history.intervals.push_back(elapsed);
+    }
+
+    pub(crate) fn is_suspected(&self, process_identifier: u8) -> bool {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        match state
+            .peers
+            .get_mut((process_identifier as usize).wrapping_sub(1))
+        {
+            Some(Some(history)) => {
+                history.suspected = history.phi(now, self.min_deviation()) > self.threshold;
+                history.suspected
+            }
+            _ => false,
+        }
+    }
+
+    /// Identifiers of the processes currently considered crashed.
+    pub(crate) fn suspected(&self) -> Vec<u8> {
+        self.liveness()
+            .into_iter()
+            .filter(|peer| peer.suspected)
+            .map(|peer| peer.process_identifier)
+            .collect()
+    }
+
+    pub(crate) fn liveness(&self) -> Vec<PeerLiveness> {
+        let now = Instant::now();
+        let min_deviation = self.min_deviation();
+        let mut state = self.state.lock().unwrap();
+        state
+            .peers
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(i, history)| {
+                let history = history.as_mut()?;
+                let phi = history.phi(now, min_deviation);
+                history.suspected = phi > self.threshold;
+                Some(PeerLiveness {
+                    process_identifier: (i + 1) as u8,
+                    phi,
+                    suspected: history.suspected,
+                })
+            })
+            .collect()
+    }
+}
+
+/// Periodically sends heartbeats to all other processes.
+pub(crate) async fn run_heartbeat_sender(
+    register_client: Arc<SolutionRegisterClient>,
+    heartbeat_interval: Duration,
+) {
+    let mut interval = time::interval(heartbeat_interval);
+    loop {
+        interval.tick().await;
+        register_client.heartbeat();
+    }
+}
+
+#[test]
+fn test_phi_grows_with_silence() {
+    assert!(phi(100.0, 100.0, 25.0) < 1.0);
+    assert!(phi(150.0, 100.0, 25.0) < phi(200.0, 100.0, 25.0));
+    assert!(phi(300.0, 100.0, 25.0) > 8.0);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_failure_detector_suspects_silent_process() {
+    let network = NetworkConfiguration::default();
+    let detector = FailureDetector::new(1, 3, &network);
+    for _ in 0..20 {
+        time::advance(network.heartbeat_interval).await;
+        detector.heartbeat(2);
+        detector.heartbeat(3);
+    }
+    time::advance(network.heartbeat_interval * 10).await;
+    detector.heartbeat(2);
+    assert_eq!(detector.suspected(), vec![3]);
+    detector.heartbeat(3);
+    assert!(detector.suspected().is_empty());
+    assert!(!detector.is_suspected(1));
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_acceptable_pause_is_bounded_and_decays() {
+    let network = NetworkConfiguration::default();
+    let detector = FailureDetector::new(1, 2, &network);
+    let pause = || {
+        let state = detector.state.lock().unwrap();
+        state.peers[1].as_ref().unwrap().acceptable_pause
+    };
+    let interval = network.heartbeat_interval.as_secs_f64() * 1000.0;
+    // More false suspicions than the bound allows for.
+    for _ in 0..12 {
+        while !detector.is_suspected(2) {
+            time::advance(network.heartbeat_interval * 10).await;
+        }
+        detector.heartbeat(2);
+    }
+    assert_eq!(pause(), interval * MAX_PAUSE_INTERVALS);
+
+    for _ in 0..PAUSE_DECAY_HEARTBEATS * 3 {
+        time::advance(network.heartbeat_interval).await;
+        detector.heartbeat(2);
+    }
+    assert_eq!(pause(), interval * (MAX_PAUSE_INTERVALS - 3.0));
+}
@@ -0,0 +1,129 @@
+//! Versioning of the layout of the storage directory.
+//!
+//! `FORMAT_FILENAME` in the storage directory holds the version of its
+//! layout. Storage created before it was introduced has none, and is of
+//! version 1. Older layouts are upgraded in place one version at a time, and
+//! the manifest is replaced only after an upgrade completes, so an
+//! interrupted upgrade is run again on the next start.
+//!
+//! Versions:
+//! 1. Sector files may hold only the data, without a checksum.
+//! 2. Every sector file ends with the checksum of its data.
+//! 3. Sector files shorter than a sector hold compressed data.
+use crate::solution::running::paths_manager::{SECTORS_DIR, STABLE_STORAGE_DIR};
+use crate::solution::sectors_manager::migrations;
+use crate::StorageError;
+use log::*;
+use std::io;
+use std::path::Path;
+use tokio::fs::{self, File};
+use tokio::io::AsyncWriteExt;
+
+pub(crate) const FORMAT_FILENAME: &str = "FORMAT";
+const FORMAT_TMP_FILENAME: &str = "FORMAT.tmp";
+const MANIFEST_PREFIX: &str = "atdd-storage ";
+/// Version of the layout written by this build.
+pub(crate) const CURRENT_VERSION: u32 = 3;
+/// First version in which every sector file has a checksum.
+pub(crate) const CHECKSUMS_VERSION: u32 = 2;
+
+/// Version of storage in `storage_dir`, `None` if there is no storage yet.
+pub(crate) async fn read_version(storage_dir: &Path) -> Result<Option<u32>, StorageError> {
+    match fs::read_to_string(storage_dir.join(FORMAT_FILENAME)).await {
+        Ok(manifest) => manifest
+            .strip_prefix(MANIFEST_PREFIX)
+            .and_then(|version| version.trim_end().parse().ok())
+            .map(Some)
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "corrupted format manifest").into()
+            }),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            let mut legacy = false;
+            for dir in [SECTORS_DIR, STABLE_STORAGE_DIR] {
+                legacy |= fs::metadata(storage_dir.join(dir)).await.is_ok();
+            }
+            Ok(legacy.then_some(1))
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+async fn write_version(storage_dir: &Path, version: u32) -> io::Result<()> {
+    let tmp_path = storage_dir.join(FORMAT_TMP_FILENAME);
+    let mut tmp = File::create(&tmp_path).await?;
+    tmp.write_all(format!("{}{}\n", MANIFEST_PREFIX, version).as_bytes())
+        .await?;
+    tmp.sync_data().await?;
+    fs::rename(&tmp_path, storage_dir.join(FORMAT_FILENAME)).await?;
+    File::open(storage_dir).await?.sync_data().await
+}
+
+/// Upgrades storage of version `version` to the next one.
+async fn upgrade(storage_dir: &Path, version: u32) -> io::Result<()> {
+    match version {
+        1 => migrations::add_checksums(&storage_dir.join(SECTORS_DIR)).await,
+        // Only tells older versions they can't read compressed sectors.
+        2 => Ok(()),
+        _ => unreachable!("No upgrade from version {}", version),
+    }
+}
+
+/// Brings storage in `storage_dir` to `CURRENT_VERSION`, and returns the
+/// version it had. Storage of an unknown version is left untouched.
+pub(crate) async fn migrate(storage_dir: &Path) -> Result<u32, StorageError> {
+    let initial = match read_version(storage_dir).await? {
+        Some(version) => version,
+        None => {
+            write_version(storage_dir, CURRENT_VERSION).await?;
+            return Ok(CURRENT_VERSION);
+        }
+    };
+    if initial == 0 || initial > CURRENT_VERSION {
+        return Err(StorageError::UnsupportedFormat(initial));
+    }
+    for version in initial..CURRENT_VERSION {
+        info!(
+            "Upgrading storage format from version {} to {}",
+            version,
+            version + 1
+        );
+        upgrade(storage_dir, version).await?;
+        write_version(storage_dir, version + 1).await?;
+    }
+    Ok(initial)
+}
+
+#[tokio::test]
+async fn test_legacy_storage_is_upgraded() {
+    use crate::solution::sectors_manager::encode_filename;
+    use crate::solution::transfer::SECTOR_LEN;
+    use crate::{build_sectors_manager, SectorVec};
+
+    let dir = tempfile::tempdir().unwrap();
+    let sectors_dir = dir.path().join(SECTORS_DIR);
+    fs::create_dir_all(&sectors_dir).await.unwrap();
+    let sector_path = sectors_dir.join(encode_filename((3, 1, 1)));
+    fs::write(&sector_path, vec![3; SECTOR_LEN]).await.unwrap();
+
+    assert_eq!(migrate(dir.path()).await.unwrap(), 1);
+    assert_eq!(
+        read_version(dir.path()).await.unwrap(),
+        Some(CURRENT_VERSION)
+    );
+    let content = fs::read(&sector_path).await.unwrap();
+    assert_eq!(content.len(), SECTOR_LEN + 4);
+    let manager = build_sectors_manager(sectors_dir).await.unwrap();
+    assert_eq!(
+        manager.read_data(3).await.unwrap(),
+        SectorVec(vec![3; SECTOR_LEN])
+    );
+    assert_eq!(migrate(dir.path()).await.unwrap(), CURRENT_VERSION);
+
+    write_version(dir.path(), CURRENT_VERSION + 1)
+        .await
+        .unwrap();
+    assert!(matches!(
+        migrate(dir.path()).await,
+        Err(StorageError::UnsupportedFormat(version)) if version == CURRENT_VERSION + 1
+    ));
+}
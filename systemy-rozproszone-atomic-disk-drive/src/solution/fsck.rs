@@ -0,0 +1,208 @@
+//! Offline check of the storage directory of a stopped process.
+use crate::solution::format;
+use crate::solution::running::paths_manager::{worker_dir_name, SECTORS_DIR, STABLE_STORAGE_DIR};
+use crate::solution::sectors_manager::fsck::check_sectors;
+use crate::solution::stable_storage::{decode_value, key_filename, TMPFILE_PREFIX};
+use crate::{StorageError, StorageProblem, StorageReport};
+use std::io;
+use std::path::{Path, PathBuf};
+use tokio::fs::{self, File};
+
+async fn remove(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path).await {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+        _ => File::open(path.parent().unwrap()).await?.sync_data().await,
+    }
+}
+
+/// Checks the stable storage of a worker. A corrupted rid is removed on
+/// repair, the register would reset it to 0 anyway.
+async fn check_worker(
+    dir: &Path,
+    worker_id: u8,
+    repair: bool,
+    report: &mut StorageReport,
+) -> Result<(), StorageError> {
+    let mut entries = fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name();
+        if !(name.to_str()).is_some_and(|name| name.starts_with(TMPFILE_PREFIX)) {
+            continue;
+        }
+        let tmpfile = entry.path();
+        let problem = StorageProblem::LeftoverTmpfile(tmpfile.clone());
+        if repair {
+            remove(&tmpfile).await?;
+            report.repaired.push(problem);
+        } else {
+            report.problems.push(problem);
+        }
+    }
+
+    let rid_path = dir.join(key_filename("rid"));
+    let content = match fs::read(&rid_path).await {
+        Ok(content) => content,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            report.rids.push((worker_id, None));
+            return Ok(());
+        }
+        Err(err) => return Err(err.into()),
+    };
+    let rid = decode_value(&content)
+        .and_then(|value| value.try_into().ok())
+        .map(u64::from_be_bytes);
+    report.rids.push((worker_id, rid));
+    if rid.is_none() {
+        if repair {
+            remove(&rid_path).await?;
+            report
+                .repaired
+                .push(StorageProblem::CorruptedRid(worker_id));
+        } else {
+            report
+                .problems
+                .push(StorageProblem::CorruptedRid(worker_id));
+        }
+    }
+    Ok(())
+}
+
+pub(crate) async fn check_storage(
+    storage_dir: PathBuf,
+    repair: bool,
+) -> Result<StorageReport, StorageError> {
+    let mut report = StorageReport::default();
+
+    let sectors_dir = storage_dir.join(SECTORS_DIR);
+    if fs::metadata(&sectors_dir).await.is_ok() {
+        let version = format::read_version(&storage_dir).await?;
+        let legacy = version.is_some_and(|version| version < format::CHECKSUMS_VERSION);
+        check_sectors(&sectors_dir, legacy, repair, &mut report).await?;
+    }
+
+    let stable_dir = storage_dir.join(STABLE_STORAGE_DIR);
+    if let Ok(mut entries) = fs::read_dir(&stable_dir).await {
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name();
+            let worker_id = (0..=u8::MAX).find(|id| name.to_str() == Some(&worker_dir_name(*id)));
+            match worker_id {
+                Some(worker_id) if entry.file_type().await?.is_dir() => {
+                    check_worker(&entry.path(), worker_id, repair, &mut report).await?;
+                }
+                _ => report
+                    .problems
+                    .push(StorageProblem::UnknownFile(entry.path())),
+            }
+        }
+    }
+
+    report.sectors.sort_by_key(|sector| sector.idx);
+    report.rids.sort_unstable();
+    Ok(report)
+}
+
+#[tokio::test]
+async fn test_check_storage_finds_and_repairs_leftovers() {
+    use crate::solution::sectors_manager::encode_filename;
+    use crate::solution::transfer::SECTOR_LEN;
+    use crate::{build_sectors_manager, SectorState, SectorVec};
+
+    let dir = tempfile::tempdir().unwrap();
+    let sectors_dir = dir.path().join(SECTORS_DIR);
+    fs::create_dir_all(&sectors_dir).await.unwrap();
+    let manager = build_sectors_manager(sectors_dir.clone()).await.unwrap();
+    for (idx, logical_timestamp) in [(1, 1), (1, 2), (3, 1)] {
+        manager
+            .write(
+                idx,
+                &(SectorVec(vec![idx as u8; SECTOR_LEN]), logical_timestamp, 1),
+            )
+            .await
+            .unwrap();
+    }
+    drop(manager);
+
+    let worker_dir = dir.path().join(STABLE_STORAGE_DIR).join(worker_dir_name(2));
+    fs::create_dir_all(&worker_dir).await.unwrap();
+    fs::write(worker_dir.join(key_filename("rid")), "not base64!")
+        .await
+        .unwrap();
+
+    // A stale version and a tmpfile, as left by interrupted writes, and a
+    // sector corrupted on disk.
+    let encode = |idx, logical_timestamp| encode_filename((idx, logical_timestamp, 1));
+    fs::write(sectors_dir.join(encode(1, 1)), vec![1; SECTOR_LEN])
+        .await
+        .unwrap();
+    fs::write(sectors_dir.join(format!("tmpfile{}", encode(5, 1))), [5])
+        .await
+        .unwrap();
+    let mut content = fs::read(sectors_dir.join(encode(3, 1))).await.unwrap();
+    content[0] ^= 1;
+    fs::write(sectors_dir.join(encode(3, 1)), content)
+        .await
+        .unwrap();
+
+    let report = check_storage(dir.path().to_path_buf(), false)
+        .await
+        .unwrap();
+    let states: Vec<_> = report
+        .sectors
+        .iter()
+        .map(|sector| (sector.idx, sector.logical_timestamp, sector.state))
+        .collect();
+    assert_eq!(
+        states,
+        vec![(1, 2, SectorState::Ok), (3, 1, SectorState::Corrupted)]
+    );
+    assert_eq!(report.rids, vec![(2, None)]);
+    assert_eq!(report.problems.len(), 4);
+    assert!(report.problems.contains(&StorageProblem::StaleVersion {
+        idx: 1,
+        logical_timestamp: 1,
+        write_rank: 1
+    }));
+    assert!(report.problems.contains(&StorageProblem::LeftoverTmpfile(
+        sectors_dir.join(format!("tmpfile{}", encode(5, 1)))
+    )));
+    assert!(report.problems.contains(&StorageProblem::CorruptedRid(2)));
+    assert!(report
+        .problems
+        .contains(&StorageProblem::CorruptedSector(3)));
+
+    let report = check_storage(dir.path().to_path_buf(), true).await.unwrap();
+    assert_eq!(report.problems, vec![StorageProblem::CorruptedSector(3)]);
+    assert_eq!(report.repaired.len(), 3);
+
+    let report = check_storage(dir.path().to_path_buf(), false)
+        .await
+        .unwrap();
+    assert_eq!(report.problems, vec![StorageProblem::CorruptedSector(3)]);
+    assert_eq!(report.rids, vec![(2, None)]);
+}
+
+#[tokio::test]
+async fn test_unchecksummed_sector_is_unverified_only_before_upgrade() {
+    use crate::solution::sectors_manager::encode_filename;
+    use crate::solution::transfer::SECTOR_LEN;
+    use crate::SectorState;
+
+    let dir = tempfile::tempdir().unwrap();
+    let sectors_dir = dir.path().join(SECTORS_DIR);
+    fs::create_dir_all(&sectors_dir).await.unwrap();
+    let sector_path = sectors_dir.join(encode_filename((1, 1, 1)));
+    fs::write(&sector_path, vec![1; SECTOR_LEN]).await.unwrap();
+    let state = |report: StorageReport| report.sectors[0].state;
+
+    let report = check_storage(dir.path().to_path_buf(), false).await;
+    assert_eq!(state(report.unwrap()), SectorState::Unverified);
+
+    format::migrate(dir.path()).await.unwrap();
+    let report = check_storage(dir.path().to_path_buf(), false).await;
+    assert_eq!(state(report.unwrap()), SectorState::Ok);
+
+    // The upgrade added checksums, so a file without one is damaged.
+    fs::write(&sector_path, vec![1; SECTOR_LEN]).await.unwrap();
+    let report = check_storage(dir.path().to_path_buf(), false).await;
+    assert_eq!(state(report.unwrap()), SectorState::Corrupted);
+}
@@ -0,0 +1,254 @@
+//! Images of the sectors of a process, for moving its data between clusters.
+//!
+//! An archive starts with `ARCHIVE_MAGIC`, the number of sectors of the
+//! exporting process and the number of records, followed by a record for
+//! every written sector: its index, timestamp, write rank, data and the
+//! checksum of the preceding bytes. Integers are little endian. A raw image
+//! is the content of all sectors, one after another.
+use crate::solution::running::paths_manager::PathsManager;
+use crate::solution::running::NUMBER_OF_WORKERS;
+use crate::solution::transfer::SECTOR_LEN;
+use crate::{
+    ImageFormat, SectorIdx, SectorVec, SectorsManager, StorageConfiguration, StorageError,
+};
+use std::io;
+use std::path::PathBuf;
+use tokio::fs;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+const ARCHIVE_MAGIC: &[u8; 8] = b"ATDDIMG1";
+const HEADER_LEN: usize = 8 + 8 + 8;
+/// Sector index, timestamp, write rank, data and the checksum.
+const RECORD_LEN: usize = 8 + 8 + 1 + SECTOR_LEN + 4;
+/// Metadata of sectors imported from a raw image. No process has rank 0, so
+/// any write replaces them.
+const RAW_SECTOR_METADATA: (u64, u8) = (1, 0);
+
+fn invalid_data(msg: &str) -> StorageError {
+    io::Error::new(io::ErrorKind::InvalidData, msg).into()
+}
+
+fn encode_record(
+    idx: SectorIdx,
+    (logical_timestamp, write_rank): (u64, u8),
+    data: &[u8],
+) -> Vec<u8> {
+    let mut record = Vec::with_capacity(RECORD_LEN);
+    record.extend_from_slice(&idx.to_le_bytes());
+    record.extend_from_slice(&logical_timestamp.to_le_bytes());
+    record.push(write_rank);
+    record.extend_from_slice(data);
+    let crc = crc32c::crc32c(&record);
+    record.extend_from_slice(&crc.to_le_bytes());
+    record
+}
+
+fn decode_record(record: &[u8]) -> Option<(SectorIdx, (u64, u8), SectorVec)> {
+    let (body, crc) = record.split_at(RECORD_LEN - 4);
+    if crc32c::crc32c(body).to_le_bytes() != crc {
+        return None;
+    }
+    let idx = u64::from_le_bytes(body[0..8].try_into().unwrap());
+    let logical_timestamp = u64::from_le_bytes(body[8..16].try_into().unwrap());
+    Some((
+        idx,
+        (logical_timestamp, body[16]),
+        SectorVec(body[17..].to_vec()),
+    ))
+}
+
+/// Like `read_exact`, but returns false on the end of input before any byte.
+async fn read_next(
+    reader: &mut (dyn AsyncRead + Send + Unpin),
+    buf: &mut [u8],
+) -> io::Result<bool> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]).await? {
+            0 if filled == 0 => return Ok(false),
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            n => filled += n,
+        }
+    }
+    Ok(true)
+}
+
+pub(crate) async fn export_image(
+    sectors_manager: &dyn SectorsManager,
+    n_sectors: u64,
+    format: ImageFormat,
+    writer: &mut (dyn AsyncWrite + Send + Unpin),
+) -> Result<u64, StorageError> {
+    let exported = match format {
+        ImageFormat::Archive => {
+            let mut written = vec![];
+            for idx in 0..n_sectors {
+                let meta = sectors_manager.read_metadata(idx).await?;
+                if meta != (0, 0) {
+                    written.push((idx, meta));
+                }
+            }
+            let mut header = ARCHIVE_MAGIC.to_vec();
+            header.extend_from_slice(&n_sectors.to_le_bytes());
+            header.extend_from_slice(&(written.len() as u64).to_le_bytes());
+            writer.write_all(&header).await?;
+            for (idx, meta) in &written {
+                let SectorVec(data) = sectors_manager.read_data(*idx).await?;
+                writer.write_all(&encode_record(*idx, *meta, &data)).await?;
+            }
+            written.len() as u64
+        }
+        ImageFormat::Raw => {
+            for idx in 0..n_sectors {
+                let SectorVec(data) = sectors_manager.read_data(idx).await?;
+                writer.write_all(&data).await?;
+            }
+            n_sectors
+        }
+    };
+    writer.flush().await?;
+    Ok(exported)
+}
+
+pub(crate) async fn import_image(
+    storage_dir: PathBuf,
+    n_sectors: u64,
+    config: &StorageConfiguration,
+    format: ImageFormat,
+    reader: &mut (dyn AsyncRead + Send + Unpin),
+) -> Result<u64, StorageError> {
+    if let Ok(mut entries) = fs::read_dir(&storage_dir).await {
+        if entries.next_entry().await?.is_some() {
+            return Err(StorageError::InvalidInput("storage directory is not empty"));
+        }
+    }
+    fs::create_dir_all(&storage_dir).await?;
+    let mut paths_manager = PathsManager::new(storage_dir, n_sectors, config.clone(), None).await?;
+    for worker_id in 0..NUMBER_OF_WORKERS {
+        paths_manager.get_stable_storage(worker_id as u8).await?;
+    }
+    let sectors_manager = paths_manager.get_sectors_manager().await?;
+
+    let mut imported = 0;
+    match format {
+        ImageFormat::Archive => {
+            let mut header = [0; HEADER_LEN];
+            reader.read_exact(&mut header).await?;
+            if &header[..8] != ARCHIVE_MAGIC {
+                return Err(invalid_data("not a sectors archive"));
+            }
+            let count = u64::from_le_bytes(header[16..24].try_into().unwrap());
+            let mut record = vec![0; RECORD_LEN];
+            for _ in 0..count {
+                reader.read_exact(&mut record).await?;
+                let (idx, (logical_timestamp, write_rank), data) = decode_record(&record)
+                    .ok_or_else(|| invalid_data("corrupted sector record"))?;
+                if idx >= n_sectors {
+                    return Err(StorageError::InvalidInput("sector index out of range"));
+                }
+                sectors_manager
+                    .write(idx, &(data, logical_timestamp, write_rank))
+                    .await?;
+                imported += 1;
+            }
+        }
+        ImageFormat::Raw => {
+            let mut data = vec![0; SECTOR_LEN];
+            let mut idx = 0;
+            while read_next(reader, &mut data).await? {
+                if idx >= n_sectors {
+                    return Err(StorageError::InvalidInput("sector index out of range"));
+                }
+                // Zeroed sectors are the same as never written ones.
+                if data.iter().any(|b| *b != 0) {
+                    let (logical_timestamp, write_rank) = RAW_SECTOR_METADATA;
+                    sectors_manager
+                        .write(
+                            idx,
+                            &(SectorVec(data.clone()), logical_timestamp, write_rank),
+                        )
+                        .await?;
+                    imported += 1;
+                }
+                idx += 1;
+            }
+        }
+    }
+    Ok(imported)
+}
+
+#[tokio::test]
+async fn test_image_round_trip() {
+    use crate::{build_sectors_manager_with_config, SectorsBackend};
+
+    let source = tempfile::tempdir().unwrap();
+    let manager =
+        build_sectors_manager_with_config(source.path().to_path_buf(), 8, &Default::default())
+            .await
+            .unwrap();
+    manager
+        .write(2, &(SectorVec(vec![2; SECTOR_LEN]), 5, 3))
+        .await
+        .unwrap();
+    manager
+        .write(7, &(SectorVec(vec![7; SECTOR_LEN]), 1, 1))
+        .await
+        .unwrap();
+
+    let config = StorageConfiguration {
+        sectors_backend: SectorsBackend::DataFile,
+        ..Default::default()
+    };
+    for format in [ImageFormat::Archive, ImageFormat::Raw] {
+        let mut image = vec![];
+        export_image(manager.as_ref(), 8, format, &mut image)
+            .await
+            .unwrap();
+        let target = tempfile::tempdir().unwrap();
+        let imported = import_image(
+            target.path().to_path_buf(),
+            8,
+            &config,
+            format,
+            &mut &image[..],
+        )
+        .await
+        .unwrap();
+        assert_eq!(imported, 2);
+        assert!(target
+            .path()
+            .join(format!(
+                "stable_storage/worker_{:#04x}",
+                NUMBER_OF_WORKERS - 1
+            ))
+            .is_dir());
+
+        let imported =
+            build_sectors_manager_with_config(target.path().join("sectors_manager"), 8, &config)
+                .await
+                .unwrap();
+        for idx in 0..8 {
+            assert_eq!(
+                imported.read_data(idx).await.unwrap(),
+                manager.read_data(idx).await.unwrap()
+            );
+        }
+        let expected = match format {
+            ImageFormat::Archive => (5, 3),
+            ImageFormat::Raw => RAW_SECTOR_METADATA,
+        };
+        assert_eq!(imported.read_metadata(2).await.unwrap(), expected);
+        assert_eq!(imported.read_metadata(3).await.unwrap(), (0, 0));
+
+        // The storage of a process is never overwritten.
+        let again = import_image(
+            target.path().to_path_buf(),
+            8,
+            &config,
+            format,
+            &mut &image[..],
+        )
+        .await;
+        assert!(matches!(again, Err(StorageError::InvalidInput(_))));
+    }
+}
@@ -1,6 +1,14 @@
+pub mod anti_entropy;
 pub mod atomic_register;
+pub mod failure_detector;
+pub mod format;
+pub mod fsck;
+pub mod image;
 pub mod register_client;
 pub mod running;
 pub mod sectors_manager;
 pub mod stable_storage;
+#[cfg(test)]
+pub(crate) mod test_cluster;
 pub mod transfer;
+pub mod transport;
@@ -1,23 +1,24 @@
+use super::peer_queue::PeerQueue;
 use crate::solution::transfer;
 use crate::*;
 use log::*;
+use std::sync::Arc;
 use tokio;
-use tokio::net::TcpStream;
-use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
 use tokio::time::{self, Duration};
 
 async fn run_connector_actor(
+    transport: Arc<dyn Transport>,
     hmac_key: [u8; 64],
-    location: (String, u16),
-    mut rx: UnboundedReceiver<SystemRegisterCommand>,
+    location: Location,
+    queue: Arc<PeerQueue>,
 ) {
     loop {
         time::sleep(Duration::from_millis(200)).await;
-        if let Ok(mut tcp_stream) = TcpStream::connect(&location).await {
-            while let Some(cmd) = rx.recv().await {
-                let command = RegisterCommand::System(cmd);
+        if let Ok(mut stream) = transport.connect(&location).await {
+            loop {
+                let command = RegisterCommand::System(queue.pop().await);
 
-                if transfer::serialize_register_command(&command, &mut tcp_stream, &hmac_key)
+                if transfer::serialize_register_command(&command, &mut stream, &hmac_key)
                     .await
                     .is_err()
                 {
@@ -25,25 +26,38 @@ async fn run_connector_actor(
                 }
             }
         }
-        trace!("One of connections broke, reconnecting...");
+        trace!("Connection to {} broke, reconnecting...", location);
     }
 }
 
 #[derive(Clone)]
 pub(crate) struct ConnectorActorHandle {
-    tx: UnboundedSender<SystemRegisterCommand>,
+    queue: Arc<PeerQueue>,
 }
 
 impl ConnectorActorHandle {
-    pub(crate) fn new(hmac_key: &[u8; 64], location: &(String, u16)) -> Self {
-        let (tx, rx) = mpsc::unbounded_channel();
-        tokio::spawn(run_connector_actor(hmac_key.clone(), location.clone(), rx));
-        Self { tx }
+    pub(crate) fn new(
+        transport: Arc<dyn Transport>,
+        hmac_key: &[u8; 64],
+        location: &Location,
+        process_identifier: u8,
+        queue_capacity: usize,
+    ) -> Self {
+        let queue = Arc::new(PeerQueue::new(process_identifier, queue_capacity));
+        tokio::spawn(run_connector_actor(
+            transport,
+            *hmac_key,
+            location.clone(),
+            queue.clone(),
+        ));
+        Self { queue }
     }
 
     pub(crate) fn send(&self, cmd: SystemRegisterCommand) {
-        if self.tx.send(cmd).is_err() {
-            error!("One of the connectors stoped working");
-        }
+        self.queue.push(cmd);
+    }
+
+    pub(crate) fn stats(&self) -> PeerQueueStats {
+        self.queue.stats()
     }
 }
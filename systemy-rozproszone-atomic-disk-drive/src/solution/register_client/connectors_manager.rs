@@ -1,6 +1,7 @@
 use super::connector::ConnectorActorHandle;
 use crate::*;
 use std::ops::Deref;
+use std::sync::Arc;
 
 #[derive(Clone)]
 pub(crate) struct ConnectorsManager {
@@ -10,15 +11,23 @@ pub(crate) struct ConnectorsManager {
 impl ConnectorsManager {
     pub(crate) fn new(
         self_ident: u8,
-        tcp_locations: &Vec<(String, u16)>,
+        tcp_locations: &[Location],
         hmac_key: &[u8; 64],
+        network: &NetworkConfiguration,
+        transport: Arc<dyn Transport>,
     ) -> Self {
         let mut handles = vec![];
         for (i, loc) in tcp_locations.iter().enumerate() {
             if i + 1 == (self_ident as usize) {
                 handles.push(None);
             } else {
-                handles.push(Some(ConnectorActorHandle::new(hmac_key, loc)));
+                handles.push(Some(ConnectorActorHandle::new(
+                    transport.clone(),
+                    hmac_key,
+                    loc,
+                    (i + 1) as u8,
+                    network.peer_queue_capacity,
+                )));
             }
         }
         ConnectorsManager { handles }
@@ -31,10 +40,12 @@ impl ConnectorsManager {
     }
 
     pub(crate) fn broadcast(&self, cmd: Broadcast) {
-        for opt in self.handles.iter() {
-            if let Some(handle) = opt {
-                handle.send(cmd.cmd.deref().to_owned());
-            }
+        for handle in self.handles.iter().flatten() {
+            handle.send(cmd.cmd.deref().to_owned());
         }
     }
+
+    pub(crate) fn queue_stats(&self) -> Vec<PeerQueueStats> {
+        self.handles.iter().flatten().map(|h| h.stats()).collect()
+    }
 }
@@ -1,22 +1,30 @@
 mod connector;
 mod connectors_manager;
+mod peer_queue;
 mod resender;
 
 use crate::solution::atomic_register::utils as arutils;
+use crate::solution::failure_detector::FailureDetector;
 use crate::*;
 use std::sync::Arc;
+use tokio::sync::mpsc::UnboundedSender;
+use uuid::Uuid;
 
 use self::connectors_manager::ConnectorsManager;
 use self::resender::ResenderActorHandle;
 
-/// Unlike the trait specification this client won't send
-/// self messages, furthermore SolutionRegisterClient is
-/// implemented such that it doesn't need such messages.
+/// Unless built with a loopback, unlike the trait specification
+/// this client won't send self messages, and SolutionAtomicRegister
+/// answers its own questions instead. With a loopback, self messages
+/// are passed to it, and it delivers them like messages from others.
 /// Stubborness is done by resending only the newest
-/// message for every UUID. Only question messages are resend.
+/// message for every UUID. Only question messages are resend,
+/// and only to processes which haven't answered them yet.
 pub(crate) struct SolutionRegisterClient {
+    self_rank: u8,
     resender: ResenderActorHandle,
     manager: ConnectorsManager,
+    loopback: Option<UnboundedSender<SystemRegisterCommand>>,
 }
 
 #[async_trait::async_trait]
@@ -24,23 +32,96 @@ impl RegisterClient for SolutionRegisterClient {
     async fn send(&self, msg: Send) {
         assert!(arutils::is_answer_command(&msg.cmd));
         assert!(msg.target >= 1);
-        self.manager.send(msg.clone());
-        self.resender.process_send(msg);
+        match &self.loopback {
+            Some(loopback) if msg.target == self.self_rank => {
+                self.deliver_to_self(loopback, &msg.cmd);
+            }
+            _ => {
+                self.manager.send(msg.clone());
+                self.resender.process_send(msg);
+            }
+        }
     }
 
     async fn broadcast(&self, msg: Broadcast) {
         assert!(arutils::is_proc_command(&msg.cmd));
+        self.resender.process_broadcast(msg.clone());
         self.manager.broadcast(msg.clone());
-        self.resender.process_broadcast(msg);
+        if let Some(loopback) = &self.loopback {
+            self.deliver_to_self(loopback, &msg.cmd);
+        }
+    }
+}
+
+impl SolutionRegisterClient {
+    fn deliver_to_self(
+        &self,
+        loopback: &UnboundedSender<SystemRegisterCommand>,
+        cmd: &SystemRegisterCommand,
+    ) {
+        if loopback.send(cmd.clone()).is_err() {
+            log::error!("Cannot deliver message to self");
+        }
+    }
+
+    pub(crate) fn peer_queue_stats(&self) -> Vec<PeerQueueStats> {
+        self.manager.queue_stats()
+    }
+
+    /// Informs the client that an answer arrived from another process,
+    /// so the question it answers won't be resent to that process.
+    pub(crate) fn process_answer(&self, cmd: &SystemRegisterCommand) {
+        self.resender.process_answer(cmd);
+    }
+
+    /// Sends a message to another process once, without resending it.
+    pub(crate) fn send_unreliable(&self, msg: Send) {
+        self.manager.send(msg);
+    }
+
+    pub(crate) fn heartbeat(&self) {
+        self.manager.broadcast(Broadcast {
+            cmd: Arc::new(SystemRegisterCommand {
+                header: SystemCommandHeader {
+                    process_identifier: self.self_rank,
+                    msg_ident: Uuid::nil(),
+                    read_ident: 0,
+                    sector_idx: 0,
+                },
+                content: SystemRegisterCommandContent::Heartbeat,
+            }),
+        });
     }
 }
 
 pub(crate) async fn build_register_client(
     self_rank: u8,
-    tcp_locations: Vec<(String, u16)>,
+    tcp_locations: Vec<Location>,
     hmac_system_key: &[u8; 64],
-) -> Arc<dyn RegisterClient> {
-    let manager = ConnectorsManager::new(self_rank, &tcp_locations, hmac_system_key);
-    let resender = ResenderActorHandle::new(manager.clone());
-    Arc::new(SolutionRegisterClient { resender, manager })
+    network: &NetworkConfiguration,
+    failure_detector: FailureDetector,
+    transport: Arc<dyn Transport>,
+    loopback: Option<UnboundedSender<SystemRegisterCommand>>,
+) -> Arc<SolutionRegisterClient> {
+    let manager = ConnectorsManager::new(
+        self_rank,
+        &tcp_locations,
+        hmac_system_key,
+        network,
+        transport,
+    );
+    let resender = ResenderActorHandle::new(
+        manager.clone(),
+        self_rank,
+        tcp_locations.len() as u8,
+        network,
+        failure_detector,
+        loopback.is_some(),
+    );
+    Arc::new(SolutionRegisterClient {
+        self_rank,
+        resender,
+        manager,
+        loopback,
+    })
 }
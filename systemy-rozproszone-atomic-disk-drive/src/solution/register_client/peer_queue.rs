@@ -0,0 +1,169 @@
+//! Bounded queue of messages waiting to be sent to a single process.
+//!
+//! A process which is down must not make us hold an unbounded number of
+//! sector copies, so the queue sheds messages once it is full. Messages with
+//! the same `msg_ident` collapse into the newest one, and proc messages are
+//! dropped before answers, as proc messages are resent by the resender anyway.
+//! This is load shedding, not backpressure: pushing never waits.
+use crate::solution::atomic_register::utils as arutils;
+use crate::*;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use tokio::sync::Notify;
+
+pub(crate) struct PeerQueue {
+    process_identifier: u8,
+    capacity: usize,
+    state: Mutex<PeerQueueState>,
+    notify: Notify,
+}
+
+struct PeerQueueState {
+    queue: VecDeque<SystemRegisterCommand>,
+    collapsed: u64,
+    shed: u64,
+}
+
+/// Messages sharing `msg_ident` belong to consecutive phases of one operation,
+/// a message of a later phase makes the earlier one obsolete.
+fn phase(cmd: &SystemRegisterCommand) -> u8 {
+    match cmd.content {
+        SystemRegisterCommandContent::ReadProc
+        | SystemRegisterCommandContent::Value { .. }
+        | SystemRegisterCommandContent::Heartbeat
+        | SystemRegisterCommandContent::RangeDigest { .. }
+        | SystemRegisterCommandContent::RangeVersions { .. } => 0,
+        SystemRegisterCommandContent::WriteProc { .. } | SystemRegisterCommandContent::Ack => 1,
+    }
+}
+
+impl PeerQueue {
+    /// `capacity` is positive, as the configuration is validated on startup.
+    pub(crate) fn new(process_identifier: u8, capacity: usize) -> Self {
+        assert!(capacity > 0);
+        PeerQueue {
+            process_identifier,
+            capacity,
+            state: Mutex::new(PeerQueueState {
+                queue: VecDeque::new(),
+                collapsed: 0,
+                shed: 0,
+            }),
+            notify: Notify::new(),
+        }
+    }
+
+    pub(crate) fn push(&self, cmd: SystemRegisterCommand) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(old) = state
+            .queue
+            .iter_mut()
+            .find(|old| old.header.msg_ident == cmd.header.msg_ident)
+        {
+            if phase(&cmd) >= phase(old) {
+                *old = cmd;
+            }
+            state.collapsed += 1;
+            return;
+        }
+        if state.queue.len() >= self.capacity {
+            state.shed += 1;
+            let victim = state.queue.iter().position(arutils::is_proc_command).or(
+                if arutils::is_proc_command(&cmd) {
+                    None
+                } else {
+                    Some(0)
+                },
+            );
+            match victim {
+                Some(position) => {
+                    state.queue.remove(position);
+                }
+                None => return,
+            }
+        }
+        state.queue.push_back(cmd);
+        drop(state);
+        self.notify.notify_one();
+    }
+
+    pub(crate) async fn pop(&self) -> SystemRegisterCommand {
+        loop {
+            if let Some(cmd) = self.state.lock().unwrap().queue.pop_front() {
+                return cmd;
+            }
+            self.notify.notified().await;
+        }
+    }
+
+    pub(crate) fn stats(&self) -> PeerQueueStats {
+        let state = self.state.lock().unwrap();
+        PeerQueueStats {
+            process_identifier: self.process_identifier,
+            queued: state.queue.len(),
+            capacity: self.capacity,
+            collapsed: state.collapsed,
+            shed: state.shed,
+        }
+    }
+}
+
+#[cfg(test)]
+fn test_command(
+    msg_ident: uuid::Uuid,
+    content: SystemRegisterCommandContent,
+) -> SystemRegisterCommand {
+    SystemRegisterCommand {
+        header: SystemCommandHeader {
+            process_identifier: 1,
+            msg_ident,
+            read_ident: 1,
+            sector_idx: 0,
+        },
+        content,
+    }
+}
+
+#[tokio::test]
+async fn test_peer_queue_collapses_same_msg_ident() {
+    let queue = PeerQueue::new(2, 4);
+    let uuid = uuid::Uuid::new_v4();
+    queue.push(test_command(uuid, SystemRegisterCommandContent::ReadProc));
+    queue.push(test_command(
+        uuid,
+        SystemRegisterCommandContent::WriteProc {
+            timestamp: 1,
+            write_rank: 1,
+            data_to_write: SectorVec(vec![0; 4096]),
+        },
+    ));
+    // A late ReadProc must not replace the WriteProc.
+    queue.push(test_command(uuid, SystemRegisterCommandContent::ReadProc));
+    let stats = queue.stats();
+    assert_eq!(stats.queued, 1);
+    assert_eq!(stats.collapsed, 2);
+    assert!(arutils::is_proc_command(&queue.pop().await));
+}
+
+#[tokio::test]
+async fn test_peer_queue_sheds_proc_messages_first() {
+    let queue = PeerQueue::new(2, 2);
+    let answer = uuid::Uuid::new_v4();
+    queue.push(test_command(
+        uuid::Uuid::new_v4(),
+        SystemRegisterCommandContent::ReadProc,
+    ));
+    queue.push(test_command(answer, SystemRegisterCommandContent::Ack));
+    queue.push(test_command(
+        uuid::Uuid::new_v4(),
+        SystemRegisterCommandContent::ReadProc,
+    ));
+    queue.push(test_command(
+        uuid::Uuid::new_v4(),
+        SystemRegisterCommandContent::ReadProc,
+    ));
+    let stats = queue.stats();
+    assert_eq!(stats.queued, 2);
+    assert_eq!(stats.shed, 2);
+    assert_eq!(queue.pop().await.header.msg_ident, answer);
+}
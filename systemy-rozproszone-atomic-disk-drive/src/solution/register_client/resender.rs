@@ -1,27 +1,82 @@
 use super::connectors_manager::ConnectorsManager;
+use crate::solution::failure_detector::FailureDetector;
 use crate::*;
 use log::*;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use tokio;
 use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
-use tokio::time::{self, Duration};
+use tokio::time::{self, Duration, Instant};
 use uuid::Uuid;
 
+struct PendingBroadcast {
+    broadcast: Broadcast,
+    /// Processes which have already answered the current phase. Once they are
+    /// a majority the phase is complete, and the broadcast isn't resent anymore.
+    answered: HashSet<u8>,
+    interval: Duration,
+    deadline: Instant,
+}
+
 struct ResenderActor {
-    resends: HashMap<Uuid, Broadcast>,
+    resends: HashMap<Uuid, PendingBroadcast>,
     manager: ConnectorsManager,
+    failure_detector: FailureDetector,
+    self_ident: u8,
+    processes_count: u8,
+    /// Whether self answers are delivered, otherwise self is assumed to answer
+    /// its own broadcasts immediately.
+    self_delivery: bool,
+    resend_interval: Duration,
+    max_resend_interval: Duration,
+    resend_backoff: f64,
 }
 
 enum ResenderActorMessage {
     ToCancel(Uuid),
     ToSend(Broadcast),
+    Answered {
+        msg_ident: Uuid,
+        process_identifier: u8,
+        is_ack: bool,
+    },
+}
+
+/// Whether an answer (`Ack` if `is_ack`, `Value` otherwise) responds to `proc`.
+fn answers(proc: &SystemRegisterCommand, is_ack: bool) -> bool {
+    match proc.content {
+        SystemRegisterCommandContent::ReadProc => !is_ack,
+        SystemRegisterCommandContent::WriteProc { .. } => is_ack,
+        _ => false,
+    }
+}
+
+/// Delay before the retransmission following one after `interval`, at most
+/// `max`, also if it doesn't fit in a `Duration`.
+fn backoff(interval: Duration, factor: f64, max: Duration) -> Duration {
+    Duration::try_from_secs_f64(interval.as_secs_f64() * factor)
+        .unwrap_or(max)
+        .min(max)
 }
 
 impl ResenderActor {
-    fn new(manager: ConnectorsManager) -> Self {
+    fn new(
+        manager: ConnectorsManager,
+        self_ident: u8,
+        processes_count: u8,
+        network: &NetworkConfiguration,
+        failure_detector: FailureDetector,
+        self_delivery: bool,
+    ) -> Self {
         Self {
             resends: HashMap::new(),
             manager,
+            failure_detector,
+            self_ident,
+            processes_count,
+            self_delivery,
+            resend_interval: network.resend_interval,
+            max_resend_interval: network.max_resend_interval,
+            resend_backoff: network.resend_backoff,
         }
     }
 
@@ -32,14 +87,70 @@ impl ResenderActor {
             }
             ResenderActorMessage::ToSend(broadcast) => {
                 let uuid = broadcast.cmd.header.msg_ident;
-                self.resends.insert(uuid, broadcast);
+                let mut answered = HashSet::new();
+                if !self.self_delivery {
+                    answered.insert(self.self_ident);
+                }
+                self.resends.insert(
+                    uuid,
+                    PendingBroadcast {
+                        broadcast,
+                        answered,
+                        interval: self.resend_interval,
+                        deadline: Instant::now() + self.resend_interval,
+                    },
+                );
+            }
+            ResenderActorMessage::Answered {
+                msg_ident,
+                process_identifier,
+                is_ack,
+            } => {
+                if let Some(pending) = self.resends.get_mut(&msg_ident) {
+                    if answers(&pending.broadcast.cmd, is_ack) {
+                        pending.answered.insert(process_identifier);
+                        if 2 * pending.answered.len() > self.processes_count as usize {
+                            self.resends.remove(&msg_ident);
+                        }
+                    }
+                }
             }
         }
     }
 
+    fn next_deadline(&self) -> Instant {
+        self.resends
+            .values()
+            .map(|pending| pending.deadline)
+            .min()
+            .unwrap_or_else(|| Instant::now() + self.max_resend_interval)
+    }
+
+    /// Resends overdue broadcasts, only to the processes which didn't answer yet
+    /// and are not suspected to have crashed.
     fn resend(&mut self) {
-        for cmd in self.resends.values() {
-            self.manager.broadcast(cmd.clone());
+        let now = Instant::now();
+        for pending in self.resends.values_mut() {
+            if pending.deadline > now {
+                continue;
+            }
+            for target in 1..=self.processes_count {
+                if target != self.self_ident
+                    && !pending.answered.contains(&target)
+                    && !self.failure_detector.is_suspected(target)
+                {
+                    self.manager.send(Send {
+                        cmd: pending.broadcast.cmd.clone(),
+                        target,
+                    });
+                }
+            }
+            pending.interval = backoff(
+                pending.interval,
+                self.resend_backoff,
+                self.max_resend_interval,
+            );
+            pending.deadline = now + pending.interval;
         }
     }
 }
@@ -50,7 +161,7 @@ async fn run_resender_actor(
 ) {
     loop {
         tokio::select! {
-            _ = time::sleep(Duration::from_millis(500)) => {
+            _ = time::sleep_until(actor.next_deadline()) => {
                 actor.resend();
             }
             Some(msg) = rx.recv() => {
@@ -70,9 +181,23 @@ pub(crate) struct ResenderActorHandle {
 }
 
 impl ResenderActorHandle {
-    pub(crate) fn new(manager: ConnectorsManager) -> Self {
+    pub(crate) fn new(
+        manager: ConnectorsManager,
+        self_ident: u8,
+        processes_count: u8,
+        network: &NetworkConfiguration,
+        failure_detector: FailureDetector,
+        self_delivery: bool,
+    ) -> Self {
         let (tx, rx) = mpsc::unbounded_channel();
-        let actor = ResenderActor::new(manager);
+        let actor = ResenderActor::new(
+            manager,
+            self_ident,
+            processes_count,
+            network,
+            failure_detector,
+            self_delivery,
+        );
         tokio::spawn(run_resender_actor(actor, rx));
         Self { tx }
     }
@@ -90,4 +215,83 @@ impl ResenderActorHandle {
             error!("Cannot pass message to resender actor");
         }
     }
+
+    pub(crate) fn process_answer(&self, cmd: &SystemRegisterCommand) {
+        let msg = ResenderActorMessage::Answered {
+            msg_ident: cmd.header.msg_ident,
+            process_identifier: cmd.header.process_identifier,
+            is_ack: cmd.content == SystemRegisterCommandContent::Ack,
+        };
+        if self.tx.send(msg).is_err() {
+            error!("Cannot pass message to resender actor");
+        }
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_resends_go_only_to_silent_processes_with_backoff() {
+    let network = NetworkConfiguration {
+        resend_interval: Duration::from_millis(100),
+        max_resend_interval: Duration::from_millis(300),
+        resend_backoff: 2.0,
+        // Nobody sends heartbeats, and nobody should be suspected.
+        heartbeat_interval: Duration::from_secs(60),
+        ..Default::default()
+    };
+    let locations: Vec<Location> = (1..=5)
+        .map(|rank| Location::Tcp("peer".to_string(), rank))
+        .collect();
+    // Nothing listens, so every message sent stays queued.
+    let transport = std::sync::Arc::new(MemoryNetwork::new().transport(locations[0].clone()));
+    let manager = ConnectorsManager::new(1, &locations, &[0; 64], &network, transport);
+    let failure_detector = FailureDetector::new(1, 5, &network);
+    let mut actor = ResenderActor::new(manager.clone(), 1, 5, &network, failure_detector, false);
+    let sent = || -> Vec<u64> {
+        (manager.queue_stats().iter())
+            .map(|stats| stats.queued as u64 + stats.collapsed)
+            .collect()
+    };
+
+    let msg_ident = Uuid::new_v4();
+    actor.handle_message(ResenderActorMessage::ToSend(Broadcast {
+        cmd: std::sync::Arc::new(SystemRegisterCommand {
+            header: SystemCommandHeader {
+                process_identifier: 1,
+                msg_ident,
+                read_ident: 1,
+                sector_idx: 0,
+            },
+            content: SystemRegisterCommandContent::ReadProc,
+        }),
+    }));
+    let answer = |process_identifier| ResenderActorMessage::Answered {
+        msg_ident,
+        process_identifier,
+        is_ack: false,
+    };
+    actor.handle_message(answer(2));
+
+    // Resent after 100, 300, 600 and 900 ms, as the delay doubles up to 300.
+    let mut resends = vec![];
+    for elapsed in (50..=900).step_by(50) {
+        time::advance(Duration::from_millis(50)).await;
+        let before = sent();
+        actor.resend();
+        if sent() != before {
+            resends.push(elapsed);
+            // Not to process 2, which has answered.
+            let expected: Vec<_> = (before.iter().zip([0, 1, 1, 1]))
+                .map(|(sent, resent)| sent + resent)
+                .collect();
+            assert_eq!(sent(), expected);
+        }
+    }
+    assert_eq!(resends, vec![100, 300, 600, 900]);
+
+    // Once a majority has answered, nothing is resent.
+    actor.handle_message(answer(3));
+    time::advance(Duration::from_secs(1)).await;
+    let before = sent();
+    actor.resend();
+    assert_eq!(sent(), before);
 }
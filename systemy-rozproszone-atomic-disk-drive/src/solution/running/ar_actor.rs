@@ -1,5 +1,4 @@
 use super::context::Context;
-use crate::solution::atomic_register::build_atomic_register;
 use crate::*;
 use log::*;
 use std::sync::Arc;
@@ -7,13 +6,15 @@ use tokio;
 use tokio::sync::mpsc::{self, Receiver, Sender, UnboundedSender};
 
 use crate::solution::running::NUMBER_OF_WORKERS;
+use crate::solution::transfer::command_type::ClientCommandType;
 
-use crate::solution::atomic_register::utils::SuccessCallback;
+use crate::solution::atomic_register::utils::OperationCallback;
 
 async fn run_atomic_register_actor(
     mut ar: Box<dyn AtomicRegister>,
     mut system_rx: Receiver<SystemRegisterCommand>,
-    mut client_rx: Receiver<(ClientRegisterCommand, UnboundedSender<OperationSuccess>)>,
+    mut client_rx: Receiver<(ClientRegisterCommand, ResultSenders)>,
+    mut background_rx: Receiver<SystemRegisterCommand>,
 ) {
     tokio::spawn(async move {
         let mut accept_client = true;
@@ -24,15 +25,29 @@ async fn run_atomic_register_actor(
                 Some(()) = finish_rx.recv() => {
                     accept_client = true;
                 }
-                Some((cmd, result_sender)) = client_rx.recv(), if accept_client  => {
+                Some((cmd, (success_sender, failure_sender))) = client_rx.recv(), if accept_client  => {
                     accept_client = false;
                     let tx = finish_tx.clone();
-                    let callback: SuccessCallback = Box::new(move |op_complete| {
+                    let request_identifier = cmd.header.request_identifier;
+                    let command_type = ClientCommandType::new_from_command(&cmd);
+                    let callback: OperationCallback = Box::new(move |op_complete| {
                         Box::pin(async move {
                             if tx.send(()).is_err() {
                                 error!("Couldn't inform via finish_tx");
                             }
-                            if result_sender.send(op_complete).is_err() {
+                            let sent = match op_complete {
+                                Ok(success) => success_sender.send(success).is_ok(),
+                                Err(err) => {
+                                    let status = match err {
+                                        StorageError::VersionNotKept(_) => StatusCode::VersionNotKept,
+                                        _ => StatusCode::StorageError,
+                                    };
+                                    failure_sender
+                                        .send((request_identifier, status, command_type))
+                                        .is_ok()
+                                }
+                            };
+                            if !sent {
                                 error!("Couldn't send error");
                             }
                         })
@@ -42,6 +57,9 @@ async fn run_atomic_register_actor(
                 Some(msg) = system_rx.recv() => {
                     ar.system_command(msg).await;
                 }
+                Some(msg) = background_rx.recv() => {
+                    ar.system_command(msg).await;
+                }
                 else => {
                     break;
                 }
@@ -51,33 +69,50 @@ async fn run_atomic_register_actor(
     });
 }
 
+/// Where responses to a client command go, depending on whether it succeeds.
+pub(crate) type ResultSenders = (
+    UnboundedSender<OperationSuccess>,
+    UnboundedSender<(u64, StatusCode, ClientCommandType)>,
+);
+
 #[derive(Clone)]
 pub(crate) struct AtomicRegisterActorHandler {
     system_tx: Sender<SystemRegisterCommand>,
-    client_tx: Sender<(ClientRegisterCommand, UnboundedSender<OperationSuccess>)>,
+    client_tx: Sender<(ClientRegisterCommand, ResultSenders)>,
+    /// Served only when there is nothing else to do.
+    background_tx: Sender<SystemRegisterCommand>,
 }
 
 impl AtomicRegisterActorHandler {
     pub(crate) async fn new(
         ctx: &Context,
+        builder: &dyn AtomicRegisterBuilder,
         register_client: Arc<dyn RegisterClient>,
         stable_storage: Box<dyn StableStorage>,
         sectors_manager: Arc<dyn SectorsManager>,
     ) -> Self {
         let (system_tx, system_rx) = mpsc::channel(NUMBER_OF_WORKERS);
         let (client_tx, client_rx) = mpsc::channel(NUMBER_OF_WORKERS);
-        let ar = build_atomic_register(
-            ctx.self_rank().clone(),
-            stable_storage,
-            register_client,
-            sectors_manager,
-            ctx.processes_count(),
-        )
-        .await;
-        tokio::spawn(run_atomic_register_actor(ar, system_rx, client_rx));
+        let (background_tx, background_rx) = mpsc::channel(NUMBER_OF_WORKERS);
+        let ar = builder
+            .build(
+                *ctx.self_rank(),
+                stable_storage,
+                register_client,
+                sectors_manager,
+                ctx.processes_count(),
+            )
+            .await;
+        tokio::spawn(run_atomic_register_actor(
+            ar,
+            system_rx,
+            client_rx,
+            background_rx,
+        ));
         Self {
             system_tx,
             client_tx,
+            background_tx,
         }
     }
 
@@ -87,13 +122,35 @@ impl AtomicRegisterActorHandler {
         }
     }
 
-    pub(crate) async fn client(
-        &self,
-        cmd: ClientRegisterCommand,
-        result_sender: UnboundedSender<OperationSuccess>,
-    ) {
-        if self.client_tx.send((cmd, result_sender)).await.is_err() {
+    /// Drops the message if the register is already behind on background work.
+    pub(crate) fn background(&self, cmd: SystemRegisterCommand) {
+        if self.background_tx.try_send(cmd).is_err() {
+            trace!("Dropped background message to the register actor");
+        }
+    }
+
+    pub(crate) async fn client(&self, cmd: ClientRegisterCommand, result_senders: ResultSenders) {
+        if self.client_tx.send((cmd, result_senders)).await.is_err() {
             error!("Couldn't send client message to the register actor");
         }
     }
 }
+
+#[tokio::test]
+async fn test_storage_failure_is_reported_to_client() {
+    use crate::solution::test_cluster::*;
+
+    let cluster = Cluster::start(17, 1).await;
+    let mut stream = cluster.connect(1).await;
+    let data = SectorVec(vec![7; 4096]);
+
+    // Writing sector files fails without their directory.
+    let sectors_dir = cluster.dirs[0].path().join("sectors_manager");
+    std::fs::remove_dir_all(&sectors_dir).unwrap();
+    let status = try_execute(&mut stream, write(1, 7, &data)).await;
+    assert_eq!(status, Err(StatusCode::StorageError as u8));
+
+    // The worker is still alive once the storage is back.
+    std::fs::create_dir(&sectors_dir).unwrap();
+    execute(&mut stream, write(2, 7, &data)).await;
+}
@@ -10,7 +10,7 @@ impl Context {
         Self { config }
     }
 
-    pub(crate) fn self_addr(&self) -> &(String, u16) {
+    pub(crate) fn self_addr(&self) -> &Location {
         &self.config.public.tcp_locations[(self.config.public.self_rank - 1) as usize]
     }
 
@@ -18,7 +18,7 @@ impl Context {
         &self.config.public.self_rank
     }
 
-    pub(crate) fn tcp_locations(&self) -> &Vec<(String, u16)> {
+    pub(crate) fn tcp_locations(&self) -> &Vec<Location> {
         &self.config.public.tcp_locations
     }
 
@@ -30,6 +30,10 @@ impl Context {
         &self.config.hmac_client_key
     }
 
+    pub(crate) fn sectors_key(&self) -> Option<&[u8; 32]> {
+        self.config.sectors_key.as_ref()
+    }
+
     pub(crate) fn storage_dir(&self) -> &PathBuf {
         &self.config.public.storage_dir
     }
@@ -41,4 +45,12 @@ impl Context {
     pub(crate) fn n_sectors(&self) -> u64 {
         self.config.public.n_sectors
     }
+
+    pub(crate) fn network(&self) -> &NetworkConfiguration {
+        &self.config.public.network
+    }
+
+    pub(crate) fn storage(&self) -> &StorageConfiguration {
+        &self.config.public.storage
+    }
 }
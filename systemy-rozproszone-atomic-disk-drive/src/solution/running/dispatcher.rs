@@ -0,0 +1,77 @@
+use super::ar_actor::{AtomicRegisterActorHandler, ResultSenders};
+use super::context::Context;
+use crate::solution::anti_entropy::AntiEntropy;
+use crate::solution::atomic_register::utils as arutils;
+use crate::solution::failure_detector::FailureDetector;
+use crate::solution::register_client::SolutionRegisterClient;
+use crate::solution::running::NUMBER_OF_WORKERS;
+use crate::*;
+use log::*;
+use std::sync::Arc;
+
+/// Routes commands read from connections to the components handling them.
+#[derive(Clone)]
+pub(crate) struct Dispatcher {
+    handlers: Vec<AtomicRegisterActorHandler>,
+    register_client: Arc<SolutionRegisterClient>,
+    failure_detector: FailureDetector,
+    anti_entropy: AntiEntropy,
+    processes_count: u8,
+    n_sectors: u64,
+}
+
+impl Dispatcher {
+    pub(crate) fn new(
+        ctx: &Context,
+        handlers: Vec<AtomicRegisterActorHandler>,
+        register_client: Arc<SolutionRegisterClient>,
+        failure_detector: FailureDetector,
+        anti_entropy: AntiEntropy,
+    ) -> Self {
+        Self {
+            handlers,
+            register_client,
+            failure_detector,
+            anti_entropy,
+            processes_count: ctx.processes_count(),
+            n_sectors: ctx.n_sectors(),
+        }
+    }
+
+    pub(crate) fn is_valid_sector(&self, sector_idx: SectorIdx) -> bool {
+        (0..self.n_sectors).contains(&sector_idx)
+    }
+
+    fn handler(&self, sector_idx: SectorIdx) -> &AtomicRegisterActorHandler {
+        &self.handlers[(sector_idx % (NUMBER_OF_WORKERS as u64)) as usize]
+    }
+
+    /// The sector index of the command must be valid.
+    pub(crate) async fn client(&self, cmd: ClientRegisterCommand, result_senders: ResultSenders) {
+        self.handler(cmd.header.sector_idx)
+            .client(cmd, result_senders)
+            .await;
+    }
+
+    pub(crate) async fn system(&self, cmd: SystemRegisterCommand) {
+        if !((1..=self.processes_count).contains(&cmd.header.process_identifier)) {
+            error!("Invalid process_identifier");
+        } else if cmd.content == SystemRegisterCommandContent::Heartbeat {
+            self.failure_detector
+                .heartbeat(cmd.header.process_identifier);
+        } else if !self.is_valid_sector(cmd.header.sector_idx) {
+            error!("Invalid sector_idx");
+        } else if AntiEntropy::is_anti_entropy_command(&cmd) {
+            self.anti_entropy.system(cmd).await;
+        } else {
+            if arutils::is_answer_command(&cmd) {
+                self.register_client.process_answer(&cmd);
+                if let Some(write) = self.anti_entropy.take_pull(&cmd) {
+                    self.handler(cmd.header.sector_idx).background(write);
+                    return;
+                }
+            }
+            self.handler(cmd.header.sector_idx).system(cmd).await;
+        }
+    }
+}
@@ -1,20 +1,29 @@
 mod ar_actor;
 mod context;
-mod paths_manager;
+mod dispatcher;
+mod options;
+pub(crate) mod paths_manager;
+mod process_handle;
 
 use crate::*;
 use context::Context;
 use log::*;
+use std::sync::Arc;
 
 use tokio;
 use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
 
 use ar_actor::AtomicRegisterActorHandler;
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
-use tokio::net::TcpListener;
+use dispatcher::Dispatcher;
+use tokio::io::{ReadHalf, WriteHalf};
 
+pub use options::RegisterProcessOptions;
 use paths_manager::PathsManager;
+pub use process_handle::RegisterProcessHandle;
 
+use crate::solution::anti_entropy::{self, AntiEntropy};
+use crate::solution::atomic_register::SolutionAtomicRegisterBuilder;
+use crate::solution::failure_detector::{self, FailureDetector};
 use crate::solution::register_client::build_register_client;
 use crate::solution::transfer;
 use crate::solution::transfer::command_type::ClientCommandType;
@@ -24,67 +33,166 @@ use tokio::io::BufReader;
 pub(crate) const NUMBER_OF_WORKERS: usize = 16;
 
 pub(crate) async fn run_register_process(config: Configuration) {
+    match start_register_process(config).await {
+        Ok(handle) => handle.wait().await,
+        Err(err) => error!("Couldn't start the process: {}", err),
+    }
+}
+
+pub(crate) async fn start_register_process(
+    config: Configuration,
+) -> Result<RegisterProcessHandle, StorageError> {
+    start_register_process_with_options(config, RegisterProcessOptions::default()).await
+}
+
+fn validate(
+    network: &NetworkConfiguration,
+    options: &RegisterProcessOptions,
+) -> Result<(), StorageError> {
+    if network.peer_queue_capacity == 0 {
+        return Err(StorageError::InvalidInput(
+            "peer queue capacity must be positive",
+        ));
+    }
+    if !(network.resend_backoff.is_finite() && network.resend_backoff >= 1.0) {
+        return Err(StorageError::InvalidInput(
+            "resend backoff must be a finite factor of at least 1",
+        ));
+    }
+    if !options.self_delivery && options.atomic_register.is_some() {
+        return Err(StorageError::InvalidInput(
+            "a custom atomic register requires self delivery",
+        ));
+    }
+    Ok(())
+}
+
+pub(crate) async fn start_register_process_with_options(
+    config: Configuration,
+    options: RegisterProcessOptions,
+) -> Result<RegisterProcessHandle, StorageError> {
+    validate(&config.public.network, &options)?;
     let ctx = Context::new(config);
-    let listener = TcpListener::bind(ctx.self_addr())
-        .await
-        .expect("Couldn't bind");
+    // Storage is opened before anything is bound or spawned, so a process
+    // which fails to start leaves nothing running.
+    let mut paths_manager = PathsManager::new(
+        ctx.storage_dir().clone(),
+        ctx.n_sectors(),
+        ctx.storage().clone(),
+        ctx.sectors_key().copied(),
+    )
+    .await?;
+    let sectors_manager = paths_manager.get_sectors_manager().await?;
+    let mut stable_storages = vec![];
+    for i in 0..NUMBER_OF_WORKERS {
+        stable_storages.push(paths_manager.get_stable_storage(i as u8).await?);
+    }
+    let listener = options.transport.bind(ctx.self_addr()).await?;
 
+    let failure_detector =
+        FailureDetector::new(*ctx.self_rank(), ctx.processes_count(), ctx.network());
+    let (loopback_tx, loopback_rx) = mpsc::unbounded_channel();
     let register_client = build_register_client(
-        ctx.self_rank().clone(),
+        *ctx.self_rank(),
         ctx.tcp_locations().clone(),
         ctx.hmac_system_key(),
+        ctx.network(),
+        failure_detector.clone(),
+        options.transport.clone(),
+        options.self_delivery.then_some(loopback_tx),
     )
     .await;
+    tokio::spawn(failure_detector::run_heartbeat_sender(
+        register_client.clone(),
+        ctx.network().heartbeat_interval,
+    ));
 
-    let mut paths_manager = PathsManager::new(ctx.storage_dir().clone()).await;
-
+    let builder = options.atomic_register.unwrap_or_else(|| {
+        Arc::new(SolutionAtomicRegisterBuilder {
+            self_delivery: options.self_delivery,
+        })
+    });
     let mut handlers = vec![];
-    for i in 0..NUMBER_OF_WORKERS {
+    for stable_storage in stable_storages {
         handlers.push(
             AtomicRegisterActorHandler::new(
                 &ctx,
+                builder.as_ref(),
                 register_client.clone(),
-                paths_manager.get_stable_storage(i as u8).await,
-                paths_manager.get_sectors_manager().await,
+                stable_storage,
+                sectors_manager.clone(),
             )
             .await,
         );
     }
 
-    listen(ctx, listener, handlers).await;
+    let anti_entropy = AntiEntropy::new(
+        *ctx.self_rank(),
+        ctx.processes_count(),
+        ctx.n_sectors(),
+        ctx.network(),
+        sectors_manager.clone(),
+        register_client.clone(),
+    );
+    if let Some(interval) = ctx.network().anti_entropy_interval {
+        tokio::spawn(anti_entropy::run_anti_entropy(
+            anti_entropy.clone(),
+            failure_detector.clone(),
+            interval,
+        ));
+    }
+
+    let dispatcher = Dispatcher::new(
+        &ctx,
+        handlers,
+        register_client.clone(),
+        failure_detector.clone(),
+        anti_entropy,
+    );
+    tokio::spawn(run_loopback(loopback_rx, dispatcher.clone()));
+    let listener = tokio::spawn(listen(ctx, listener, dispatcher));
+    Ok(RegisterProcessHandle::new(
+        register_client,
+        failure_detector,
+        sectors_manager,
+        listener,
+    ))
+}
+
+/// Delivers messages the process sends to itself.
+async fn run_loopback(mut rx: UnboundedReceiver<SystemRegisterCommand>, dispatcher: Dispatcher) {
+    while let Some(cmd) = rx.recv().await {
+        dispatcher.system(cmd).await;
+    }
 }
 
-async fn listen(ctx: Context, listener: TcpListener, handlers: Vec<AtomicRegisterActorHandler>) {
-    while let Ok((stream, _)) = listener.accept().await {
-        let (read_stream, write_stream) = stream.into_split();
+async fn listen(ctx: Context, mut listener: Box<dyn TransportListener>, dispatcher: Dispatcher) {
+    while let Ok(stream) = listener.accept().await {
+        let (read_stream, write_stream) = tokio::io::split(stream);
         let (success_rx, success_tx) = mpsc::unbounded_channel();
         let (failure_rx, failure_tx) = mpsc::unbounded_channel();
         tokio::spawn(run_command_reader_actor(
             read_stream,
-            handlers.clone(),
+            dispatcher.clone(),
             success_rx,
             failure_rx,
-            ctx.processes_count(),
-            ctx.n_sectors(),
-            ctx.hmac_system_key().clone(),
-            ctx.hmac_client_key().clone(),
+            *ctx.hmac_system_key(),
+            *ctx.hmac_client_key(),
         ));
         tokio::spawn(run_command_writer_actor(
             write_stream,
             success_tx,
             failure_tx,
-            ctx.hmac_client_key().clone(),
+            *ctx.hmac_client_key(),
         ));
     }
 }
 
 async fn run_command_reader_actor(
-    read_stream: OwnedReadHalf,
-    handlers: Vec<AtomicRegisterActorHandler>,
+    read_stream: ReadHalf<Box<dyn TransportStream>>,
+    dispatcher: Dispatcher,
     success_rx: UnboundedSender<OperationSuccess>,
     failure_rx: UnboundedSender<(u64, StatusCode, ClientCommandType)>,
-    processes_count: u8,
-    n_sectors: u64,
     hmac_system_key: [u8; 64],
     hmac_client_key: [u8; 32],
 ) {
@@ -107,7 +215,7 @@ async fn run_command_reader_actor(
                 };
             }
             (RegisterCommand::Client(cmd), true) => {
-                if !((0..(n_sectors)).contains(&cmd.header.sector_idx)) {
+                if !dispatcher.is_valid_sector(cmd.header.sector_idx) {
                     if failure_rx
                         .send((
                             cmd.header.request_identifier,
@@ -119,21 +227,13 @@ async fn run_command_reader_actor(
                         trace!("Failed to send sector index failure to the sending actor");
                     }
                 } else {
-                    handlers[(cmd.header.sector_idx % (NUMBER_OF_WORKERS as u64)) as usize]
-                        .client(cmd, success_rx.clone())
+                    dispatcher
+                        .client(cmd, (success_rx.clone(), failure_rx.clone()))
                         .await;
                 }
             }
             (RegisterCommand::System(cmd), true) => {
-                if !((1..=processes_count).contains(&cmd.header.process_identifier)) {
-                    error!("Invalid process_identifier");
-                } else if !((0..(n_sectors)).contains(&cmd.header.sector_idx)) {
-                    error!("Invalid sector_idx");
-                } else {
-                    handlers[(cmd.header.sector_idx % (NUMBER_OF_WORKERS as u64)) as usize]
-                        .system(cmd)
-                        .await;
-                }
+                dispatcher.system(cmd).await;
             }
             _ => {
                 trace!("Ignored command.");
@@ -143,7 +243,7 @@ async fn run_command_reader_actor(
 }
 
 async fn run_command_writer_actor(
-    mut write_stream: OwnedWriteHalf,
+    mut write_stream: WriteHalf<Box<dyn TransportStream>>,
     mut success_tx: UnboundedReceiver<OperationSuccess>,
     mut failure_tx: UnboundedReceiver<(u64, StatusCode, ClientCommandType)>,
     hmac_client_key: [u8; 32],
@@ -177,3 +277,292 @@ async fn run_command_writer_actor(
         }
     }
 }
+
+#[tokio::test]
+async fn test_custom_register_with_self_delivery() {
+    use crate::solution::atomic_register::utils::OperationCallback;
+    use crate::solution::test_cluster::*;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    type Delivered = Arc<Mutex<Vec<(u8, SystemRegisterCommand)>>>;
+
+    /// Majority quorum register which shares no code with the built-in one,
+    /// so it works only if its own messages are delivered back to it. Every
+    /// delivered message is recorded along with the receiving process.
+    struct RecordingRegister {
+        self_ident: u8,
+        processes_count: u8,
+        register_client: Arc<dyn RegisterClient>,
+        sectors_manager: Arc<dyn SectorsManager>,
+        delivered: Delivered,
+        pending: Option<(SystemCommandHeader, u64, OperationCallback)>,
+        answers: HashMap<u8, (u64, u8, SectorVec)>,
+    }
+
+    impl RecordingRegister {
+        async fn send(&self, to: &SystemRegisterCommand, content: SystemRegisterCommandContent) {
+            let header = SystemCommandHeader {
+                process_identifier: self.self_ident,
+                ..to.header
+            };
+            let cmd = Arc::new(SystemRegisterCommand { header, content });
+            (self.register_client)
+                .send(Send {
+                    cmd,
+                    target: to.header.process_identifier,
+                })
+                .await;
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl AtomicRegister for RecordingRegister {
+        async fn client_command(
+            &mut self,
+            cmd: ClientRegisterCommand,
+            callback: OperationCallback,
+        ) {
+            let sector_idx = cmd.header.sector_idx;
+            let content = match cmd.content {
+                ClientRegisterCommandContent::Write { data } => {
+                    let (timestamp, _) = self
+                        .sectors_manager
+                        .read_metadata(sector_idx)
+                        .await
+                        .unwrap();
+                    SystemRegisterCommandContent::WriteProc {
+                        timestamp: timestamp + 1,
+                        write_rank: self.self_ident,
+                        data_to_write: data,
+                    }
+                }
+                _ => SystemRegisterCommandContent::ReadProc,
+            };
+            let header = SystemCommandHeader {
+                process_identifier: self.self_ident,
+                msg_ident: uuid::Uuid::new_v4(),
+                read_ident: 0,
+                sector_idx,
+            };
+            self.answers.clear();
+            self.pending = Some((header, cmd.header.request_identifier, callback));
+            let cmd = Arc::new(SystemRegisterCommand { header, content });
+            self.register_client.broadcast(Broadcast { cmd }).await;
+        }
+
+        async fn system_command(&mut self, cmd: SystemRegisterCommand) {
+            self.delivered
+                .lock()
+                .unwrap()
+                .push((self.self_ident, cmd.clone()));
+            let sector_idx = cmd.header.sector_idx;
+            let answer = match &cmd.content {
+                SystemRegisterCommandContent::ReadProc => {
+                    let (timestamp, write_rank) = self
+                        .sectors_manager
+                        .read_metadata(sector_idx)
+                        .await
+                        .unwrap();
+                    let sector_data = self.sectors_manager.read_data(sector_idx).await.unwrap();
+                    let value = SystemRegisterCommandContent::Value {
+                        timestamp,
+                        write_rank,
+                        sector_data,
+                    };
+                    return self.send(&cmd, value).await;
+                }
+                SystemRegisterCommandContent::WriteProc {
+                    timestamp,
+                    write_rank,
+                    data_to_write,
+                } => {
+                    let stored = self
+                        .sectors_manager
+                        .read_metadata(sector_idx)
+                        .await
+                        .unwrap();
+                    if (*timestamp, *write_rank) > stored {
+                        let sector = (data_to_write.clone(), *timestamp, *write_rank);
+                        self.sectors_manager
+                            .write(sector_idx, &sector)
+                            .await
+                            .unwrap();
+                    }
+                    return self.send(&cmd, SystemRegisterCommandContent::Ack).await;
+                }
+                SystemRegisterCommandContent::Value {
+                    timestamp,
+                    write_rank,
+                    sector_data,
+                } => (*timestamp, *write_rank, sector_data.clone()),
+                _ => (0, 0, SectorVec(vec![])),
+            };
+            match &self.pending {
+                Some((header, _, _)) if header.msg_ident == cmd.header.msg_ident => {}
+                _ => return,
+            }
+            self.answers.insert(cmd.header.process_identifier, answer);
+            if 2 * self.answers.len() > self.processes_count as usize {
+                let (_, request_identifier, callback) = self.pending.take().unwrap();
+                let op_return = match cmd.content {
+                    SystemRegisterCommandContent::Ack => OperationReturn::Write,
+                    _ => {
+                        let (_, _, read_data) =
+                            (self.answers.values().max_by_key(|(ts, wr, _)| (*ts, *wr)))
+                                .unwrap()
+                                .clone();
+                        OperationReturn::Read(ReadReturn { read_data })
+                    }
+                };
+                callback(Ok(OperationSuccess {
+                    request_identifier,
+                    op_return,
+                }))
+                .await;
+            }
+        }
+    }
+
+    struct RecordingBuilder(Delivered);
+
+    #[async_trait::async_trait]
+    impl AtomicRegisterBuilder for RecordingBuilder {
+        async fn build(
+            &self,
+            self_ident: u8,
+            _metadata: Box<dyn StableStorage>,
+            register_client: Arc<dyn RegisterClient>,
+            sectors_manager: Arc<dyn SectorsManager>,
+            processes_count: u8,
+        ) -> Box<dyn AtomicRegister> {
+            Box::new(RecordingRegister {
+                self_ident,
+                processes_count,
+                register_client,
+                sectors_manager,
+                delivered: self.0.clone(),
+                pending: None,
+                answers: HashMap::new(),
+            })
+        }
+    }
+
+    let delivered = Delivered::default();
+    let builder = Arc::new(RecordingBuilder(delivered.clone()));
+    let cluster = Cluster::start_with_options(31, 3, || RegisterProcessOptions {
+        self_delivery: true,
+        atomic_register: Some(builder.clone()),
+        ..Default::default()
+    })
+    .await;
+    cluster.network.set_faults(FaultConfig {
+        drop_probability: 0.1,
+        ..Default::default()
+    });
+
+    let mut writer = cluster.connect(2).await;
+    let mut reader = cluster.connect(1).await;
+    for i in 0..5u8 {
+        let data = SectorVec(vec![i; 4096]);
+        execute(&mut writer, write(i as u64, i as u64, &data)).await;
+        let read_data = execute(&mut reader, read(i as u64, i as u64)).await;
+        assert_eq!(read_data, Some(data));
+    }
+
+    // Both the proc messages and the answers of a process reach it.
+    let delivered = delivered.lock().unwrap();
+    let from_self = |content: fn(&SystemRegisterCommandContent) -> bool, rank| {
+        (delivered.iter()).any(|(to, cmd)| {
+            *to == rank && cmd.header.process_identifier == rank && content(&cmd.content)
+        })
+    };
+    let is_write = |c: &_| matches!(c, SystemRegisterCommandContent::WriteProc { .. });
+    let is_ack = |c: &_| matches!(c, SystemRegisterCommandContent::Ack);
+    let is_read = |c: &_| matches!(c, SystemRegisterCommandContent::ReadProc);
+    let is_value = |c: &_| matches!(c, SystemRegisterCommandContent::Value { .. });
+    assert!(from_self(is_write, 2) && from_self(is_ack, 2));
+    assert!(from_self(is_read, 1) && from_self(is_value, 1));
+}
+
+#[tokio::test]
+async fn test_invalid_configuration_is_rejected() {
+    use crate::solution::test_cluster::*;
+
+    let dir = tempfile::tempdir().unwrap();
+    let network = MemoryNetwork::new();
+    let start = |config, options: RegisterProcessOptions| {
+        start_register_process_with_options(
+            config,
+            RegisterProcessOptions {
+                transport: Arc::new(network.transport(location(1))),
+                ..options
+            },
+        )
+    };
+    let invalid_input = |result| matches!(result, Err(StorageError::InvalidInput(_)));
+
+    let mut config = configuration(dir.path(), 1, 1);
+    config.public.network.peer_queue_capacity = 0;
+    assert!(invalid_input(start(config, Default::default()).await));
+    for backoff in [0.5, f64::NAN, f64::INFINITY] {
+        let mut config = configuration(dir.path(), 1, 1);
+        config.public.network.resend_backoff = backoff;
+        assert!(invalid_input(start(config, Default::default()).await));
+    }
+    let custom = RegisterProcessOptions {
+        atomic_register: Some(Arc::new(SolutionAtomicRegisterBuilder {
+            self_delivery: false,
+        })),
+        ..Default::default()
+    };
+    let config = configuration(dir.path(), 1, 1);
+    assert!(invalid_input(start(config, custom).await));
+
+    // The location is taken by another process.
+    let _other = network
+        .transport(location(1))
+        .bind(&location(1))
+        .await
+        .unwrap();
+    let config = configuration(dir.path(), 1, 1);
+    assert!(matches!(
+        start(config, Default::default()).await,
+        Err(StorageError::Io(err)) if err.kind() == std::io::ErrorKind::AddrInUse
+    ));
+}
+
+#[tokio::test]
+async fn test_failed_storage_leaves_nothing_running() {
+    use crate::solution::test_cluster::*;
+    use tokio::time::{self, Duration};
+
+    let dir = tempfile::tempdir().unwrap();
+    let network = MemoryNetwork::new();
+    let mut peer = network
+        .transport(location(2))
+        .bind(&location(2))
+        .await
+        .unwrap();
+
+    // The storage directory can't be created under a regular file.
+    let file = dir.path().join("file");
+    std::fs::write(&file, []).unwrap();
+    let options = RegisterProcessOptions {
+        transport: Arc::new(network.transport(location(1))),
+        ..Default::default()
+    };
+    let config = configuration(&file.join("storage"), 1, 2);
+    assert!(start_register_process_with_options(config, options)
+        .await
+        .is_err());
+
+    // No heartbeats are sent, and the location isn't kept bound.
+    let accepted = time::timeout(Duration::from_secs(1), peer.accept()).await;
+    assert!(accepted.is_err());
+    network
+        .transport(location(1))
+        .bind(&location(1))
+        .await
+        .unwrap();
+}
@@ -0,0 +1,25 @@
+use crate::*;
+use std::sync::Arc;
+
+/// Parts of a register process which can be replaced by the caller.
+pub struct RegisterProcessOptions {
+    /// Used both to accept connections and to connect to other processes.
+    pub transport: Arc<dyn Transport>,
+    /// Whether the register client delivers messages to self, as described in
+    /// `RegisterClient`. Without it, the built-in register answers its own
+    /// messages directly, which saves a round trip through the dispatcher.
+    pub self_delivery: bool,
+    /// Replaces the built-in atomic register. Such a register relies on the
+    /// contract of `RegisterClient`, so starting fails without `self_delivery`.
+    pub atomic_register: Option<Arc<dyn AtomicRegisterBuilder>>,
+}
+
+impl Default for RegisterProcessOptions {
+    fn default() -> Self {
+        RegisterProcessOptions {
+            transport: Arc::new(SocketTransport),
+            self_delivery: false,
+            atomic_register: None,
+        }
+    }
+}
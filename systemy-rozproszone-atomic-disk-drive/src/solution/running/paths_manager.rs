@@ -1,69 +1,104 @@
-use crate::solution::stable_storage::build_stable_storage;
+use crate::solution::format;
+use crate::solution::sectors_manager::build_sectors_manager_with_key;
+use crate::solution::stable_storage::{build_stable_storage, remove_tmpfiles};
 use crate::*;
 use std::collections::HashSet;
-use std::path::PathBuf;
+use std::io;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use tokio::fs;
 use tokio::fs::File;
 
+/// Directory of the sectors manager, within the storage directory.
+pub(crate) const SECTORS_DIR: &str = "sectors_manager";
+/// Directory of the stable storages of workers, within the storage directory.
+pub(crate) const STABLE_STORAGE_DIR: &str = "stable_storage";
+
+/// Directory of the stable storage of a worker, within `STABLE_STORAGE_DIR`.
+pub(crate) fn worker_dir_name(worker_id: u8) -> String {
+    format!("worker_{:#04x}", worker_id)
+}
+
 pub(crate) struct PathsManager {
     root_path: PathBuf,
+    n_sectors: u64,
+    storage: StorageConfiguration,
+    sectors_key: Option<[u8; 32]>,
     sectors_manager: Option<Arc<dyn SectorsManager>>,
     set: HashSet<u8>,
 }
 
 impl PathsManager {
-    pub(crate) async fn new(root_path: PathBuf) -> Self {
-        Self {
+    /// Brings the storage to the current format, refusing to start on storage
+    /// of an unknown one.
+    pub(crate) async fn new(
+        root_path: PathBuf,
+        n_sectors: u64,
+        storage: StorageConfiguration,
+        sectors_key: Option<[u8; 32]>,
+    ) -> Result<Self, StorageError> {
+        fs::create_dir_all(&root_path).await?;
+        format::migrate(&root_path).await?;
+        Ok(Self {
             root_path,
+            n_sectors,
+            storage,
+            sectors_key,
             sectors_manager: None,
             set: HashSet::new(),
-        }
+        })
     }
 
-    pub(crate) async fn get_stable_storage(&mut self, worker_id: u8) -> Box<dyn StableStorage> {
+    pub(crate) async fn get_stable_storage(
+        &mut self,
+        worker_id: u8,
+    ) -> Result<Box<dyn StableStorage>, StorageError> {
         assert!(!self.set.contains(&worker_id));
         self.set.insert(worker_id);
         let mut dir = self.root_path.clone();
-        dir.push("stable_storage");
-        fs::create_dir_all(dir.clone())
-            .await
-            .expect("Couldn't create stable storage dir");
-        File::open(&self.root_path)
-            .await
-            .expect("failed to open rooth path")
-            .sync_data()
-            .await
-            .expect("failed to sync data");
+        dir.push(STABLE_STORAGE_DIR);
+        fs::create_dir_all(dir.clone()).await?;
+        sync_dir(&self.root_path).await?;
         let storage_dir = dir.clone();
-        dir.push(format!("worker_{:#04x}", worker_id));
-        fs::create_dir_all(dir.clone())
-            .await
-            .expect("couldn't create worker dir");
-        File::open(storage_dir)
-            .await
-            .expect("failed to open storage dir")
-            .sync_data()
-            .await
-            .unwrap();
-        File::open(&dir).await.unwrap().sync_data().await.unwrap();
-        build_stable_storage(dir).await
+        dir.push(worker_dir_name(worker_id));
+        fs::create_dir_all(dir.clone()).await?;
+        sync_dir(&storage_dir).await?;
+        sync_dir(&dir).await?;
+        remove_tmpfiles(&dir).await?;
+        Ok(build_stable_storage(dir).await)
     }
 
-    pub(crate) async fn get_sectors_manager(&mut self) -> Arc<dyn SectorsManager> {
-        if self.sectors_manager.is_none() {
-            let mut dir = self.root_path.clone();
-            dir.push("sectors_manager");
-            fs::create_dir_all(dir.clone()).await.unwrap();
-            File::open(&self.root_path)
-                .await
-                .unwrap()
-                .sync_data()
-                .await
-                .unwrap();
-            File::open(&dir).await.unwrap().sync_data().await.unwrap();
-            self.sectors_manager = Some(build_sectors_manager(dir).await);
+    pub(crate) async fn get_sectors_manager(
+        &mut self,
+    ) -> Result<Arc<dyn SectorsManager>, StorageError> {
+        if let Some(sectors_manager) = &self.sectors_manager {
+            return Ok(sectors_manager.clone());
         }
-        self.sectors_manager.as_ref().unwrap().clone()
+        let mut dir = self.root_path.clone();
+        dir.push(SECTORS_DIR);
+        fs::create_dir_all(dir.clone()).await?;
+        sync_dir(&self.root_path).await?;
+        sync_dir(&dir).await?;
+        let mut storage = self.storage.clone();
+        for data_dir in &mut storage.data_dirs {
+            data_dir.push(SECTORS_DIR);
+            fs::create_dir_all(&data_dir).await?;
+            if let Some(parent) = data_dir.parent() {
+                sync_dir(parent).await?;
+            }
+        }
+        let sectors_manager = build_sectors_manager_with_key(
+            dir,
+            self.n_sectors,
+            &storage,
+            self.sectors_key.as_ref(),
+        )
+        .await?;
+        self.sectors_manager = Some(sectors_manager.clone());
+        Ok(sectors_manager)
     }
 }
+
+async fn sync_dir(path: &Path) -> io::Result<()> {
+    File::open(path).await?.sync_data().await
+}
@@ -0,0 +1,63 @@
+use crate::solution::failure_detector::FailureDetector;
+use crate::solution::register_client::SolutionRegisterClient;
+use crate::*;
+use std::sync::Arc;
+use tokio::task::JoinHandle;
+
+/// Handle to a register process running in the background. It lets operators
+/// inspect the state of the process while it serves requests.
+pub struct RegisterProcessHandle {
+    register_client: Arc<SolutionRegisterClient>,
+    failure_detector: FailureDetector,
+    sectors_manager: Arc<dyn SectorsManager>,
+    listener: JoinHandle<()>,
+}
+
+impl RegisterProcessHandle {
+    pub(crate) fn new(
+        register_client: Arc<SolutionRegisterClient>,
+        failure_detector: FailureDetector,
+        sectors_manager: Arc<dyn SectorsManager>,
+        listener: JoinHandle<()>,
+    ) -> Self {
+        Self {
+            register_client,
+            failure_detector,
+            sectors_manager,
+            listener,
+        }
+    }
+
+    /// State of the queues of messages waiting to be sent to other processes.
+    pub fn peer_queue_stats(&self) -> Vec<PeerQueueStats> {
+        self.register_client.peer_queue_stats()
+    }
+
+    /// Identifiers of the processes currently suspected to have crashed.
+    pub fn suspected_processes(&self) -> Vec<u8> {
+        self.failure_detector.suspected()
+    }
+
+    /// Suspicion level of every other process.
+    pub fn peer_liveness(&self) -> Vec<PeerLiveness> {
+        self.failure_detector.liveness()
+    }
+
+    /// Counters of the cache of sector contents, `None` if it is disabled.
+    pub fn sectors_cache_stats(&self) -> Option<SectorCacheStats> {
+        self.sectors_manager.cache_stats()
+    }
+
+    /// Counters of the removal of files left by failed writes, `None` if the
+    /// sectors backend leaves none behind.
+    pub fn sectors_gc_stats(&self) -> Option<SectorsGcStats> {
+        self.sectors_manager.gc_stats()
+    }
+
+    /// Waits until the process stops accepting connections.
+    pub async fn wait(self) {
+        if self.listener.await.is_err() {
+            log::error!("Listener task of the register process panicked");
+        }
+    }
+}
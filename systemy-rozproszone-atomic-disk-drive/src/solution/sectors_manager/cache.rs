@@ -0,0 +1,118 @@
+//! Bounded LRU cache of sector contents.
+//!
+//! The cache is not synchronized with the storage on its own: sectors
+//! managers access it only while holding the lock of the sector, for reading
+//! on lookups and fills, and for writing on writes.
+use crate::{SectorCacheStats, SectorIdx, SectorVec};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+struct Lru {
+    /// Content and last use of every cached sector.
+    entries: HashMap<SectorIdx, (SectorVec, u64)>,
+    /// Cached sectors by last use.
+    by_use: BTreeMap<u64, SectorIdx>,
+    clock: u64,
+}
+
+impl Lru {
+    fn touch(&mut self, idx: SectorIdx) {
+        if let Some((_, last_use)) = self.entries.get_mut(&idx) {
+            self.by_use.remove(last_use);
+            self.clock += 1;
+            *last_use = self.clock;
+            self.by_use.insert(self.clock, idx);
+        }
+    }
+}
+
+pub(super) struct SectorCache {
+    capacity: usize,
+    lru: Mutex<Lru>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl SectorCache {
+    /// `None` if the cache is disabled by zero capacity.
+    pub(super) fn new(capacity: usize) -> Option<Self> {
+        (capacity > 0).then(|| SectorCache {
+            capacity,
+            lru: Mutex::new(Lru {
+                entries: HashMap::new(),
+                by_use: BTreeMap::new(),
+                clock: 0,
+            }),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        })
+    }
+
+    pub(super) fn get(&self, idx: SectorIdx) -> Option<SectorVec> {
+        let mut lru = self.lru.lock().unwrap();
+        lru.touch(idx);
+        match lru.entries.get(&idx) {
+            Some((data, _)) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                Some(data.clone())
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    /// Caches `data` as the current content of the sector, evicting the least
+    /// recently used sector if the cache is full.
+    pub(super) fn insert(&self, idx: SectorIdx, data: SectorVec) {
+        let mut lru = self.lru.lock().unwrap();
+        if let Some((cached, _)) = lru.entries.get_mut(&idx) {
+            *cached = data;
+            lru.touch(idx);
+            return;
+        }
+        if lru.entries.len() >= self.capacity {
+            if let Some((_, evicted)) = lru.by_use.pop_first() {
+                lru.entries.remove(&evicted);
+            }
+        }
+        lru.clock += 1;
+        let clock = lru.clock;
+        lru.entries.insert(idx, (data, clock));
+        lru.by_use.insert(clock, idx);
+    }
+
+    pub(super) fn stats(&self) -> SectorCacheStats {
+        SectorCacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            cached: self.lru.lock().unwrap().entries.len(),
+            capacity: self.capacity,
+        }
+    }
+}
+
+#[test]
+fn test_sector_cache_evicts_least_recently_used() {
+    let cache = SectorCache::new(2).unwrap();
+    cache.insert(1, SectorVec(vec![1]));
+    cache.insert(2, SectorVec(vec![2]));
+    assert_eq!(cache.get(1), Some(SectorVec(vec![1])));
+    cache.insert(3, SectorVec(vec![3]));
+    assert_eq!(cache.get(2), None);
+    cache.insert(1, SectorVec(vec![4]));
+    assert_eq!(cache.get(1), Some(SectorVec(vec![4])));
+    assert_eq!(cache.get(3), Some(SectorVec(vec![3])));
+    assert_eq!(
+        cache.stats(),
+        SectorCacheStats {
+            hits: 3,
+            misses: 1,
+            cached: 2,
+            capacity: 2,
+        }
+    );
+    assert!(SectorCache::new(0).is_none());
+}
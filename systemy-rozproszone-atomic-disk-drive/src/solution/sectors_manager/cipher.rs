@@ -0,0 +1,227 @@
+//! Encryption of sectors at rest.
+//!
+//! Sector files hold the data sealed with ChaCha20-Poly1305 under a random
+//! nonce, with the sector index and metadata as associated data. Metadata in
+//! file names and in the index is disguised by a keyed permutation: a four
+//! round Feistel network over the 72 bits of timestamp and write rank, with
+//! HMAC-SHA256 tweaked by the sector index as the round function. Names stay
+//! computable from metadata, but without the key they tell only which
+//! sectors were written, and when they were last written.
+//!
+//! Superseded versions kept in history aren't disguised beyond that. Each
+//! is a file of its own, so their number and modification times reveal how
+//! many times, and when, every sector was written within the kept history.
+//! Disable history where this matters.
+use crate::solution::transfer::SECTOR_LEN;
+use crate::{SectorIdx, SectorVec, StorageError};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::io;
+use std::path::Path;
+use tokio::fs::{self, File};
+use tokio::io::AsyncWriteExt;
+
+type HmacSha256 = Hmac<Sha256>;
+
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+/// Length of a sealed sector file.
+pub(super) const SEALED_LEN: usize = NONCE_LEN + SECTOR_LEN + TAG_LEN;
+const FEISTEL_ROUNDS: u8 = 4;
+const HALF_BITS: u32 = 36;
+const HALF_MASK: u128 = (1 << HALF_BITS) - 1;
+/// Holds a value derived from the key, so a wrong key is detected on startup
+/// instead of making every sector look corrupted.
+const KEY_CHECK_FILENAME: &str = "key_check";
+
+fn derive(key: &[u8; 32], label: &[u8]) -> [u8; 32] {
+    let mut mac = <HmacSha256 as Mac>::new_from_slice(key).unwrap();
+    mac.update(label);
+    mac.finalize().into_bytes().into()
+}
+
+fn associated_data(idx: SectorIdx, (logical_timestamp, write_rank): (u64, u8)) -> [u8; 17] {
+    let mut aad = [0; 17];
+    aad[0..8].copy_from_slice(&idx.to_le_bytes());
+    aad[8..16].copy_from_slice(&logical_timestamp.to_le_bytes());
+    aad[16] = write_rank;
+    aad
+}
+
+pub(super) async fn is_encrypted(path: &Path) -> io::Result<bool> {
+    match fs::metadata(path.join(KEY_CHECK_FILENAME)).await {
+        Ok(_) => Ok(true),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+pub(super) struct SectorsCipher {
+    aead: ChaCha20Poly1305,
+    metadata_key: [u8; 32],
+    key_check: [u8; 32],
+}
+
+impl SectorsCipher {
+    pub(super) fn new(key: &[u8; 32]) -> Self {
+        SectorsCipher {
+            aead: ChaCha20Poly1305::new(Key::from_slice(&derive(key, b"sectors data"))),
+            metadata_key: derive(key, b"sectors metadata"),
+            key_check: derive(key, b"sectors key check"),
+        }
+    }
+
+    fn round(&self, idx: SectorIdx, round: u8, half: u128) -> u128 {
+        let mut mac = <HmacSha256 as Mac>::new_from_slice(&self.metadata_key).unwrap();
+        mac.update(&idx.to_le_bytes());
+        mac.update(&[round]);
+        mac.update(&(half as u64).to_le_bytes());
+        let out = mac.finalize().into_bytes();
+        u64::from_le_bytes(out[..8].try_into().unwrap()) as u128 & HALF_MASK
+    }
+
+    /// Metadata of sector `idx` as it is stored on disk.
+    pub(super) fn disguise(
+        &self,
+        idx: SectorIdx,
+        (logical_timestamp, write_rank): (u64, u8),
+    ) -> (u64, u8) {
+        let value = ((logical_timestamp as u128) << 8) | write_rank as u128;
+        let (mut left, mut right) = (value >> HALF_BITS, value & HALF_MASK);
+        for round in 0..FEISTEL_ROUNDS {
+            (left, right) = (right, left ^ self.round(idx, round, right));
+        }
+        let value = (left << HALF_BITS) | right;
+        ((value >> 8) as u64, value as u8)
+    }
+
+    /// Inverse of `disguise`.
+    pub(super) fn reveal(
+        &self,
+        idx: SectorIdx,
+        (logical_timestamp, write_rank): (u64, u8),
+    ) -> (u64, u8) {
+        let value = ((logical_timestamp as u128) << 8) | write_rank as u128;
+        let (mut left, mut right) = (value >> HALF_BITS, value & HALF_MASK);
+        for round in (0..FEISTEL_ROUNDS).rev() {
+            (left, right) = (right ^ self.round(idx, round, left), left);
+        }
+        let value = (left << HALF_BITS) | right;
+        ((value >> 8) as u64, value as u8)
+    }
+
+    pub(super) fn seal(&self, idx: SectorIdx, meta: (u64, u8), data: &[u8]) -> Vec<u8> {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let payload = Payload {
+            msg: data,
+            aad: &associated_data(idx, meta),
+        };
+        let mut content = nonce.to_vec();
+        content.extend(
+            self.aead
+                .encrypt(&nonce, payload)
+                .expect("Sector data is too long to encrypt"),
+        );
+        content
+    }
+
+    /// Fails with `StorageError::Corrupted` unless the content was sealed for
+    /// this sector and metadata with the same key.
+    pub(super) fn open(
+        &self,
+        idx: SectorIdx,
+        meta: (u64, u8),
+        content: &[u8],
+    ) -> Result<SectorVec, StorageError> {
+        if content.len() != SEALED_LEN {
+            return Err(StorageError::Corrupted(idx));
+        }
+        let (nonce, ciphertext) = content.split_at(NONCE_LEN);
+        let payload = Payload {
+            msg: ciphertext,
+            aad: &associated_data(idx, meta),
+        };
+        self.aead
+            .decrypt(Nonce::from_slice(nonce), payload)
+            .map(SectorVec)
+            .map_err(|_| StorageError::Corrupted(idx))
+    }
+}
+
+/// Metadata of sector `idx` as stored in file names and the index.
+pub(super) fn stored_meta(
+    cipher: Option<&SectorsCipher>,
+    idx: SectorIdx,
+    meta: (u64, u8),
+) -> (u64, u8) {
+    cipher.map_or(meta, |cipher| cipher.disguise(idx, meta))
+}
+
+/// Inverse of `stored_meta`.
+pub(super) fn real_meta(
+    cipher: Option<&SectorsCipher>,
+    idx: SectorIdx,
+    meta: (u64, u8),
+) -> (u64, u8) {
+    cipher.map_or(meta, |cipher| cipher.reveal(idx, meta))
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Checks that sectors in `path` are encrypted with the key of `cipher`, or
+/// not encrypted if there is none. `empty` tells whether there are no
+/// sectors yet, in which case encryption is set up.
+pub(super) async fn check_key(
+    path: &Path,
+    cipher: Option<&SectorsCipher>,
+    empty: bool,
+) -> io::Result<()> {
+    let stored = match fs::read(path.join(KEY_CHECK_FILENAME)).await {
+        Ok(stored) => Some(stored),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+        Err(err) => return Err(err),
+    };
+    match (cipher, stored) {
+        (None, None) => Ok(()),
+        (None, Some(_)) => Err(invalid_data("sectors are encrypted, but no key was given")),
+        (Some(cipher), Some(stored)) if stored == cipher.key_check => Ok(()),
+        (Some(_), Some(_)) => Err(invalid_data("wrong sectors encryption key")),
+        (Some(_), None) if !empty => Err(invalid_data(
+            "sectors were stored unencrypted, and can't be encrypted in place",
+        )),
+        (Some(cipher), None) => {
+            let tmp_path = path.join(format!("tmpfile{}", KEY_CHECK_FILENAME));
+            let mut tmp = File::create(&tmp_path).await?;
+            tmp.write_all(&cipher.key_check).await?;
+            tmp.sync_data().await?;
+            fs::rename(&tmp_path, path.join(KEY_CHECK_FILENAME)).await?;
+            File::open(path).await?.sync_data().await
+        }
+    }
+}
+
+#[test]
+fn test_metadata_disguise_is_reversible() {
+    let cipher = SectorsCipher::new(&[7; 32]);
+    for meta in [(0, 0), (1, 1), (u64::MAX, 255), (12345, 3)] {
+        let disguised = cipher.disguise(42, meta);
+        assert_ne!(disguised, meta);
+        assert_ne!(cipher.disguise(43, meta), disguised);
+        assert_eq!(cipher.reveal(42, disguised), meta);
+    }
+
+    let sealed = cipher.seal(42, (1, 1), &[5; SECTOR_LEN]);
+    assert_eq!(sealed.len(), SEALED_LEN);
+    assert_eq!(
+        cipher.open(42, (1, 1), &sealed).unwrap(),
+        SectorVec(vec![5; SECTOR_LEN])
+    );
+    assert!(cipher.open(42, (2, 1), &sealed).is_err());
+    assert!(SectorsCipher::new(&[8; 32])
+        .open(42, (1, 1), &sealed)
+        .is_err());
+}
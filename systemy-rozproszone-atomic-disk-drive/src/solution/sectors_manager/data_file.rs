@@ -0,0 +1,593 @@
+//! Sectors manager keeping all sectors in a single preallocated file.
+//!
+//! Sector `idx` lives at offset `idx * SECTOR_LEN` of `sectors.data`, and its
+//! metadata, including the checksum of the data, is a checksummed record at
+//! offset `idx * META_RECORD_LEN` of `sectors.meta`. Both are overwritten in
+//! place, so a crash could leave a torn sector behind. Hence every write is
+//! first appended to `sectors.wal` and synced, and only then applied. On
+//! startup the log is replayed, up to the first incomplete record. Once the
+//! log grows long enough, the data and metadata files are synced and the log
+//! is truncated.
+//!
+//! Both files are preallocated by writing zeros, rather than extended with
+//! `set_len`, which would leave them sparse, so running out of disk space is
+//! detected on startup and not by a write.
+use super::cache::SectorCache;
+use super::merkle::MerkleTree;
+use super::sector_checksum;
+use crate::solution::running::NUMBER_OF_WORKERS;
+use crate::solution::transfer::SECTOR_LEN;
+use crate::{
+    SectorCacheStats, SectorIdx, SectorVec, SectorsManager, StorageConfiguration, StorageError,
+};
+use log::*;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::ops::Range;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::sync::{Mutex, RwLock};
+
+pub(super) const DATA_FILENAME: &str = "sectors.data";
+const META_FILENAME: &str = "sectors.meta";
+const WAL_FILENAME: &str = "sectors.wal";
+
+/// Timestamp, write rank, padding, checksum of the data and the checksum of
+/// the preceding bytes.
+const META_RECORD_LEN: usize = 24;
+/// Sector index, timestamp, write rank, data and the checksum of the
+/// preceding bytes.
+const WAL_RECORD_LEN: usize = 8 + 8 + 1 + SECTOR_LEN + 4;
+/// The log is truncated after that many records.
+const WAL_CHECKPOINT_RECORDS: usize = 256;
+/// Zeros are written in chunks of that many bytes when preallocating.
+const PREALLOCATION_CHUNK_LEN: usize = 1 << 20;
+
+pub(crate) struct DataFileSectorsManager {
+    data: Arc<File>,
+    meta: Arc<File>,
+    wal: Mutex<Wal>,
+    /// Held for reading between appending a record to the log and applying
+    /// it, so the log is never truncated before its records are applied.
+    checkpoint: RwLock<()>,
+    idx_to_meta: Vec<RwLock<HashMap<SectorIdx, SectorMeta>>>,
+    /// Updated while holding the lock of `idx_to_meta`.
+    merkle: std::sync::Mutex<MerkleTree>,
+    cache: Option<SectorCache>,
+    n_sectors: u64,
+}
+
+#[derive(Clone, Copy)]
+enum SectorMeta {
+    Written {
+        logical_timestamp: u64,
+        write_rank: u8,
+        checksum: u32,
+    },
+    /// The metadata record itself is damaged, so the data can't be trusted.
+    Corrupted,
+}
+
+struct Wal {
+    file: Arc<File>,
+    records: usize,
+}
+
+#[cfg(unix)]
+fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buf, offset)
+}
+
+#[cfg(unix)]
+fn write_all_at(file: &File, buf: &[u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::write_all_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    while !buf.is_empty() {
+        match std::os::windows::fs::FileExt::seek_read(file, buf, offset)? {
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            n => {
+                let rest = buf;
+                buf = &mut rest[n..];
+                offset += n as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+fn write_all_at(file: &File, mut buf: &[u8], mut offset: u64) -> io::Result<()> {
+    while !buf.is_empty() {
+        match std::os::windows::fs::FileExt::seek_write(file, buf, offset)? {
+            0 => return Err(io::ErrorKind::WriteZero.into()),
+            n => {
+                buf = &buf[n..];
+                offset += n as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(any(unix, windows)))]
+fn read_exact_at(_file: &File, _buf: &mut [u8], _offset: u64) -> io::Result<()> {
+    Err(positional_io_unsupported())
+}
+
+#[cfg(not(any(unix, windows)))]
+fn write_all_at(_file: &File, _buf: &[u8], _offset: u64) -> io::Result<()> {
+    Err(positional_io_unsupported())
+}
+
+#[cfg(not(any(unix, windows)))]
+fn positional_io_unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "The data file backend is not supported on this platform",
+    )
+}
+
+async fn blocking<T: Send + 'static>(
+    f: impl FnOnce() -> io::Result<T> + Send + 'static,
+) -> io::Result<T> {
+    tokio::task::spawn_blocking(f)
+        .await
+        .expect("Access to sector files has panicked")
+}
+
+fn encode_meta_record(
+    logical_timestamp: u64,
+    write_rank: u8,
+    checksum: u32,
+) -> [u8; META_RECORD_LEN] {
+    let mut record = [0; META_RECORD_LEN];
+    record[0..8].copy_from_slice(&logical_timestamp.to_le_bytes());
+    record[8] = write_rank;
+    record[16..20].copy_from_slice(&checksum.to_le_bytes());
+    let crc = crc32c::crc32c(&record[..20]);
+    record[20..].copy_from_slice(&crc.to_le_bytes());
+    record
+}
+
+/// `None` for sectors which were never written.
+fn decode_meta_record(idx: SectorIdx, record: &[u8]) -> Option<SectorMeta> {
+    if record.iter().all(|b| *b == 0) {
+        return None;
+    }
+    if crc32c::crc32c(&record[..20]).to_le_bytes() != record[20..24] {
+        error!("Metadata of sector {} is corrupted", idx);
+        return Some(SectorMeta::Corrupted);
+    }
+    Some(SectorMeta::Written {
+        logical_timestamp: u64::from_le_bytes(record[0..8].try_into().unwrap()),
+        write_rank: record[8],
+        checksum: u32::from_le_bytes(record[16..20].try_into().unwrap()),
+    })
+}
+
+fn encode_wal_record(
+    idx: SectorIdx,
+    logical_timestamp: u64,
+    write_rank: u8,
+    content: &[u8],
+) -> Vec<u8> {
+    let mut record = Vec::with_capacity(WAL_RECORD_LEN);
+    record.extend_from_slice(&idx.to_le_bytes());
+    record.extend_from_slice(&logical_timestamp.to_le_bytes());
+    record.push(write_rank);
+    record.extend_from_slice(content);
+    let crc = crc32c::crc32c(&record);
+    record.extend_from_slice(&crc.to_le_bytes());
+    record
+}
+
+/// `None` for a record torn by a crash.
+fn decode_wal_record(record: &[u8]) -> Option<(SectorIdx, u64, u8, &[u8])> {
+    let (body, crc) = record.split_at(WAL_RECORD_LEN - 4);
+    if crc32c::crc32c(body).to_le_bytes() != crc {
+        return None;
+    }
+    let idx = u64::from_le_bytes(body[0..8].try_into().unwrap());
+    let logical_timestamp = u64::from_le_bytes(body[8..16].try_into().unwrap());
+    Some((idx, logical_timestamp, body[16], &body[17..]))
+}
+
+fn apply(
+    data: &File,
+    meta: &File,
+    idx: SectorIdx,
+    logical_timestamp: u64,
+    write_rank: u8,
+    content: &[u8],
+) -> io::Result<()> {
+    write_all_at(data, content, idx * SECTOR_LEN as u64)?;
+    let checksum = sector_checksum(idx, logical_timestamp, write_rank, content);
+    write_all_at(
+        meta,
+        &encode_meta_record(logical_timestamp, write_rank, checksum),
+        idx * META_RECORD_LEN as u64,
+    )
+}
+
+fn open_sized(path: &Path, len: u64) -> io::Result<File> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+    // Never shrink, sectors past `n_sectors` may still be needed after
+    // a configuration change is reverted.
+    let mut offset = file.metadata()?.len();
+    if offset < len {
+        let zeros = vec![0; PREALLOCATION_CHUNK_LEN];
+        while offset < len {
+            let chunk_len = (len - offset).min(PREALLOCATION_CHUNK_LEN as u64);
+            write_all_at(&file, &zeros[..chunk_len as usize], offset)?;
+            offset += chunk_len;
+        }
+        file.sync_data()?;
+    }
+    Ok(file)
+}
+
+/// Applies records of the log and truncates it. Returns the applied records.
+fn replay_wal(data: &File, meta: &File, wal: &mut File) -> io::Result<usize> {
+    let mut content = vec![];
+    wal.read_to_end(&mut content)?;
+    let mut applied = 0;
+    for record in content.chunks_exact(WAL_RECORD_LEN) {
+        match decode_wal_record(record) {
+            Some((idx, logical_timestamp, write_rank, sector)) => {
+                apply(data, meta, idx, logical_timestamp, write_rank, sector)?;
+                applied += 1;
+            }
+            None => break,
+        }
+    }
+    data.sync_data()?;
+    meta.sync_data()?;
+    wal.set_len(0)?;
+    wal.sync_data()?;
+    Ok(applied)
+}
+
+/// Opens and preallocates the files and replays the log. Returns the files
+/// and the content of the metadata file.
+fn open_files(
+    path: &Path,
+    n_sectors: u64,
+    data_len: u64,
+) -> io::Result<(File, File, File, Vec<u8>)> {
+    let data = open_sized(&path.join(DATA_FILENAME), data_len)?;
+    let meta = open_sized(
+        &path.join(META_FILENAME),
+        n_sectors * META_RECORD_LEN as u64,
+    )?;
+    let mut wal = OpenOptions::new()
+        .read(true)
+        .append(true)
+        .create(true)
+        .open(path.join(WAL_FILENAME))?;
+    let replayed = replay_wal(&data, &meta, &mut wal)?;
+    if replayed > 0 {
+        info!(
+            "Replayed {} sector writes from the write-ahead log",
+            replayed
+        );
+    }
+    File::open(path)?.sync_data()?;
+    let mut records = vec![];
+    (&meta).read_to_end(&mut records)?;
+    Ok((data, meta, wal, records))
+}
+
+impl DataFileSectorsManager {
+    pub(crate) async fn new(
+        path: &Path,
+        n_sectors: u64,
+        config: &StorageConfiguration,
+    ) -> Result<Self, StorageError> {
+        let data_len = (n_sectors.checked_mul(SECTOR_LEN as u64)).ok_or(
+            StorageError::InvalidInput("too many sectors for a single data file"),
+        )?;
+        let path = path.to_path_buf();
+        let (data, meta, wal, records) =
+            blocking(move || open_files(&path, n_sectors, data_len)).await?;
+
+        let mut idx_to_meta = vec![HashMap::new(); NUMBER_OF_WORKERS];
+        let mut written = vec![];
+        for (idx, record) in records.chunks_exact(META_RECORD_LEN).enumerate() {
+            let idx = idx as SectorIdx;
+            if let Some(sector_meta) = decode_meta_record(idx, record) {
+                if let SectorMeta::Written {
+                    logical_timestamp,
+                    write_rank,
+                    ..
+                } = sector_meta
+                {
+                    written.push((idx, (logical_timestamp, write_rank)));
+                }
+                idx_to_meta[(idx as usize) % NUMBER_OF_WORKERS].insert(idx, sector_meta);
+            }
+        }
+
+        Ok(DataFileSectorsManager {
+            data: Arc::new(data),
+            meta: Arc::new(meta),
+            wal: Mutex::new(Wal {
+                file: Arc::new(wal),
+                records: 0,
+            }),
+            checkpoint: RwLock::new(()),
+            idx_to_meta: idx_to_meta.into_iter().map(RwLock::new).collect(),
+            merkle: std::sync::Mutex::new(MerkleTree::from_entries(written.into_iter())),
+            cache: SectorCache::new(config.read_cache_sectors),
+            n_sectors,
+        })
+    }
+
+    /// A failed checkpoint is retried after the next write.
+    async fn checkpoint(&self) {
+        let _checkpoint = self.checkpoint.write().await;
+        let mut wal = self.wal.lock().await;
+        if wal.records < WAL_CHECKPOINT_RECORDS {
+            // Someone else was faster.
+            return;
+        }
+        let (data, meta, file) = (self.data.clone(), self.meta.clone(), wal.file.clone());
+        let result = blocking(move || {
+            data.sync_data()?;
+            meta.sync_data()?;
+            file.set_len(0)?;
+            file.sync_data()
+        })
+        .await;
+        match result {
+            Ok(()) => wal.records = 0,
+            Err(err) => error!("Couldn't truncate the write-ahead log: {}", err),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl SectorsManager for DataFileSectorsManager {
+    async fn read_data(&self, idx: SectorIdx) -> Result<SectorVec, StorageError> {
+        let map = self.idx_to_meta[(idx as usize) % NUMBER_OF_WORKERS]
+            .read()
+            .await;
+        match map.get(&idx).copied() {
+            Some(SectorMeta::Written {
+                logical_timestamp,
+                write_rank,
+                checksum,
+            }) => {
+                if let Some(data) = self.cache.as_ref().and_then(|cache| cache.get(idx)) {
+                    return Ok(data);
+                }
+                let data = self.data.clone();
+                let content = blocking(move || {
+                    let mut content = vec![0; SECTOR_LEN];
+                    read_exact_at(&data, &mut content, idx * SECTOR_LEN as u64)?;
+                    Ok(content)
+                })
+                .await?;
+                if sector_checksum(idx, logical_timestamp, write_rank, &content) == checksum {
+                    if let Some(cache) = &self.cache {
+                        cache.insert(idx, SectorVec(content.clone()));
+                    }
+                    Ok(SectorVec(content))
+                } else {
+                    Err(StorageError::Corrupted(idx))
+                }
+            }
+            Some(SectorMeta::Corrupted) => Err(StorageError::Corrupted(idx)),
+            None => Ok(SectorVec(vec![0; SECTOR_LEN])),
+        }
+    }
+
+    async fn read_metadata(&self, idx: SectorIdx) -> Result<(u64, u8), StorageError> {
+        let map = self.idx_to_meta[(idx as usize) % NUMBER_OF_WORKERS]
+            .read()
+            .await;
+        match map.get(&idx) {
+            Some(SectorMeta::Written {
+                logical_timestamp,
+                write_rank,
+                ..
+            }) => Ok((*logical_timestamp, *write_rank)),
+            Some(SectorMeta::Corrupted) => Err(StorageError::Corrupted(idx)),
+            None => Ok((0, 0)),
+        }
+    }
+
+    async fn range_digest(&self, range: Range<SectorIdx>) -> Result<u64, StorageError> {
+        Ok(self.merkle.lock().unwrap().range_digest(range))
+    }
+
+    fn cache_stats(&self) -> Option<SectorCacheStats> {
+        self.cache.as_ref().map(SectorCache::stats)
+    }
+
+    /// A record which failed to be logged is cut off the log, so it doesn't
+    /// hide the following ones from replay. A write which failed to be applied
+    /// stays in the log, and may take effect on restart.
+    async fn write(
+        &self,
+        idx: SectorIdx,
+        sector: &(SectorVec, u64, u8),
+    ) -> Result<(), StorageError> {
+        let (SectorVec(content), logical_timestamp, write_rank) = sector;
+        let (logical_timestamp, write_rank) = (*logical_timestamp, *write_rank);
+        if idx >= self.n_sectors {
+            return Err(StorageError::InvalidInput(
+                "sector index out of the data file",
+            ));
+        }
+        if content.len() != SECTOR_LEN {
+            return Err(StorageError::InvalidInput("sector data of wrong length"));
+        }
+
+        let mut map = self.idx_to_meta[(idx as usize) % NUMBER_OF_WORKERS]
+            .write()
+            .await;
+        let checkpoint = self.checkpoint.read().await;
+        let record = encode_wal_record(idx, logical_timestamp, write_rank, content);
+        let needs_checkpoint = {
+            let mut wal = self.wal.lock().await;
+            let file = wal.file.clone();
+            let logged_len = (wal.records * WAL_RECORD_LEN) as u64;
+            let result = blocking(move || {
+                let result = (&*file).write_all(&record).and_then(|()| file.sync_data());
+                if result.is_err() {
+                    file.set_len(logged_len)?;
+                }
+                result
+            })
+            .await;
+            if let Err(err) = result {
+                error!("Couldn't log a write of sector {}: {}", idx, err);
+                return Err(err.into());
+            }
+            wal.records += 1;
+            wal.records >= WAL_CHECKPOINT_RECORDS
+        };
+        let checksum = sector_checksum(idx, logical_timestamp, write_rank, content);
+        let (data, meta, applied) = (self.data.clone(), self.meta.clone(), content.clone());
+        blocking(move || apply(&data, &meta, idx, logical_timestamp, write_rank, &applied)).await?;
+        if let Some(cache) = &self.cache {
+            cache.insert(idx, SectorVec(content.clone()));
+        }
+        drop(checkpoint);
+        map.insert(
+            idx,
+            SectorMeta::Written {
+                logical_timestamp,
+                write_rank,
+                checksum,
+            },
+        );
+        self.merkle
+            .lock()
+            .unwrap()
+            .update(idx, Some((logical_timestamp, write_rank)));
+        drop(map);
+
+        if needs_checkpoint {
+            self.checkpoint().await;
+        }
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn test_data_file_replays_wal_up_to_torn_record() {
+    let dir = tempfile::tempdir().unwrap();
+    let manager = DataFileSectorsManager::new(dir.path(), 8, &Default::default())
+        .await
+        .unwrap();
+    manager
+        .write(3, &(SectorVec(vec![1; SECTOR_LEN]), 1, 2))
+        .await
+        .unwrap();
+    drop(manager);
+
+    // A crash after logging two writes, the second one torn.
+    let mut wal = OpenOptions::new()
+        .append(true)
+        .open(dir.path().join(WAL_FILENAME))
+        .unwrap();
+    wal.write_all(&encode_wal_record(5, 4, 1, &[5; SECTOR_LEN]))
+        .unwrap();
+    wal.write_all(&encode_wal_record(6, 4, 1, &[6; SECTOR_LEN])[..100])
+        .unwrap();
+    drop(wal);
+
+    let manager = DataFileSectorsManager::new(dir.path(), 8, &Default::default())
+        .await
+        .unwrap();
+    assert_eq!(manager.read_metadata(3).await.unwrap(), (1, 2));
+    assert_eq!(
+        manager.read_data(3).await.unwrap(),
+        SectorVec(vec![1; SECTOR_LEN])
+    );
+    assert_eq!(manager.read_metadata(5).await.unwrap(), (4, 1));
+    assert_eq!(
+        manager.read_data(5).await.unwrap(),
+        SectorVec(vec![5; SECTOR_LEN])
+    );
+    assert_eq!(manager.read_metadata(6).await.unwrap(), (0, 0));
+    assert_eq!(
+        manager.read_data(6).await.unwrap(),
+        SectorVec(vec![0; SECTOR_LEN])
+    );
+    assert_eq!(
+        std::fs::metadata(dir.path().join(WAL_FILENAME))
+            .unwrap()
+            .len(),
+        0
+    );
+}
+
+#[tokio::test]
+async fn test_data_file_detects_corruption() {
+    let dir = tempfile::tempdir().unwrap();
+    let manager = DataFileSectorsManager::new(dir.path(), 8, &Default::default())
+        .await
+        .unwrap();
+    manager
+        .write(1, &(SectorVec(vec![1; SECTOR_LEN]), 1, 1))
+        .await
+        .unwrap();
+    manager
+        .write(2, &(SectorVec(vec![2; SECTOR_LEN]), 1, 1))
+        .await
+        .unwrap();
+    drop(manager);
+    // Restart, so the writes are not in the log anymore.
+    drop(
+        DataFileSectorsManager::new(dir.path(), 8, &Default::default())
+            .await
+            .unwrap(),
+    );
+
+    let data = OpenOptions::new()
+        .write(true)
+        .open(dir.path().join(DATA_FILENAME))
+        .unwrap();
+    write_all_at(&data, &[0xff], SECTOR_LEN as u64 + 7).unwrap();
+    let meta = OpenOptions::new()
+        .write(true)
+        .open(dir.path().join(META_FILENAME))
+        .unwrap();
+    write_all_at(&meta, &[0xff], 2 * META_RECORD_LEN as u64).unwrap();
+
+    let manager = DataFileSectorsManager::new(dir.path(), 8, &Default::default())
+        .await
+        .unwrap();
+    assert!(matches!(
+        manager.read_data(1).await,
+        Err(StorageError::Corrupted(1))
+    ));
+    assert!(matches!(
+        manager.read_data(2).await,
+        Err(StorageError::Corrupted(2))
+    ));
+    assert!(matches!(
+        manager.read_metadata(2).await,
+        Err(StorageError::Corrupted(2))
+    ));
+    manager
+        .write(2, &(SectorVec(vec![3; SECTOR_LEN]), 2, 1))
+        .await
+        .unwrap();
+    assert_eq!(
+        manager.read_data(2).await.unwrap(),
+        SectorVec(vec![3; SECTOR_LEN])
+    );
+    assert_eq!(manager.read_metadata(2).await.unwrap(), (2, 1));
+}
@@ -0,0 +1,159 @@
+//! Offline check of the directory of `FileSystemSectorsManager`.
+use super::{
+    cipher, data_file, decode_filename, encode_filename, history, index, snapshots, stripes,
+    verify_sector_file,
+};
+use crate::solution::transfer::SECTOR_LEN;
+use crate::{SectorIdx, SectorReport, SectorState, StorageError, StorageProblem, StorageReport};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::{Path, PathBuf};
+use tokio::fs::{self, File};
+
+/// Whether the sectors manager would end up with `current` on startup, see
+/// `reconcile`.
+fn index_matches(loaded: &index::LoadedIndex, current: &index::Entries) -> bool {
+    let written: HashSet<SectorIdx> = loaded.intents.iter().map(|(idx, _, _)| *idx).collect();
+    let current_known = current.iter().all(|(idx, meta)| {
+        loaded.entries.get(idx) == Some(meta)
+            || loaded
+                .intents
+                .iter()
+                .any(|(intent_idx, ts, wr)| intent_idx == idx && (*ts, *wr) == *meta)
+    });
+    let snapshot_present = loaded
+        .entries
+        .keys()
+        .all(|idx| written.contains(idx) || current.contains_key(idx));
+    current_known && snapshot_present
+}
+
+/// Checks the sector files in `path`, adding findings to `report`. With
+/// `repair`, removes what the sectors manager would remove on startup, and
+/// the index if it doesn't match the sector files. Sector files without a
+/// checksum are expected only in `legacy` storage, not yet upgraded.
+pub(crate) async fn check_sectors(
+    path: &Path,
+    legacy: bool,
+    repair: bool,
+    report: &mut StorageReport,
+) -> Result<(), StorageError> {
+    if fs::metadata(path.join(data_file::DATA_FILENAME))
+        .await
+        .is_ok()
+    {
+        return Err(StorageError::InvalidInput(
+            "checking the data file backend is not supported",
+        ));
+    }
+    if cipher::is_encrypted(path).await? {
+        return Err(StorageError::InvalidInput(
+            "checking encrypted sectors is not supported",
+        ));
+    }
+    if stripes::is_striped(path).await? {
+        return Err(StorageError::InvalidInput(
+            "checking sectors striped across data directories is not supported",
+        ));
+    }
+
+    let mut versions: HashMap<SectorIdx, Vec<(u64, u8)>> = HashMap::new();
+    let mut removable: Vec<(StorageProblem, PathBuf)> = vec![];
+    let mut has_index = false;
+    let mut entries = fs::read_dir(path).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let filename = match entry.file_name().into_string() {
+            Ok(filename) => filename,
+            Err(_) => {
+                report
+                    .problems
+                    .push(StorageProblem::UnknownFile(entry.path()));
+                continue;
+            }
+        };
+        if filename.starts_with("tmpfile") || index::is_checkpoint_tmpfile(&filename) {
+            removable.push((StorageProblem::LeftoverTmpfile(entry.path()), entry.path()));
+        } else if index::is_index_file(&filename) {
+            has_index = true;
+        } else if stripes::is_marker(&filename) {
+            // Checked by the sectors manager on startup.
+            continue;
+        } else if history::is_history_dir(&filename) || snapshots::is_snapshots_dir(&filename) {
+            // Superseded versions and snapshots, managed by the sectors manager.
+            continue;
+        } else if let Ok((idx, logical_timestamp, write_rank)) = decode_filename(filename) {
+            versions
+                .entry(idx)
+                .or_default()
+                .push((logical_timestamp, write_rank));
+        } else {
+            report
+                .problems
+                .push(StorageProblem::UnknownFile(entry.path()));
+        }
+    }
+
+    let mut current: index::Entries = HashMap::new();
+    for (idx, mut metas) in versions {
+        metas.sort_unstable();
+        let (logical_timestamp, write_rank) = metas.pop().unwrap();
+        for (stale_timestamp, stale_rank) in metas {
+            let problem = StorageProblem::StaleVersion {
+                idx,
+                logical_timestamp: stale_timestamp,
+                write_rank: stale_rank,
+            };
+            let filename = encode_filename((idx, stale_timestamp, stale_rank));
+            removable.push((problem, path.join(filename)));
+        }
+
+        let filename = encode_filename((idx, logical_timestamp, write_rank));
+        let content = fs::read(path.join(filename)).await?;
+        let state = if legacy && content.len() == SECTOR_LEN {
+            SectorState::Unverified
+        } else if verify_sector_file(idx, logical_timestamp, write_rank, content).is_ok() {
+            SectorState::Ok
+        } else {
+            report.problems.push(StorageProblem::CorruptedSector(idx));
+            SectorState::Corrupted
+        };
+        report.sectors.push(SectorReport {
+            idx,
+            logical_timestamp,
+            write_rank,
+            state,
+        });
+        current.insert(idx, (logical_timestamp, write_rank));
+    }
+
+    let index_consistent = match has_index {
+        false => true,
+        true => match index::load(path).await {
+            Some(loaded) => index_matches(&loaded, &current),
+            None => false,
+        },
+    };
+
+    for (problem, file) in removable {
+        if !repair {
+            report.problems.push(problem);
+            continue;
+        }
+        match fs::remove_file(file).await {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err.into()),
+            _ => report.repaired.push(problem),
+        }
+    }
+    if !index_consistent {
+        if repair {
+            index::remove(path).await?;
+            report.repaired.push(StorageProblem::InconsistentIndex);
+        } else {
+            report.problems.push(StorageProblem::InconsistentIndex);
+        }
+    }
+    if repair {
+        File::open(path).await?.sync_data().await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,89 @@
+//! Removal of files which are no longer referenced: tmpfiles of failed
+//! writes, and sector versions whose removal after being superseded failed.
+use super::{decode_filename, index};
+use crate::{SectorIdx, SectorsGcStats};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::fs::{self, File};
+
+#[derive(Default)]
+pub(super) struct GcCounters {
+    runs: AtomicU64,
+    removed_files: AtomicU64,
+    reclaimed_bytes: AtomicU64,
+}
+
+impl GcCounters {
+    pub(super) fn record(&self, removed_files: u64, reclaimed_bytes: u64) {
+        self.runs.fetch_add(1, Ordering::Relaxed);
+        self.removed_files
+            .fetch_add(removed_files, Ordering::Relaxed);
+        self.reclaimed_bytes
+            .fetch_add(reclaimed_bytes, Ordering::Relaxed);
+    }
+
+    pub(super) fn stats(&self) -> SectorsGcStats {
+        SectorsGcStats {
+            runs: self.runs.load(Ordering::Relaxed),
+            removed_files: self.removed_files.load(Ordering::Relaxed),
+            reclaimed_bytes: self.reclaimed_bytes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Removes tmpfiles and sector files other than the current version, given
+/// by `current`, from `dirs`. No write may be in progress. Returns the number
+/// of removed files and their total size.
+pub(super) async fn collect(
+    dirs: &[PathBuf],
+    current: &(dyn Fn(SectorIdx) -> Option<(u64, u8)> + Send + Sync),
+) -> io::Result<(u64, u64)> {
+    let mut removed_files = 0;
+    let mut reclaimed_bytes = 0;
+    for path in dirs {
+        let (files, bytes) = collect_dir(path, current).await?;
+        removed_files += files;
+        reclaimed_bytes += bytes;
+    }
+    Ok((removed_files, reclaimed_bytes))
+}
+
+async fn collect_dir(
+    path: &Path,
+    current: &(dyn Fn(SectorIdx) -> Option<(u64, u8)> + Send + Sync),
+) -> io::Result<(u64, u64)> {
+    let mut removed_files = 0;
+    let mut reclaimed_bytes = 0;
+    let mut entries = fs::read_dir(path).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let filename = match entry.file_name().into_string() {
+            Ok(filename) => filename,
+            Err(_) => continue,
+        };
+        let orphaned = if filename.starts_with("tmpfile") || index::is_checkpoint_tmpfile(&filename)
+        {
+            true
+        } else if let Ok((idx, logical_timestamp, write_rank)) = decode_filename(filename) {
+            current(idx) != Some((logical_timestamp, write_rank))
+        } else {
+            false
+        };
+        if !orphaned {
+            continue;
+        }
+        let len = entry.metadata().await?.len();
+        match fs::remove_file(entry.path()).await {
+            Ok(()) => {
+                removed_files += 1;
+                reclaimed_bytes += len;
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+    }
+    if removed_files > 0 {
+        File::open(path).await?.sync_data().await?;
+    }
+    Ok((removed_files, reclaimed_bytes))
+}
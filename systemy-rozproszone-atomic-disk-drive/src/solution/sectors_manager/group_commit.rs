@@ -0,0 +1,199 @@
+//! Group commit of sector writes.
+//!
+//! Making a single write durable takes an fsync of the index log, of the
+//! tmpfile, and two of the directory. Writes of different workers are
+//! therefore handed to a single committer task, which makes all writes that
+//! arrived in the meantime durable together: their intents share one fsync of
+//! the index log, their tmpfiles are synced concurrently, and their renames
+//! share the directory fsyncs. Directories of different stripes are synced
+//! concurrently as well, so batches spread across more disks get durable
+//! faster. Every write of a batch succeeds or fails on its own.
+use super::encode_filename;
+use super::history;
+use super::index::IndexLog;
+use super::stripes::Stripes;
+use crate::{SectorIdx, StorageError};
+use log::*;
+use std::future::Future;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::fs::{self, File};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{mpsc, oneshot, Mutex};
+use tokio::time::{self, Duration};
+
+pub(super) struct SectorWrite {
+    pub(super) idx: SectorIdx,
+    pub(super) logical_timestamp: u64,
+    pub(super) write_rank: u8,
+    /// Content of the sector file, data followed by its checksum.
+    pub(super) content: Vec<u8>,
+    /// Metadata of the version being replaced, if any.
+    pub(super) old: Option<(u64, u8)>,
+    /// Informed once the write is durable, or has failed.
+    pub(super) done: oneshot::Sender<Result<(), StorageError>>,
+}
+
+async fn sync_dir(path: &Path) -> io::Result<()> {
+    File::open(path).await?.sync_data().await
+}
+
+/// Runs `tasks` concurrently and returns their results, in order. A task
+/// which has panicked fails with an io error.
+async fn run_all<F>(tasks: impl IntoIterator<Item = F>) -> Vec<io::Result<()>>
+where
+    F: Future<Output = io::Result<()>> + Send + 'static,
+{
+    let handles: Vec<_> = tasks.into_iter().map(tokio::spawn).collect();
+    let mut results = vec![];
+    for handle in handles {
+        results.push(
+            handle
+                .await
+                .unwrap_or_else(|err| Err(io::Error::other(err))),
+        );
+    }
+    results
+}
+
+/// Syncs the directories of the stripes written to by writes which haven't
+/// failed yet, concurrently, together with their history directories if
+/// `with_history`. Writes fail if their directory can't be synced.
+async fn sync_dirs(
+    stripes: &Stripes,
+    writes: &[SectorWrite],
+    results: &mut [Result<(), StorageError>],
+    with_history: bool,
+) {
+    let mut dirs: Vec<&Path> = (writes.iter().zip(results.iter()))
+        .filter(|(_, result)| result.is_ok())
+        .map(|(write, _)| stripes.dir(write.idx))
+        .collect();
+    dirs.sort_unstable();
+    dirs.dedup();
+    let synced = run_all(dirs.iter().map(|dir| {
+        let dir = dir.to_path_buf();
+        async move {
+            sync_dir(&dir).await?;
+            match with_history {
+                true => sync_dir(&history::dir(&dir)).await,
+                false => Ok(()),
+            }
+        }
+    }))
+    .await;
+    for (dir, result) in dirs.into_iter().zip(synced) {
+        if let Err(err) = result {
+            let err = StorageError::from(err);
+            for (write, result) in writes.iter().zip(results.iter_mut()) {
+                if result.is_ok() && stripes.dir(write.idx) == dir {
+                    *result = Err(err.clone());
+                }
+            }
+        }
+    }
+}
+
+/// Makes `writes` durable, and returns the result of each of them, so a
+/// failed write doesn't fail the others. With `keep_history`, superseded
+/// versions are moved to the history directory of their stripe instead of
+/// being removed.
+async fn commit(
+    stripes: &Stripes,
+    index: &Mutex<IndexLog>,
+    keep_history: bool,
+    writes: &[SectorWrite],
+) -> Vec<Result<(), StorageError>> {
+    let logged = index
+        .lock()
+        .await
+        .append_all(
+            writes
+                .iter()
+                .map(|write| (write.idx, write.logical_timestamp, write.write_rank)),
+        )
+        .await;
+    if let Err(err) = logged {
+        let err = StorageError::from(err);
+        return writes.iter().map(|_| Err(err.clone())).collect();
+    }
+
+    let mut renames = vec![];
+    let mut tmpfiles = vec![];
+    for write in writes {
+        let path = stripes.dir(write.idx);
+        let filename = encode_filename((write.idx, write.logical_timestamp, write.write_rank));
+        let tmppath = path.join(format!("tmpfile{}", filename));
+        let content = write.content.clone();
+        renames.push((tmppath.clone(), path.join(filename)));
+        tmpfiles.push(async move {
+            let mut tmpfile = File::create(&tmppath).await?;
+            tmpfile.write_all(&content).await?;
+            tmpfile.sync_data().await
+        });
+    }
+    let mut results: Vec<_> = (run_all(tmpfiles).await.into_iter())
+        .map(|result| result.map_err(StorageError::from))
+        .collect();
+    sync_dirs(stripes, writes, &mut results, false).await;
+
+    for ((tmppath, new_filepath), result) in renames.iter().zip(results.iter_mut()) {
+        if result.is_ok() {
+            if let Err(err) = fs::rename(tmppath, new_filepath).await {
+                *result = Err(err.into());
+            }
+        }
+    }
+    for (write, result) in writes.iter().zip(results.iter()) {
+        if result.is_err() {
+            continue;
+        }
+        if let Some((logical_timestamp, write_rank)) = write.old {
+            if (logical_timestamp, write_rank) != (write.logical_timestamp, write.write_rank) {
+                let old_filename = encode_filename((write.idx, logical_timestamp, write_rank));
+                let path = stripes.dir(write.idx);
+                let _ = match keep_history {
+                    true => {
+                        let history_path = history::dir(path).join(&old_filename);
+                        fs::rename(path.join(old_filename), history_path).await
+                    }
+                    false => fs::remove_file(path.join(old_filename)).await,
+                };
+            }
+        }
+    }
+    sync_dirs(stripes, writes, &mut results, keep_history).await;
+    results
+}
+
+/// Commits writes in batches until all senders are gone. After the first
+/// write of a batch arrives, others are awaited for `window`. Every batch is
+/// counted in `batches`.
+pub(super) async fn run_committer(
+    stripes: Stripes,
+    index: Arc<Mutex<IndexLog>>,
+    window: Duration,
+    keep_history: bool,
+    batches: Arc<AtomicU64>,
+    mut rx: mpsc::UnboundedReceiver<SectorWrite>,
+) {
+    while let Some(write) = rx.recv().await {
+        if !window.is_zero() {
+            time::sleep(window).await;
+        }
+        let mut writes = vec![write];
+        while let Ok(write) = rx.try_recv() {
+            writes.push(write);
+        }
+        batches.fetch_add(1, Ordering::Relaxed);
+        let results = commit(&stripes, &index, keep_history, &writes).await;
+        for (write, result) in writes.into_iter().zip(results) {
+            if let Err(err) = &result {
+                error!("Couldn't commit a write of sector {}: {}", write.idx, err);
+            }
+            let _ = write.done.send(result);
+        }
+    }
+}
@@ -0,0 +1,144 @@
+//! Superseded versions of sectors. Instead of being removed, a superseded
+//! version is moved to the `history` directory of its stripe, until it is
+//! neither among the newest `keep` ones nor written within `retention`.
+use super::cipher::{real_meta, SectorsCipher};
+use super::decode_filename;
+use super::stripes::Stripes;
+use crate::SectorIdx;
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+use tokio::fs;
+
+const HISTORY_DIR: &str = "history";
+
+pub(super) fn is_history_dir(filename: &str) -> bool {
+    filename == HISTORY_DIR
+}
+
+/// Directory of superseded versions of the stripe in `path`.
+pub(super) fn dir(path: &Path) -> PathBuf {
+    path.join(HISTORY_DIR)
+}
+
+/// Metadata of a version, with the time it was written.
+type Version = ((u64, u8), SystemTime);
+
+pub(super) struct History {
+    keep: usize,
+    retention: Duration,
+    /// Versions of every sector, oldest first.
+    versions: Mutex<HashMap<SectorIdx, Vec<Version>>>,
+}
+
+impl History {
+    /// Whether superseded versions are kept at all.
+    pub(super) fn enabled(&self) -> bool {
+        self.keep > 0 || !self.retention.is_zero()
+    }
+
+    /// Finds the versions kept in the history directories of `stripes`,
+    /// creating them if history is enabled.
+    pub(super) async fn load(
+        stripes: &Stripes,
+        cipher: Option<&SectorsCipher>,
+        keep: usize,
+        retention: Duration,
+    ) -> io::Result<Self> {
+        let mut versions: HashMap<SectorIdx, Vec<_>> = HashMap::new();
+        for path in stripes.dirs() {
+            let path = dir(path);
+            if keep > 0 || !retention.is_zero() {
+                fs::create_dir_all(&path).await?;
+            }
+            let mut entries = match fs::read_dir(&path).await {
+                Ok(entries) => entries,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err),
+            };
+            while let Some(entry) = entries.next_entry().await? {
+                let filename = match entry.file_name().into_string() {
+                    Ok(filename) => filename,
+                    Err(_) => continue,
+                };
+                if let Ok((idx, logical_timestamp, write_rank)) = decode_filename(filename) {
+                    let written = entry.metadata().await?.modified()?;
+                    let meta = real_meta(cipher, idx, (logical_timestamp, write_rank));
+                    versions.entry(idx).or_default().push((meta, written));
+                }
+            }
+        }
+        for sector_versions in versions.values_mut() {
+            sector_versions.sort_unstable();
+        }
+        Ok(History {
+            keep,
+            retention,
+            versions: Mutex::new(versions),
+        })
+    }
+
+    /// Kept versions of the sector, newest first.
+    pub(super) fn versions(&self, idx: SectorIdx) -> Vec<(u64, u8)> {
+        let versions = self.versions.lock().unwrap();
+        (versions.get(&idx).into_iter().flatten().rev())
+            .map(|(meta, _)| *meta)
+            .collect()
+    }
+
+    pub(super) fn contains(&self, idx: SectorIdx, meta: (u64, u8)) -> bool {
+        let versions = self.versions.lock().unwrap();
+        (versions.get(&idx).into_iter().flatten()).any(|(kept, _)| *kept == meta)
+    }
+
+    /// Records a superseded version, returning the versions which are no
+    /// longer kept.
+    pub(super) fn push(
+        &self,
+        idx: SectorIdx,
+        meta: (u64, u8),
+        written: SystemTime,
+    ) -> Vec<(u64, u8)> {
+        let mut versions = self.versions.lock().unwrap();
+        let sector_versions = versions.entry(idx).or_default();
+        sector_versions.push((meta, written));
+        sector_versions.sort_unstable();
+        self.trim(sector_versions)
+    }
+
+    /// Forgets versions which are no longer kept, returning them.
+    pub(super) fn expire(&self) -> Vec<(SectorIdx, (u64, u8))> {
+        let mut versions = self.versions.lock().unwrap();
+        let mut expired = vec![];
+        for (idx, sector_versions) in versions.iter_mut() {
+            expired.extend(
+                self.trim(sector_versions)
+                    .into_iter()
+                    .map(|meta| (*idx, meta)),
+            );
+        }
+        versions.retain(|_, sector_versions| !sector_versions.is_empty());
+        expired
+    }
+
+    fn trim(&self, sector_versions: &mut Vec<Version>) -> Vec<(u64, u8)> {
+        let now = SystemTime::now();
+        let older = sector_versions.len().saturating_sub(self.keep);
+        let mut expired = vec![];
+        let mut i = 0;
+        sector_versions.retain(|(meta, written)| {
+            let recent = now
+                .duration_since(*written)
+                .map_or(true, |age| age < self.retention);
+            i += 1;
+            let kept = i > older || recent;
+            if !kept {
+                expired.push(*meta);
+            }
+            kept
+        });
+        expired
+    }
+}
@@ -0,0 +1,228 @@
+//! Persisted metadata of sectors, so that startup doesn't need to list the
+//! whole sectors directory.
+//!
+//! The index consists of `index.snapshot`, holding metadata of every sector
+//! at the time of the last checkpoint, and `index.log`, to which the intent
+//! of every later write is appended before the sector file is renamed into
+//! place. On startup only sectors mentioned in the log have to be checked
+//! against the directory, since their writes may or may not have completed.
+use crate::SectorIdx;
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+
+const SNAPSHOT_FILENAME: &str = "index.snapshot";
+const SNAPSHOT_TMP_FILENAME: &str = "index.snapshot.tmp";
+const LOG_FILENAME: &str = "index.log";
+const SNAPSHOT_MAGIC: &[u8; 8] = b"ATDDIDX1";
+
+/// Sector index, timestamp, write rank.
+const ENTRY_LEN: usize = 8 + 8 + 1;
+/// An entry followed by its checksum.
+const LOG_RECORD_LEN: usize = ENTRY_LEN + 4;
+/// The log is folded into the snapshot after that many records.
+const LOG_CHECKPOINT_RECORDS: usize = 4096;
+
+pub(super) type Entries = HashMap<SectorIdx, (u64, u8)>;
+
+pub(super) struct LoadedIndex {
+    /// Metadata at the time of the last checkpoint.
+    pub(super) entries: Entries,
+    /// Writes started after the checkpoint, in order.
+    pub(super) intents: Vec<(SectorIdx, u64, u8)>,
+}
+
+fn encode_entry(buf: &mut Vec<u8>, (idx, logical_timestamp, write_rank): (SectorIdx, u64, u8)) {
+    buf.extend_from_slice(&idx.to_le_bytes());
+    buf.extend_from_slice(&logical_timestamp.to_le_bytes());
+    buf.push(write_rank);
+}
+
+fn decode_entry(buf: &[u8]) -> (SectorIdx, u64, u8) {
+    (
+        u64::from_le_bytes(buf[0..8].try_into().unwrap()),
+        u64::from_le_bytes(buf[8..16].try_into().unwrap()),
+        buf[16],
+    )
+}
+
+fn decode_snapshot(content: &[u8]) -> Option<Entries> {
+    if content.len() < SNAPSHOT_MAGIC.len() + 8 + 4 || &content[..8] != SNAPSHOT_MAGIC {
+        return None;
+    }
+    let (body, crc) = content.split_at(content.len() - 4);
+    if crc32c::crc32c(body).to_le_bytes() != crc {
+        return None;
+    }
+    let count = u64::from_le_bytes(body[8..16].try_into().unwrap()) as usize;
+    let entries = &body[16..];
+    if entries.len() != count.checked_mul(ENTRY_LEN)? {
+        return None;
+    }
+    Some(
+        entries
+            .chunks_exact(ENTRY_LEN)
+            .map(decode_entry)
+            .map(|(idx, logical_timestamp, write_rank)| (idx, (logical_timestamp, write_rank)))
+            .collect(),
+    )
+}
+
+/// Only the last record can be torn by a crash, a broken record followed by
+/// others means the log is corrupted.
+fn decode_log(content: &[u8]) -> Option<Vec<(SectorIdx, u64, u8)>> {
+    let mut intents = vec![];
+    let mut records = content.chunks(LOG_RECORD_LEN).peekable();
+    while let Some(record) = records.next() {
+        let valid = record.len() == LOG_RECORD_LEN
+            && crc32c::crc32c(&record[..ENTRY_LEN]).to_le_bytes() == record[ENTRY_LEN..];
+        if valid {
+            intents.push(decode_entry(record));
+        } else if records.peek().is_some() {
+            return None;
+        }
+    }
+    Some(intents)
+}
+
+/// `None` if there is no usable index, and the directory has to be scanned.
+pub(super) async fn load(dir: &Path) -> Option<LoadedIndex> {
+    let snapshot = fs::read(dir.join(SNAPSHOT_FILENAME)).await.ok()?;
+    let log = fs::read(dir.join(LOG_FILENAME)).await.ok()?;
+    Some(LoadedIndex {
+        entries: decode_snapshot(&snapshot)?,
+        intents: decode_log(&log)?,
+    })
+}
+
+pub(super) fn is_index_file(filename: &str) -> bool {
+    filename == SNAPSHOT_FILENAME || filename == LOG_FILENAME
+}
+
+/// Whether the file is a leftover of an interrupted checkpoint.
+pub(super) fn is_checkpoint_tmpfile(filename: &str) -> bool {
+    filename == SNAPSHOT_TMP_FILENAME
+}
+
+/// Removes the index, so it is rebuilt from sector files on startup.
+pub(super) async fn remove(dir: &Path) -> io::Result<()> {
+    for filename in [SNAPSHOT_FILENAME, LOG_FILENAME] {
+        match fs::remove_file(dir.join(filename)).await {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+    }
+    File::open(dir).await?.sync_data().await
+}
+
+/// Whether there are index files, even if they are not usable.
+pub(super) async fn exists(dir: &Path) -> bool {
+    fs::metadata(dir.join(SNAPSHOT_FILENAME)).await.is_ok()
+}
+
+pub(super) struct IndexLog {
+    dir: PathBuf,
+    file: File,
+    records: usize,
+}
+
+impl IndexLog {
+    /// Starts a new index, with `entries` as its snapshot.
+    pub(super) async fn create(dir: &Path, entries: &Entries) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.join(LOG_FILENAME))
+            .await?;
+        let mut log = IndexLog {
+            dir: dir.to_path_buf(),
+            file,
+            records: 0,
+        };
+        log.checkpoint(entries.iter().map(|(idx, tup)| (*idx, *tup)))
+            .await?;
+        Ok(log)
+    }
+
+    /// Durably records that sector `idx` is about to be written.
+    #[cfg(test)]
+    pub(super) async fn append(
+        &mut self,
+        idx: SectorIdx,
+        logical_timestamp: u64,
+        write_rank: u8,
+    ) -> io::Result<()> {
+        self.append_all([(idx, logical_timestamp, write_rank)].into_iter())
+            .await
+    }
+
+    /// Like `append`, for many writes at once, with a single fsync.
+    pub(super) async fn append_all(
+        &mut self,
+        entries: impl Iterator<Item = (SectorIdx, u64, u8)>,
+    ) -> io::Result<()> {
+        let mut records = vec![];
+        for entry in entries {
+            let start = records.len();
+            encode_entry(&mut records, entry);
+            let crc = crc32c::crc32c(&records[start..]);
+            records.extend_from_slice(&crc.to_le_bytes());
+            self.records += 1;
+        }
+        self.file.write_all(&records).await?;
+        self.file.sync_data().await
+    }
+
+    pub(super) fn needs_checkpoint(&self) -> bool {
+        self.records >= LOG_CHECKPOINT_RECORDS
+    }
+
+    /// Replaces the snapshot with `entries` and empties the log. No write
+    /// may be in progress.
+    pub(super) async fn checkpoint(
+        &mut self,
+        entries: impl Iterator<Item = (SectorIdx, (u64, u8))>,
+    ) -> io::Result<()> {
+        let mut body = SNAPSHOT_MAGIC.to_vec();
+        body.extend_from_slice(&[0; 8]);
+        let mut count: u64 = 0;
+        for (idx, (logical_timestamp, write_rank)) in entries {
+            encode_entry(&mut body, (idx, logical_timestamp, write_rank));
+            count += 1;
+        }
+        body[8..16].copy_from_slice(&count.to_le_bytes());
+        let crc = crc32c::crc32c(&body);
+        body.extend_from_slice(&crc.to_le_bytes());
+
+        let tmp_path = self.dir.join(SNAPSHOT_TMP_FILENAME);
+        let mut tmp = File::create(&tmp_path).await?;
+        tmp.write_all(&body).await?;
+        tmp.sync_data().await?;
+        drop(tmp);
+        fs::rename(&tmp_path, self.dir.join(SNAPSHOT_FILENAME)).await?;
+        File::open(&self.dir).await?.sync_data().await?;
+
+        self.file.set_len(0).await?;
+        self.file.sync_data().await?;
+        self.records = 0;
+        Ok(())
+    }
+}
+
+#[test]
+fn test_index_log_tolerates_only_torn_tail() {
+    let mut content = vec![];
+    for idx in 0..3 {
+        let mut record = vec![];
+        encode_entry(&mut record, (idx, 1, 1));
+        let crc = crc32c::crc32c(&record);
+        record.extend_from_slice(&crc.to_le_bytes());
+        content.extend_from_slice(&record);
+    }
+    assert_eq!(decode_log(&content).unwrap().len(), 3);
+    assert_eq!(decode_log(&content[..50]).unwrap().len(), 2);
+    content[30] ^= 1;
+    assert!(decode_log(&content).is_none());
+}
@@ -0,0 +1,157 @@
+//! Merkle tree over metadata of sectors, for comparing replicas cheaply.
+//!
+//! The tree spans the whole space of sector indices: a node of level `l` at
+//! index `i` covers sectors `i << l` up to `(i + 1) << l` (exclusive), and
+//! the root is the only node of level 64. Nodes covering no written sector
+//! have hash 0 and are not stored, so the tree is sparse, and a write updates
+//! only the 65 nodes on the path from its leaf to the root.
+use crate::SectorIdx;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::ops::Range;
+
+const LEVELS: u8 = 64;
+
+fn hash(parts: &[&[u8]]) -> u64 {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    u64::from_le_bytes(hasher.finalize()[..8].try_into().unwrap())
+}
+
+fn leaf_hash(idx: SectorIdx, (logical_timestamp, write_rank): (u64, u8)) -> u64 {
+    hash(&[
+        &[0],
+        &idx.to_le_bytes(),
+        &logical_timestamp.to_le_bytes(),
+        &[write_rank],
+    ])
+}
+
+fn node_hash(left: u64, right: u64) -> u64 {
+    if left == 0 && right == 0 {
+        0
+    } else {
+        hash(&[&[1], &left.to_le_bytes(), &right.to_le_bytes()])
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct MerkleTree {
+    /// Non-zero hashes by level and index within the level.
+    nodes: HashMap<(u8, u64), u64>,
+}
+
+impl MerkleTree {
+    /// Builds the tree bottom-up, hashing every stored node once.
+    pub(crate) fn from_entries(entries: impl Iterator<Item = (SectorIdx, (u64, u8))>) -> Self {
+        let mut nodes = HashMap::new();
+        let mut level: HashMap<u64, u64> = entries
+            .map(|(idx, meta)| (idx, leaf_hash(idx, meta)))
+            .collect();
+        for l in 0..=LEVELS {
+            let mut parents = HashMap::new();
+            for (&index, &node) in &level {
+                nodes.insert((l, index), node);
+                if l < LEVELS {
+                    parents.entry(index >> 1).or_insert(0);
+                }
+            }
+            for (index, parent) in parents.iter_mut() {
+                let left = level.get(&(index << 1)).copied().unwrap_or(0);
+                let right = level.get(&((index << 1) | 1)).copied().unwrap_or(0);
+                *parent = node_hash(left, right);
+            }
+            level = parents;
+        }
+        MerkleTree { nodes }
+    }
+
+    fn node(&self, level: u8, index: u64) -> u64 {
+        self.nodes.get(&(level, index)).copied().unwrap_or(0)
+    }
+
+    fn set_node(&mut self, level: u8, index: u64, node: u64) {
+        if node == 0 {
+            self.nodes.remove(&(level, index));
+        } else {
+            self.nodes.insert((level, index), node);
+        }
+    }
+
+    /// Sets metadata of sector `idx`, `None` if it is not written.
+    pub(crate) fn update(&mut self, idx: SectorIdx, meta: Option<(u64, u8)>) {
+        let mut node = meta.map_or(0, |meta| leaf_hash(idx, meta));
+        let mut index = idx;
+        self.set_node(0, index, node);
+        for level in 1..=LEVELS {
+            let sibling = self.node(level - 1, index ^ 1);
+            node = if index & 1 == 0 {
+                node_hash(node, sibling)
+            } else {
+                node_hash(sibling, node)
+            };
+            index >>= 1;
+            self.set_node(level, index, node);
+        }
+    }
+
+    /// Digest of the range, combining hashes of the largest nodes which
+    /// together cover it exactly. Zero if no sector of the range is written.
+    pub(crate) fn range_digest(&self, range: Range<SectorIdx>) -> u64 {
+        let mut start = range.start as u128;
+        let end = range.end as u128;
+        let mut nodes = vec![];
+        while start < end {
+            let mut level = 0;
+            while level < LEVELS
+                && start.is_multiple_of(1 << (level + 1))
+                && start + (1 << (level + 1)) <= end
+            {
+                level += 1;
+            }
+            nodes.push((level, self.node(level, (start >> level) as u64)));
+            start += 1 << level;
+        }
+        if nodes.iter().all(|(_, node)| *node == 0) {
+            return 0;
+        }
+        let mut hasher = Sha256::new();
+        hasher.update([2]);
+        for (level, node) in nodes {
+            hasher.update([level]);
+            hasher.update(node.to_le_bytes());
+        }
+        u64::from_le_bytes(hasher.finalize()[..8].try_into().unwrap())
+    }
+}
+
+#[test]
+fn test_merkle_tree_updates_match_rebuild() {
+    let entries = [(0, (1, 1)), (5, (2, 3)), (6, (1, 2)), (1000, (7, 1))];
+    let mut tree = MerkleTree::default();
+    for (idx, meta) in entries {
+        tree.update(idx, Some(meta));
+    }
+    let rebuilt = MerkleTree::from_entries(entries.into_iter());
+    for range in [0..8, 5..6, 3..1001, 0..u64::MAX, 7..1000] {
+        assert_eq!(
+            tree.range_digest(range.clone()),
+            rebuilt.range_digest(range)
+        );
+    }
+    assert_eq!(tree.range_digest(7..1000), 0);
+    assert_ne!(tree.range_digest(0..8), 0);
+
+    let before = tree.range_digest(0..1024);
+    tree.update(5, Some((3, 1)));
+    assert_ne!(tree.range_digest(0..1024), before);
+    assert_eq!(
+        tree.range_digest(1000..1024),
+        rebuilt.range_digest(1000..1024)
+    );
+    tree.update(5, Some((2, 3)));
+    assert_eq!(tree.range_digest(0..1024), before);
+    assert_eq!(tree.nodes, rebuilt.nodes);
+}
@@ -0,0 +1,47 @@
+//! Upgrades of the layout of the sectors directory, run by `format::migrate`
+//! before the sectors manager starts. Every upgrade can be interrupted and
+//! run again.
+use super::{decode_filename, sector_checksum};
+use crate::solution::transfer::SECTOR_LEN;
+use std::io;
+use std::path::Path;
+use tokio::fs::{self, File};
+use tokio::io::AsyncWriteExt;
+
+/// Appends checksums to sector files written before they were introduced.
+/// Files are replaced one by one, and leftover tmpfiles are removed on startup.
+pub(crate) async fn add_checksums(path: &Path) -> io::Result<()> {
+    let mut entries = match fs::read_dir(path).await {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err),
+    };
+    let mut upgraded = false;
+    while let Some(entry) = entries.next_entry().await? {
+        let filename = match entry.file_name().into_string() {
+            Ok(filename) if !filename.starts_with("tmpfile") => filename,
+            _ => continue,
+        };
+        let (idx, logical_timestamp, write_rank) = match decode_filename(filename.clone()) {
+            Ok(meta) => meta,
+            Err(_) => continue,
+        };
+        if entry.metadata().await?.len() != SECTOR_LEN as u64 {
+            continue;
+        }
+        let mut content = fs::read(entry.path()).await?;
+        let checksum = sector_checksum(idx, logical_timestamp, write_rank, &content);
+        content.extend_from_slice(&checksum.to_le_bytes());
+
+        let tmppath = path.join(format!("tmpfile{}", filename));
+        let mut tmpfile = File::create(&tmppath).await?;
+        tmpfile.write_all(&content).await?;
+        tmpfile.sync_data().await?;
+        fs::rename(&tmppath, entry.path()).await?;
+        upgraded = true;
+    }
+    if upgraded {
+        File::open(path).await?.sync_data().await?;
+    }
+    Ok(())
+}
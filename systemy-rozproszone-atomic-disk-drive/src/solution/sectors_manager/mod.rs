@@ -1,35 +1,229 @@
+mod cache;
+mod cipher;
+mod data_file;
+pub(crate) mod fsck;
+mod gc;
+mod group_commit;
+mod history;
+mod index;
+pub(crate) mod merkle;
+pub(crate) mod migrations;
+mod snapshots;
+mod stripes;
+
 use crate::solution::transfer::SECTOR_LEN;
-use crate::{SectorIdx, SectorVec, SectorsManager};
+use crate::{
+    SectorCacheStats, SectorIdx, SectorVec, SectorsBackend, SectorsCompression, SectorsGcStats,
+    SectorsManager, StorageConfiguration, StorageError,
+};
+use cache::SectorCache;
+use cipher::{real_meta, stored_meta, SectorsCipher};
+use log::*;
+use merkle::MerkleTree;
 use std::collections::HashMap;
-use std::fs::DirEntry;
 use std::io;
-use std::path::PathBuf;
-use std::sync::Arc;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicU64;
+use std::sync::{Arc, Weak};
 use tokio::fs;
 use tokio::fs::File;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::sync::RwLock;
+use tokio::io::AsyncReadExt;
+use tokio::sync::{mpsc, oneshot, Mutex, RwLock};
+use tokio::time::{self, Duration};
 
 use crate::solution::running::NUMBER_OF_WORKERS;
 
-pub async fn build_sectors_manager(path: PathBuf) -> Arc<dyn SectorsManager> {
-    Arc::new(FileSystemSectorsManager::new(path).await)
+pub async fn build_sectors_manager(path: PathBuf) -> Result<Arc<dyn SectorsManager>, StorageError> {
+    Ok(FileSystemSectorsManager::start(path, &StorageConfiguration::default(), None).await?)
+}
+
+pub async fn build_sectors_manager_with_config(
+    path: PathBuf,
+    n_sectors: u64,
+    config: &StorageConfiguration,
+) -> Result<Arc<dyn SectorsManager>, StorageError> {
+    build_sectors_manager_with_key(path, n_sectors, config, None).await
+}
+
+/// With `key`, sectors are encrypted at rest.
+pub async fn build_sectors_manager_with_key(
+    path: PathBuf,
+    n_sectors: u64,
+    config: &StorageConfiguration,
+    key: Option<&[u8; 32]>,
+) -> Result<Arc<dyn SectorsManager>, StorageError> {
+    match config.sectors_backend {
+        SectorsBackend::FilePerSector => {
+            // Sizes of compressed sectors would tell about their content.
+            if key.is_some() && config.compression != SectorsCompression::None {
+                return Err(StorageError::InvalidInput(
+                    "compression can't be combined with encryption",
+                ));
+            }
+            Ok(FileSystemSectorsManager::start(path, config, key).await?)
+        }
+        SectorsBackend::DataFile => {
+            if !config.data_dirs.is_empty() {
+                return Err(StorageError::InvalidInput(
+                    "data directories aren't supported by the data file backend",
+                ));
+            }
+            if key.is_some() {
+                return Err(StorageError::InvalidInput(
+                    "encryption isn't supported by the data file backend",
+                ));
+            }
+            if config.compression != SectorsCompression::None {
+                return Err(StorageError::InvalidInput(
+                    "compression isn't supported by the data file backend",
+                ));
+            }
+            Ok(Arc::new(
+                data_file::DataFileSectorsManager::new(&path, n_sectors, config).await?,
+            ))
+        }
+    }
+}
+
+/// Length of the checksum stored along with sector data.
+const CHECKSUM_LEN: usize = 4;
+
+/// Checksum of sector data, covering also its index and metadata, so data
+/// stored under wrong metadata is detected as well.
+pub(crate) fn sector_checksum(
+    idx: SectorIdx,
+    logical_timestamp: u64,
+    write_rank: u8,
+    content: &[u8],
+) -> u32 {
+    let mut header = [0; 17];
+    header[0..8].copy_from_slice(&idx.to_le_bytes());
+    header[8..16].copy_from_slice(&logical_timestamp.to_le_bytes());
+    header[16] = write_rank;
+    crc32c::crc32c_append(crc32c::crc32c(&header), content)
+}
+
+/// Length of the compressed length, which precedes compressed data.
+///
+/// It is kept in the sector file rather than in the sectors index, so that
+/// sector files stay self-describing: the index is only an optimization of
+/// startup, and is rebuilt by scanning file names when it is lost or
+/// corrupted, which would lose lengths stored only there. Versions kept in
+/// history and snapshots aren't in the index at all. The index is also
+/// written before the sector file, so a length in it could describe a file
+/// which never made it to disk.
+const COMPRESSED_LEN_LEN: usize = 2;
+
+/// Sector file holding `content` followed by its checksum. With compression,
+/// the data is stored compressed, preceded by its compressed length, if that
+/// makes the file shorter than a sector.
+fn encode_sector_file(
+    idx: SectorIdx,
+    logical_timestamp: u64,
+    write_rank: u8,
+    content: &[u8],
+    compression: SectorsCompression,
+) -> Vec<u8> {
+    let checksum = sector_checksum(idx, logical_timestamp, write_rank, content);
+    let mut file_content = Vec::with_capacity(content.len() + CHECKSUM_LEN);
+    if compression == SectorsCompression::Lz4 {
+        let compressed = lz4_flex::block::compress(content);
+        if COMPRESSED_LEN_LEN + compressed.len() + CHECKSUM_LEN < SECTOR_LEN {
+            file_content.extend_from_slice(&(compressed.len() as u16).to_le_bytes());
+            file_content.extend_from_slice(&compressed);
+        }
+    }
+    if file_content.is_empty() {
+        file_content.extend_from_slice(content);
+    }
+    file_content.extend_from_slice(&checksum.to_le_bytes());
+    file_content
+}
+
+fn decompress(idx: SectorIdx, content: &[u8]) -> Result<Vec<u8>, StorageError> {
+    let (len, compressed) = content.split_at(COMPRESSED_LEN_LEN);
+    if u16::from_le_bytes([len[0], len[1]]) as usize != compressed.len() {
+        return Err(StorageError::Corrupted(idx));
+    }
+    match lz4_flex::block::decompress(compressed, SECTOR_LEN) {
+        Ok(data) if data.len() == SECTOR_LEN => Ok(data),
+        _ => Err(StorageError::Corrupted(idx)),
+    }
+}
+
+/// Sector files hold the data followed by its checksum. Files shorter than
+/// a sector hold compressed data. Files without a checksum, written before
+/// checksums were introduced, are upgraded before the sectors manager starts,
+/// so a file of exactly a sector is corrupted.
+fn verify_sector_file(
+    idx: SectorIdx,
+    logical_timestamp: u64,
+    write_rank: u8,
+    mut content: Vec<u8>,
+) -> Result<SectorVec, StorageError> {
+    match content.len() {
+        len if (COMPRESSED_LEN_LEN + CHECKSUM_LEN..SECTOR_LEN).contains(&len) => {
+            let stored = content.split_off(len - CHECKSUM_LEN);
+            let data = decompress(idx, &content)?;
+            let checksum = sector_checksum(idx, logical_timestamp, write_rank, &data);
+            if checksum.to_le_bytes()[..] == stored[..] {
+                Ok(SectorVec(data))
+            } else {
+                Err(StorageError::Corrupted(idx))
+            }
+        }
+        len if len == SECTOR_LEN + CHECKSUM_LEN => {
+            let stored = content.split_off(SECTOR_LEN);
+            let checksum = sector_checksum(idx, logical_timestamp, write_rank, &content);
+            if checksum.to_le_bytes()[..] == stored[..] {
+                Ok(SectorVec(content))
+            } else {
+                Err(StorageError::Corrupted(idx))
+            }
+        }
+        _ => Err(StorageError::Corrupted(idx)),
+    }
 }
 
 struct FileSystemSectorsManager {
+    /// Directory of the index.
     path: PathBuf,
+    stripes: stripes::Stripes,
     idx_to_meta: Vec<RwLock<HashMap<SectorIdx, (u64, u8)>>>,
+    index: Arc<Mutex<index::IndexLog>>,
+    committer: mpsc::UnboundedSender<group_commit::SectorWrite>,
+    /// Number of batches of writes committed, each with a single fsync of the
+    /// index log.
+    #[cfg(test)]
+    commit_batches: Arc<AtomicU64>,
+    /// Held for reading by writes, from logging their intent until they are
+    /// visible in `idx_to_meta`, so a checkpoint never misses a write. Taken
+    /// before the lock of `idx_to_meta`.
+    checkpoint: RwLock<()>,
+    /// Updated while holding the lock of `idx_to_meta`.
+    merkle: std::sync::Mutex<MerkleTree>,
+    cache: Option<SectorCache>,
+    gc: gc::GcCounters,
+    /// Metadata in file names and the index is disguised by it, see `cipher`.
+    cipher: Option<SectorsCipher>,
+    /// Applied to written sectors, compressed sectors are read regardless.
+    compression: SectorsCompression,
+    /// Superseded versions, changed while holding the lock of `idx_to_meta`
+    /// or the checkpoint lock for writing.
+    history: history::History,
+    snapshots: snapshots::Snapshots,
 }
 
-fn encode_filename(data: (u64, u64, u8)) -> String {
+pub(crate) fn encode_filename(data: (u64, u64, u8)) -> String {
     let mut buf = [0; 18];
     let mut buf_slice = &mut buf[..];
     let (sector_idx, logical_timestamp, write_rank) = data;
     io::Write::write_all(&mut buf_slice, &sector_idx.to_le_bytes()).unwrap();
     io::Write::write_all(&mut buf_slice, &logical_timestamp.to_le_bytes()).unwrap();
     io::Write::write_all(&mut buf_slice, &write_rank.to_le_bytes()).unwrap();
-    let filename = base64::encode_config(buf, base64::URL_SAFE_NO_PAD);
-    filename
+
+    base64::encode_config(buf, base64::URL_SAFE_NO_PAD)
 }
 
 fn decode_filename(filename: String) -> Result<(u64, u64, u8), base64::DecodeError> {
@@ -58,165 +252,1076 @@ fn test_coding() {
     assert_eq!(decode_filename(encode_filename(test2)).unwrap(), test2);
 }
 
-async fn unpack_entry_result(
-    path: PathBuf,
-    entry_result: Result<DirEntry, io::Error>,
-) -> Option<(u64, u64, u8)> {
-    if let Ok(entry) = entry_result {
-        let filename = entry.file_name().into_string();
-        if filename.is_err() {
-            return None;
-        }
-        let filename = filename.unwrap();
+/// Finds metadata of all sectors by listing the directory of every stripe,
+/// and removes leftovers of interrupted writes.
+async fn scan(
+    stripes: &stripes::Stripes,
+    cipher: Option<&SectorsCipher>,
+) -> io::Result<index::Entries> {
+    let mut meta: index::Entries = HashMap::new();
+    for path in stripes.dirs() {
+        scan_dir(path, cipher, &mut meta).await?;
+    }
+    Ok(meta)
+}
+
+async fn scan_dir(
+    path: &Path,
+    cipher: Option<&SectorsCipher>,
+    meta: &mut index::Entries,
+) -> io::Result<()> {
+    let mut entries = fs::read_dir(path).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let filename = match entry.file_name().into_string() {
+            Ok(filename) => filename,
+            Err(_) => continue,
+        };
         // Delete temporary files:
         if filename.starts_with("tmpfile") {
-            let mut to_delete_filepath = path.clone();
-            to_delete_filepath.push(filename);
-            let _ = fs::remove_file(to_delete_filepath);
-            return None;
-        }
-        let tup_err = decode_filename(filename);
-        if let Ok(tup) = tup_err {
-            return Some(tup);
-        } else {
-            return None;
+            let _ = fs::remove_file(path.join(filename)).await;
+            continue;
+        }
+        if let Ok((sector_idx, logical_timestamp, write_rank)) = decode_filename(filename) {
+            let (logical_timestamp, write_rank) =
+                real_meta(cipher, sector_idx, (logical_timestamp, write_rank));
+            if let Some(tup) = meta.get(&sector_idx).copied() {
+                let to_delete = if (logical_timestamp, write_rank) > tup {
+                    meta.insert(sector_idx, (logical_timestamp, write_rank));
+                    tup
+                } else {
+                    (logical_timestamp, write_rank)
+                };
+                let to_delete = stored_meta(cipher, sector_idx, to_delete);
+                let to_delete_filename = encode_filename((sector_idx, to_delete.0, to_delete.1));
+                let _ = fs::remove_file(path.join(to_delete_filename)).await;
+            } else {
+                meta.insert(sector_idx, (logical_timestamp, write_rank));
+            }
+        }
+    }
+    File::open(path).await?.sync_data().await
+}
+
+/// Collects garbage every `interval`, until the manager is dropped.
+async fn run_gc(manager: Weak<FileSystemSectorsManager>, interval: Duration) {
+    loop {
+        time::sleep(interval).await;
+        match manager.upgrade() {
+            Some(manager) => manager.collect_garbage().await,
+            None => return,
+        }
+    }
+}
+
+/// Writes started after the last checkpoint may have completed or not, so for
+/// every such sector the newest existing version is kept, and the rest is
+/// removed.
+async fn reconcile(
+    stripes: &stripes::Stripes,
+    cipher: Option<&SectorsCipher>,
+    loaded: index::LoadedIndex,
+) -> io::Result<index::Entries> {
+    let mut meta: index::Entries = (loaded.entries.into_iter())
+        .map(|(sector_idx, tup)| (sector_idx, real_meta(cipher, sector_idx, tup)))
+        .collect();
+    let mut candidates: HashMap<SectorIdx, Vec<(u64, u8)>> = HashMap::new();
+    for (sector_idx, logical_timestamp, write_rank) in loaded.intents {
+        candidates.entry(sector_idx).or_default().push(real_meta(
+            cipher,
+            sector_idx,
+            (logical_timestamp, write_rank),
+        ));
+    }
+    for (sector_idx, mut versions) in candidates {
+        versions.extend(meta.get(&sector_idx));
+        versions.sort_unstable();
+        versions.dedup();
+        meta.remove(&sector_idx);
+        let path = stripes.dir(sector_idx);
+        for (logical_timestamp, write_rank) in versions.into_iter().rev() {
+            let (stored_timestamp, stored_rank) =
+                stored_meta(cipher, sector_idx, (logical_timestamp, write_rank));
+            let filename = encode_filename((sector_idx, stored_timestamp, stored_rank));
+            let _ = fs::remove_file(path.join(format!("tmpfile{}", filename))).await;
+            if !meta.contains_key(&sector_idx) && fs::metadata(path.join(&filename)).await.is_ok() {
+                meta.insert(sector_idx, (logical_timestamp, write_rank));
+            } else {
+                let _ = fs::remove_file(path.join(filename)).await;
+            }
+        }
+    }
+    for path in stripes.dirs() {
+        File::open(path).await?.sync_data().await?;
+    }
+    Ok(meta)
+}
+
+/// Removes superseded versions which are no longer kept.
+async fn remove_history(
+    stripes: &stripes::Stripes,
+    cipher: Option<&SectorsCipher>,
+    versions: Vec<(SectorIdx, (u64, u8))>,
+) {
+    for (idx, meta) in versions {
+        let (stored_timestamp, stored_rank) = stored_meta(cipher, idx, meta);
+        let filename = encode_filename((idx, stored_timestamp, stored_rank));
+        let path = history::dir(stripes.dir(idx)).join(filename);
+        if let Err(err) = fs::remove_file(&path).await {
+            if err.kind() != io::ErrorKind::NotFound {
+                error!("Couldn't remove old version {}: {}", path.display(), err);
+            }
         }
-    } else {
-        return None;
     }
 }
 
 impl FileSystemSectorsManager {
-    async fn new(path: PathBuf) -> Self {
-        let mut meta = vec![HashMap::new(); NUMBER_OF_WORKERS];
-        // This is not asynchronous but it is okay because
-        // sectors manager is created before the algorithm
-        // is runned.
-        for entry_result in path.read_dir().expect("read_dir call failed") {
-            if let Some((sector_idx, logical_timestamp, write_rank)) =
-                unpack_entry_result(path.clone(), entry_result).await
-            {
-                let tup_opt = meta[(sector_idx as usize) % NUMBER_OF_WORKERS]
-                    .get(&sector_idx)
-                    .clone();
-                if let Some(tup) = tup_opt {
-                    if (logical_timestamp, write_rank) > *tup {
-                        let old_filename = encode_filename((sector_idx, tup.0, tup.1));
-                        meta[(sector_idx as usize) % NUMBER_OF_WORKERS]
-                            .insert(sector_idx, (logical_timestamp, write_rank));
-                        let mut old_filepath = path.clone();
-                        old_filepath.push(old_filename);
-                        let _ = fs::remove_file(old_filepath).await;
-                    } else {
-                        let to_delete_filename =
-                            encode_filename((sector_idx, logical_timestamp, write_rank));
-                        let mut to_delete_filepath = path.clone();
-                        to_delete_filepath.push(to_delete_filename);
-                        let _ = fs::remove_file(to_delete_filepath).await;
-                    }
-                    File::open(&path).await.unwrap().sync_data().await.unwrap();
-                } else {
-                    meta[(sector_idx as usize) % NUMBER_OF_WORKERS]
-                        .insert(sector_idx, (logical_timestamp, write_rank));
+    async fn new(
+        path: PathBuf,
+        config: &StorageConfiguration,
+        key: Option<&[u8; 32]>,
+    ) -> io::Result<Self> {
+        let stripes = stripes::Stripes::new(&path, &config.data_dirs);
+        stripes.check(&path).await?;
+        let cipher = key.map(SectorsCipher::new);
+        let empty = !index::exists(&path).await && !stripes.hold_sectors().await?;
+        cipher::check_key(&path, cipher.as_ref(), empty).await?;
+        let entries = match index::load(&path).await {
+            Some(loaded) => reconcile(&stripes, cipher.as_ref(), loaded).await?,
+            None => {
+                if index::exists(&path).await {
+                    warn!("Sectors index is corrupted, scanning the sectors directory");
                 }
+                scan(&stripes, cipher.as_ref()).await?
             }
+        };
+        let stored_entries = (entries.iter())
+            .map(|(idx, tup)| (*idx, stored_meta(cipher.as_ref(), *idx, *tup)))
+            .collect();
+        let index = index::IndexLog::create(&path, &stored_entries).await?;
+        let index = Arc::new(Mutex::new(index));
+        let history = history::History::load(
+            &stripes,
+            cipher.as_ref(),
+            config.history_versions,
+            config.history_retention,
+        )
+        .await?;
+        remove_history(&stripes, cipher.as_ref(), history.expire()).await;
+        let snapshots = snapshots::Snapshots::load(&path, &stripes, cipher.as_ref()).await?;
+        let (committer, committer_rx) = mpsc::unbounded_channel();
+        let commit_batches = Arc::new(AtomicU64::new(0));
+        tokio::spawn(group_commit::run_committer(
+            stripes.clone(),
+            index.clone(),
+            config.group_commit_window,
+            history.enabled(),
+            commit_batches.clone(),
+            committer_rx,
+        ));
+        let merkle = MerkleTree::from_entries(entries.iter().map(|(idx, tup)| (*idx, *tup)));
+        let mut meta = vec![HashMap::new(); NUMBER_OF_WORKERS];
+        for (sector_idx, tup) in entries {
+            meta[(sector_idx as usize) % NUMBER_OF_WORKERS].insert(sector_idx, tup);
         }
-        FileSystemSectorsManager {
+        Ok(FileSystemSectorsManager {
             path,
-            idx_to_meta: meta.into_iter().map(|x| RwLock::new(x)).collect(),
+            stripes,
+            idx_to_meta: meta.into_iter().map(RwLock::new).collect(),
+            index,
+            committer,
+            #[cfg(test)]
+            commit_batches,
+            checkpoint: RwLock::new(()),
+            merkle: std::sync::Mutex::new(merkle),
+            cache: SectorCache::new(config.read_cache_sectors),
+            gc: gc::GcCounters::default(),
+            cipher,
+            compression: config.compression,
+            history,
+            snapshots,
+        })
+    }
+
+    async fn start(
+        path: PathBuf,
+        config: &StorageConfiguration,
+        key: Option<&[u8; 32]>,
+    ) -> io::Result<Arc<Self>> {
+        let manager = Arc::new(Self::new(path, config, key).await?);
+        if !config.gc_interval.is_zero() {
+            tokio::spawn(run_gc(Arc::downgrade(&manager), config.gc_interval));
+        }
+        Ok(manager)
+    }
+
+    /// Blocks writes, so none is in progress while files are removed.
+    async fn collect_garbage(&self) {
+        let _checkpoint = self.checkpoint.write().await;
+        let mut maps = vec![];
+        for map in &self.idx_to_meta {
+            maps.push(map.read().await);
+        }
+        let current = |idx: SectorIdx| {
+            maps[(idx as usize) % NUMBER_OF_WORKERS]
+                .get(&idx)
+                .map(|tup| stored_meta(self.cipher.as_ref(), idx, *tup))
+        };
+        match gc::collect(&self.stripes.all_dirs(&self.path), &current).await {
+            Ok((removed_files, reclaimed_bytes)) => {
+                if removed_files > 0 {
+                    info!(
+                        "Removed {} files left by failed writes, {} bytes",
+                        removed_files, reclaimed_bytes
+                    );
+                }
+                self.gc.record(removed_files, reclaimed_bytes);
+            }
+            Err(err) => error!("Couldn't remove files left by failed writes: {}", err),
+        }
+        remove_history(&self.stripes, self.cipher.as_ref(), self.history.expire()).await;
+    }
+
+    /// Reads the sector file at `path`, holding version `meta` of the sector.
+    async fn read_file(
+        &self,
+        path: &Path,
+        idx: SectorIdx,
+        meta: (u64, u8),
+    ) -> Result<SectorVec, StorageError> {
+        let mut file = File::open(path).await?;
+        let mut content = vec![];
+        file.read_to_end(&mut content).await?;
+        match &self.cipher {
+            Some(cipher) => cipher.open(idx, meta, &content),
+            None => verify_sector_file(idx, meta.0, meta.1, content),
+        }
+    }
+
+    /// Records the version superseded by a write, and removes versions which
+    /// are no longer kept.
+    async fn supersede(&self, idx: SectorIdx, old: (u64, u8)) {
+        let (stored_timestamp, stored_rank) = stored_meta(self.cipher.as_ref(), idx, old);
+        let filename = encode_filename((idx, stored_timestamp, stored_rank));
+        let path = history::dir(self.stripes.dir(idx)).join(filename);
+        let written = match fs::metadata(&path).await.and_then(|meta| meta.modified()) {
+            Ok(written) => written,
+            Err(_) => return,
+        };
+        let expired = self.history.push(idx, old, written);
+        remove_history(
+            &self.stripes,
+            self.cipher.as_ref(),
+            expired.into_iter().map(|meta| (idx, meta)).collect(),
+        )
+        .await;
+    }
+
+    /// A failed checkpoint is retried after the next write.
+    async fn checkpoint(&self) {
+        let _checkpoint = self.checkpoint.write().await;
+        let mut index = self.index.lock().await;
+        if !index.needs_checkpoint() {
+            // Someone else was faster.
+            return;
+        }
+        let mut entries = vec![];
+        for map in &self.idx_to_meta {
+            entries.extend(
+                (map.read().await.iter())
+                    .map(|(idx, tup)| (*idx, stored_meta(self.cipher.as_ref(), *idx, *tup))),
+            );
+        }
+        if let Err(err) = index.checkpoint(entries.into_iter()).await {
+            error!("Couldn't checkpoint sectors index: {}", err);
         }
     }
 }
 
 #[async_trait::async_trait]
 impl SectorsManager for FileSystemSectorsManager {
-    async fn read_data(&self, idx: SectorIdx) -> SectorVec {
+    async fn read_data(&self, idx: SectorIdx) -> Result<SectorVec, StorageError> {
         let map = self.idx_to_meta[(idx as usize) % NUMBER_OF_WORKERS]
             .read()
             .await;
         if let Some((logical_timestamp, write_rank)) = map.get(&idx) {
-            let filename = encode_filename((idx, *logical_timestamp, *write_rank));
-            let mut filepath = self.path.clone();
-            filepath.push(filename);
-            let mut file = File::open(&filepath).await.unwrap();
-            let mut content = vec![];
-            file.read_to_end(&mut content).await.unwrap();
-            if content.len() != 4096 {
-                panic!("SectorsManager invariant doesn't hold");
+            if let Some(data) = self.cache.as_ref().and_then(|cache| cache.get(idx)) {
+                return Ok(data);
+            }
+            let meta = (*logical_timestamp, *write_rank);
+            let (stored_timestamp, stored_rank) = stored_meta(self.cipher.as_ref(), idx, meta);
+            let filename = encode_filename((idx, stored_timestamp, stored_rank));
+            let data = (self.read_file(&self.stripes.dir(idx).join(filename), idx, meta)).await?;
+            if let Some(cache) = &self.cache {
+                cache.insert(idx, data.clone());
             }
-            SectorVec(content)
+            Ok(data)
         } else {
-            SectorVec(vec![0; SECTOR_LEN])
+            Ok(SectorVec(vec![0; SECTOR_LEN]))
         }
     }
 
-    async fn read_metadata(&self, idx: SectorIdx) -> (u64, u8) {
+    async fn read_metadata(&self, idx: SectorIdx) -> Result<(u64, u8), StorageError> {
         let map = self.idx_to_meta[(idx as usize) % NUMBER_OF_WORKERS]
             .read()
             .await;
-        if let Some(tup) = map.get(&idx) {
-            tup.clone()
-        } else {
-            (0, 0)
+        Ok(map.get(&idx).copied().unwrap_or((0, 0)))
+    }
+
+    async fn range_digest(&self, range: Range<SectorIdx>) -> Result<u64, StorageError> {
+        Ok(self.merkle.lock().unwrap().range_digest(range))
+    }
+
+    fn cache_stats(&self) -> Option<SectorCacheStats> {
+        self.cache.as_ref().map(SectorCache::stats)
+    }
+
+    fn gc_stats(&self) -> Option<SectorsGcStats> {
+        Some(self.gc.stats())
+    }
+
+    async fn list_versions(&self, idx: SectorIdx) -> Result<Vec<(u64, u8)>, StorageError> {
+        let map = self.idx_to_meta[(idx as usize) % NUMBER_OF_WORKERS]
+            .read()
+            .await;
+        let mut versions: Vec<_> = map.get(&idx).copied().into_iter().collect();
+        versions.extend(self.history.versions(idx));
+        Ok(versions)
+    }
+
+    async fn read_version(
+        &self,
+        idx: SectorIdx,
+        meta: (u64, u8),
+    ) -> Result<Option<SectorVec>, StorageError> {
+        let map = self.idx_to_meta[(idx as usize) % NUMBER_OF_WORKERS]
+            .read()
+            .await;
+        if map.get(&idx) == Some(&meta) {
+            drop(map);
+            return self.read_data(idx).await.map(Some);
         }
+        if !self.history.contains(idx, meta) {
+            return Ok(None);
+        }
+        let (stored_timestamp, stored_rank) = stored_meta(self.cipher.as_ref(), idx, meta);
+        let filename = encode_filename((idx, stored_timestamp, stored_rank));
+        let path = history::dir(self.stripes.dir(idx)).join(filename);
+        match self.read_file(&path, idx, meta).await {
+            // Removed by garbage collection in the meantime.
+            Err(StorageError::Io(err))
+                if err.kind() == io::ErrorKind::NotFound && !self.history.contains(idx, meta) =>
+            {
+                Ok(None)
+            }
+            result => result.map(Some),
+        }
+    }
+
+    /// Blocks writes while sector files are linked.
+    async fn create_snapshot(&self, name: &str) -> Result<(), StorageError> {
+        let _checkpoint = self.checkpoint.write().await;
+        let mut current = index::Entries::new();
+        for map in &self.idx_to_meta {
+            current.extend(map.read().await.iter());
+        }
+        (self.snapshots)
+            .create(
+                &self.path,
+                &self.stripes,
+                self.cipher.as_ref(),
+                name,
+                current,
+            )
+            .await
+    }
+
+    async fn list_snapshots(&self) -> Result<Vec<String>, StorageError> {
+        Ok(self.snapshots.names())
+    }
+
+    async fn read_snapshot(
+        &self,
+        name: &str,
+        idx: SectorIdx,
+    ) -> Result<(SectorVec, u64, u8), StorageError> {
+        let sectors =
+            (self.snapshots.get(name)).ok_or(StorageError::InvalidInput("no such snapshot"))?;
+        let meta = match sectors.get(&idx) {
+            Some(meta) => *meta,
+            None => return Ok((SectorVec(vec![0; SECTOR_LEN]), 0, 0)),
+        };
+        let (stored_timestamp, stored_rank) = stored_meta(self.cipher.as_ref(), idx, meta);
+        let filename = encode_filename((idx, stored_timestamp, stored_rank));
+        let path = snapshots::dir(self.stripes.dir(idx))
+            .join(name)
+            .join(filename);
+        let data = self.read_file(&path, idx, meta).await?;
+        Ok((data, meta.0, meta.1))
+    }
+
+    async fn delete_snapshot(&self, name: &str) -> Result<(), StorageError> {
+        self.snapshots.delete(&self.path, &self.stripes, name).await
     }
 
-    async fn write(&self, idx: SectorIdx, sector: &(SectorVec, u64, u8)) {
+    async fn write(
+        &self,
+        idx: SectorIdx,
+        sector: &(SectorVec, u64, u8),
+    ) -> Result<(), StorageError> {
         let (SectorVec(content), logical_timestamp, write_rank) = sector;
 
+        let checkpoint = self.checkpoint.read().await;
         let mut map = self.idx_to_meta[(idx as usize) % NUMBER_OF_WORKERS]
             .write()
             .await;
-        let mut old_filename = None;
-        if let Some((logical_timestamp, write_rank)) = map.get(&idx) {
-            old_filename = Some(encode_filename((idx, *logical_timestamp, *write_rank)));
+        let meta = (*logical_timestamp, *write_rank);
+        let file_content = match &self.cipher {
+            Some(cipher) => cipher.seal(idx, meta, content),
+            None => encode_sector_file(
+                idx,
+                *logical_timestamp,
+                *write_rank,
+                content,
+                self.compression,
+            ),
+        };
+        let (stored_timestamp, stored_rank) = stored_meta(self.cipher.as_ref(), idx, meta);
+        let (done_tx, done_rx) = oneshot::channel();
+        let old = map.get(&idx).copied();
+        let write = group_commit::SectorWrite {
+            idx,
+            logical_timestamp: stored_timestamp,
+            write_rank: stored_rank,
+            content: file_content,
+            old: old.map(|tup| stored_meta(self.cipher.as_ref(), idx, tup)),
+            done: done_tx,
+        };
+        let committer_ended = || io::Error::other("sector writes committer has ended");
+        if self.committer.send(write).is_err() {
+            return Err(committer_ended().into());
+        }
+        done_rx.await.map_err(|_| committer_ended())??;
+        if let Some(old) = old.filter(|old| *old != meta && self.history.enabled()) {
+            self.supersede(idx, old).await;
+        }
+
+        map.insert(idx, (*logical_timestamp, *write_rank));
+        if let Some(cache) = &self.cache {
+            cache.insert(idx, SectorVec(content.clone()));
+        }
+        self.merkle
+            .lock()
+            .unwrap()
+            .update(idx, Some((*logical_timestamp, *write_rank)));
+        drop(map);
+        drop(checkpoint);
+
+        if self.index.lock().await.needs_checkpoint() {
+            self.checkpoint().await;
         }
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn test_restart_reconciles_interrupted_writes() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().to_path_buf();
+    let manager = FileSystemSectorsManager::new(path.clone(), &Default::default(), None)
+        .await
+        .unwrap();
+    manager
+        .write(1, &(SectorVec(vec![1; SECTOR_LEN]), 1, 1))
+        .await
+        .unwrap();
+    manager
+        .write(2, &(SectorVec(vec![2; SECTOR_LEN]), 1, 1))
+        .await
+        .unwrap();
 
-        let new_filename = encode_filename((idx, *logical_timestamp, *write_rank));
-        let mut new_filepath = self.path.clone();
-        new_filepath.push(new_filename.clone());
+    // A crash after the rename of sector 1, and before the rename of sector 2.
+    let mut index = manager.index.lock().await;
+    index.append(1, 2, 3).await.unwrap();
+    index.append(2, 2, 3).await.unwrap();
+    drop(index);
+    drop(manager);
+    let renamed = path.join(encode_filename((1, 2, 3)));
+    let content = encode_sector_file(1, 2, 3, &[3; SECTOR_LEN], SectorsCompression::None);
+    fs::write(&renamed, content).await.unwrap();
+    let tmp = path.join(format!("tmpfile{}", encode_filename((2, 2, 3))));
+    fs::write(&tmp, vec![3; SECTOR_LEN]).await.unwrap();
 
-        let mut tmppath = self.path.clone();
-        tmppath.push(format!("tmpfile{}", new_filename));
+    let manager = FileSystemSectorsManager::new(path.clone(), &Default::default(), None)
+        .await
+        .unwrap();
+    assert_eq!(manager.read_metadata(1).await.unwrap(), (2, 3));
+    assert_eq!(
+        manager.read_data(1).await.unwrap(),
+        SectorVec(vec![3; SECTOR_LEN])
+    );
+    assert_eq!(manager.read_metadata(2).await.unwrap(), (1, 1));
+    assert!(fs::metadata(&tmp).await.is_err());
+    assert!(fs::metadata(path.join(encode_filename((1, 1, 1))))
+        .await
+        .is_err());
+    drop(manager);
 
-        let tmpfile_result = File::create(&tmppath).await;
+    // A corrupted index falls back to listing the directory.
+    fs::write(path.join("index.snapshot"), b"garbage")
+        .await
+        .unwrap();
+    let manager = FileSystemSectorsManager::new(path, &Default::default(), None)
+        .await
+        .unwrap();
+    assert_eq!(manager.read_metadata(1).await.unwrap(), (2, 3));
+    assert_eq!(manager.read_metadata(2).await.unwrap(), (1, 1));
+}
 
-        let mut tmpfile = tmpfile_result.unwrap();
+#[tokio::test]
+async fn test_corrupted_sector_is_reported() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().to_path_buf();
+    let manager = FileSystemSectorsManager::new(path.clone(), &Default::default(), None)
+        .await
+        .unwrap();
+    manager
+        .write(1, &(SectorVec(vec![1; SECTOR_LEN]), 1, 1))
+        .await
+        .unwrap();
+    manager
+        .write(2, &(SectorVec(vec![2; SECTOR_LEN]), 1, 1))
+        .await
+        .unwrap();
 
-        tmpfile.write_all(content).await.unwrap();
+    let filepath = path.join(encode_filename((1, 1, 1)));
+    let mut content = fs::read(&filepath).await.unwrap();
+    content[100] ^= 0x10;
+    fs::write(&filepath, content).await.unwrap();
+    // A sector without a checksum, which the upgrade of the storage format
+    // would have added.
+    fs::write(path.join(encode_filename((2, 1, 1))), vec![2; SECTOR_LEN])
+        .await
+        .unwrap();
 
-        tmpfile.sync_data().await.unwrap();
+    assert!(matches!(
+        manager.read_data(1).await,
+        Err(StorageError::Corrupted(1))
+    ));
+    assert!(matches!(
+        manager.read_data(2).await,
+        Err(StorageError::Corrupted(2))
+    ));
+    assert_eq!(
+        manager.read_data(3).await.unwrap(),
+        SectorVec(vec![0; SECTOR_LEN])
+    );
+}
 
-        drop(tmpfile);
+#[tokio::test]
+async fn test_range_digest_is_rebuilt_on_startup() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().to_path_buf();
+    let manager = FileSystemSectorsManager::new(path.clone(), &Default::default(), None)
+        .await
+        .unwrap();
+    assert_eq!(manager.range_digest(0..64).await.unwrap(), 0);
+    manager
+        .write(3, &(SectorVec(vec![1; SECTOR_LEN]), 1, 1))
+        .await
+        .unwrap();
+    manager
+        .write(40, &(SectorVec(vec![2; SECTOR_LEN]), 2, 3))
+        .await
+        .unwrap();
+    let digest = manager.range_digest(0..64).await.unwrap();
+    assert_ne!(digest, 0);
+    assert_eq!(manager.range_digest(4..40).await.unwrap(), 0);
+    drop(manager);
 
-        File::open(&self.path)
+    let manager = FileSystemSectorsManager::new(path, &Default::default(), None)
+        .await
+        .unwrap();
+    assert_eq!(manager.range_digest(0..64).await.unwrap(), digest);
+    manager
+        .write(40, &(SectorVec(vec![3; SECTOR_LEN]), 3, 1))
+        .await
+        .unwrap();
+    assert_ne!(manager.range_digest(0..64).await.unwrap(), digest);
+}
+
+#[tokio::test]
+async fn test_concurrent_writes_are_committed_together() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().to_path_buf();
+    let config = StorageConfiguration {
+        group_commit_window: std::time::Duration::from_millis(5),
+        ..Default::default()
+    };
+    let manager = Arc::new(
+        FileSystemSectorsManager::new(path.clone(), &config, None)
             .await
-            .unwrap()
-            .sync_data()
+            .unwrap(),
+    );
+    let mut writes = vec![];
+    for idx in 0..NUMBER_OF_WORKERS as u64 {
+        let manager = manager.clone();
+        writes.push(tokio::spawn(async move {
+            let data = SectorVec(vec![idx as u8; SECTOR_LEN]);
+            manager.write(idx, &(data, 1, 1)).await.unwrap();
+            manager
+                .write(idx, &(SectorVec(vec![0xff; SECTOR_LEN]), 2, 1))
+                .await
+                .unwrap();
+        }));
+    }
+    for write in writes {
+        write.await.unwrap();
+    }
+    let batches = manager
+        .commit_batches
+        .load(std::sync::atomic::Ordering::Relaxed);
+    assert!(
+        batches < 2 * NUMBER_OF_WORKERS as u64,
+        "{} batches",
+        batches
+    );
+    drop(manager);
+
+    let manager = FileSystemSectorsManager::new(path.clone(), &Default::default(), None)
+        .await
+        .unwrap();
+    for idx in 0..NUMBER_OF_WORKERS as u64 {
+        assert_eq!(manager.read_metadata(idx).await.unwrap(), (2, 1));
+        assert_eq!(
+            manager.read_data(idx).await.unwrap(),
+            SectorVec(vec![0xff; SECTOR_LEN])
+        );
+    }
+    // Only the latest version of every sector and the index are left.
+    let mut entries = fs::read_dir(&path).await.unwrap();
+    let mut files = 0;
+    while entries.next_entry().await.unwrap().is_some() {
+        files += 1;
+    }
+    assert_eq!(files, NUMBER_OF_WORKERS + 2);
+}
+
+#[tokio::test]
+async fn test_failed_write_doesnt_fail_its_batch() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().to_path_buf();
+    let config = StorageConfiguration {
+        group_commit_window: std::time::Duration::from_millis(50),
+        ..Default::default()
+    };
+    let manager = Arc::new(
+        FileSystemSectorsManager::new(path.clone(), &config, None)
+            .await
+            .unwrap(),
+    );
+    // The tmpfile of the write of sector 1 can't be created.
+    let tmppath = path.join(format!("tmpfile{}", encode_filename((1, 1, 1))));
+    fs::create_dir(&tmppath).await.unwrap();
+
+    let writes: Vec<_> = [1, 2]
+        .into_iter()
+        .map(|idx| {
+            let manager = manager.clone();
+            tokio::spawn(async move {
+                let data = SectorVec(vec![idx as u8; SECTOR_LEN]);
+                manager.write(idx, &(data, 1, 1)).await
+            })
+        })
+        .collect();
+    let mut results = vec![];
+    for write in writes {
+        results.push(write.await.unwrap());
+    }
+    assert_eq!(
+        manager
+            .commit_batches
+            .load(std::sync::atomic::Ordering::Relaxed),
+        1
+    );
+    assert!(matches!(results[0], Err(StorageError::Io(_))));
+    assert!(results[1].is_ok());
+    assert_eq!(manager.read_metadata(1).await.unwrap(), (0, 0));
+    assert_eq!(manager.read_metadata(2).await.unwrap(), (1, 1));
+    assert_eq!(
+        manager.read_data(2).await.unwrap(),
+        SectorVec(vec![2; SECTOR_LEN])
+    );
+}
+
+#[tokio::test]
+async fn test_read_cache_is_coherent_with_writes() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = StorageConfiguration {
+        read_cache_sectors: 1,
+        ..Default::default()
+    };
+    let manager = FileSystemSectorsManager::new(dir.path().to_path_buf(), &config, None)
+        .await
+        .unwrap();
+    manager
+        .write(1, &(SectorVec(vec![1; SECTOR_LEN]), 1, 1))
+        .await
+        .unwrap();
+    assert_eq!(
+        manager.read_data(1).await.unwrap(),
+        SectorVec(vec![1; SECTOR_LEN])
+    );
+    manager
+        .write(2, &(SectorVec(vec![2; SECTOR_LEN]), 1, 1))
+        .await
+        .unwrap();
+    assert_eq!(
+        manager.read_data(1).await.unwrap(),
+        SectorVec(vec![1; SECTOR_LEN])
+    );
+    manager
+        .write(1, &(SectorVec(vec![3; SECTOR_LEN]), 2, 1))
+        .await
+        .unwrap();
+    assert_eq!(
+        manager.read_data(1).await.unwrap(),
+        SectorVec(vec![3; SECTOR_LEN])
+    );
+    // Sectors which were never written don't count.
+    assert_eq!(
+        manager.read_data(5).await.unwrap(),
+        SectorVec(vec![0; SECTOR_LEN])
+    );
+    assert_eq!(
+        manager.cache_stats(),
+        Some(SectorCacheStats {
+            hits: 2,
+            misses: 1,
+            cached: 1,
+            capacity: 1,
+        })
+    );
+}
+
+#[tokio::test]
+async fn test_files_left_by_failed_writes_are_collected() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().to_path_buf();
+    let manager = build_sectors_manager(path.clone()).await.unwrap();
+    manager
+        .write(1, &(SectorVec(vec![1; SECTOR_LEN]), 2, 1))
+        .await
+        .unwrap();
+    drop(manager);
+
+    // A superseded version whose removal failed, and a tmpfile of a write
+    // which failed before its rename.
+    fs::write(path.join(encode_filename((1, 1, 1))), vec![0; 100])
+        .await
+        .unwrap();
+    fs::write(
+        path.join(format!("tmpfile{}", encode_filename((2, 1, 1)))),
+        [0; 20],
+    )
+    .await
+    .unwrap();
+    // Startup cleans up only after writes logged in the index.
+    let manager = build_sectors_manager(path.clone()).await.unwrap();
+    assert_eq!(manager.gc_stats().unwrap().runs, 0);
+    assert!(fs::metadata(path.join(encode_filename((1, 1, 1))))
+        .await
+        .is_ok());
+    drop(manager);
+
+    let config = StorageConfiguration {
+        gc_interval: Duration::from_millis(10),
+        ..Default::default()
+    };
+    let manager = build_sectors_manager_with_config(path.clone(), 8, &config)
+        .await
+        .unwrap();
+    time::sleep(Duration::from_millis(100)).await;
+    let stats = manager.gc_stats().unwrap();
+    assert_eq!((stats.removed_files, stats.reclaimed_bytes), (2, 120));
+    assert_eq!(
+        manager.read_data(1).await.unwrap(),
+        SectorVec(vec![1; SECTOR_LEN])
+    );
+
+    fs::write(
+        path.join(format!("tmpfile{}", encode_filename((3, 1, 1)))),
+        [0; 30],
+    )
+    .await
+    .unwrap();
+    time::sleep(Duration::from_millis(100)).await;
+    let stats = manager.gc_stats().unwrap();
+    assert!(stats.runs > 1);
+    assert_eq!((stats.removed_files, stats.reclaimed_bytes), (3, 150));
+    assert_eq!(
+        manager.read_data(1).await.unwrap(),
+        SectorVec(vec![1; SECTOR_LEN])
+    );
+}
+
+#[tokio::test]
+async fn test_sectors_are_striped_across_data_dirs() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("index");
+    let data_dirs = vec![dir.path().join("disk0"), dir.path().join("disk1")];
+    fs::create_dir_all(&path).await.unwrap();
+    let config = StorageConfiguration {
+        data_dirs: data_dirs.clone(),
+        ..Default::default()
+    };
+    let manager = build_sectors_manager_with_config(path.clone(), 8, &config)
+        .await
+        .unwrap();
+    for idx in 0..4 {
+        manager
+            .write(idx, &(SectorVec(vec![idx as u8; SECTOR_LEN]), 1, 1))
             .await
             .unwrap();
+    }
+    drop(manager);
+    for (idx, data_dir) in [(0, &data_dirs[0]), (1, &data_dirs[1])] {
+        assert!(fs::metadata(data_dir.join(encode_filename((idx, 1, 1))))
+            .await
+            .is_ok());
+    }
 
-        fs::rename(&tmppath, &new_filepath).await.unwrap();
+    let manager = build_sectors_manager_with_config(path.clone(), 8, &config)
+        .await
+        .unwrap();
+    for idx in 0..4 {
+        assert_eq!(
+            manager.read_data(idx).await.unwrap(),
+            SectorVec(vec![idx as u8; SECTOR_LEN])
+        );
+    }
+    drop(manager);
 
-        let dest_file = File::open(&new_filepath).await.unwrap();
-        dest_file.sync_data().await.unwrap();
-        drop(dest_file);
+    // Sectors would be looked for in wrong directories.
+    let reordered = stripes::Stripes::new(&path, &[data_dirs[1].clone(), data_dirs[0].clone()]);
+    assert!(reordered.check(&path).await.is_err());
+    let unstriped = stripes::Stripes::new(&path, &[]);
+    assert!(unstriped.check(&path).await.is_err());
+}
 
-        if let Some(old_filename) = old_filename {
-            let mut old_filepath = self.path.clone();
-            old_filepath.push(old_filename);
-            if old_filepath != new_filepath {
-                let _ = fs::remove_file(old_filepath).await;
-            }
+#[tokio::test]
+async fn test_encrypted_sectors_reveal_only_their_indices() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().to_path_buf();
+    let key = [1; 32];
+    let config = StorageConfiguration::default();
+    let manager = build_sectors_manager_with_key(path.clone(), 8, &config, Some(&key))
+        .await
+        .unwrap();
+    manager
+        .write(3, &(SectorVec(vec![0xab; SECTOR_LEN]), 5, 2))
+        .await
+        .unwrap();
+    manager
+        .write(3, &(SectorVec(vec![0xcd; SECTOR_LEN]), 6, 2))
+        .await
+        .unwrap();
+    drop(manager);
+
+    let mut sector_files = vec![];
+    let mut entries = fs::read_dir(&path).await.unwrap();
+    while let Some(entry) = entries.next_entry().await.unwrap() {
+        if let Ok(meta) = decode_filename(entry.file_name().into_string().unwrap()) {
+            sector_files.push((meta, fs::read(entry.path()).await.unwrap()));
         }
+    }
+    assert_eq!(sector_files.len(), 1);
+    let ((idx, logical_timestamp, write_rank), content) = &sector_files[0];
+    assert_eq!(*idx, 3);
+    assert_ne!((*logical_timestamp, *write_rank), (6, 2));
+    assert!(!content.windows(16).any(|window| window == [0xcd; 16]));
 
-        File::open(&self.path)
-            .await
-            .unwrap()
-            .sync_data()
+    // Also when the index is lost, and names have to be decoded.
+    index::remove(&path).await.unwrap();
+    let manager = build_sectors_manager_with_key(path.clone(), 8, &config, Some(&key))
+        .await
+        .unwrap();
+    assert_eq!(manager.read_metadata(3).await.unwrap(), (6, 2));
+    assert_eq!(
+        manager.read_data(3).await.unwrap(),
+        SectorVec(vec![0xcd; SECTOR_LEN])
+    );
+    drop(manager);
+
+    // A wrong or missing key is reported instead of panicking.
+    let wrong = build_sectors_manager_with_key(path.clone(), 8, &config, Some(&[2; 32])).await;
+    assert!(matches!(wrong, Err(StorageError::Io(_))));
+    assert!(build_sectors_manager(path.clone()).await.is_err());
+
+    let compressed = StorageConfiguration {
+        compression: SectorsCompression::Lz4,
+        ..Default::default()
+    };
+    let refused = build_sectors_manager_with_key(path.clone(), 8, &compressed, Some(&key)).await;
+    assert!(matches!(refused, Err(StorageError::InvalidInput(_))));
+}
+
+#[tokio::test]
+async fn test_compressed_sectors_are_read_in_full() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().to_path_buf();
+    let config = StorageConfiguration {
+        compression: SectorsCompression::Lz4,
+        ..Default::default()
+    };
+    let manager = build_sectors_manager_with_config(path.clone(), 8, &config)
+        .await
+        .unwrap();
+    let mut sparse = vec![0; SECTOR_LEN];
+    sparse[100..110].copy_from_slice(b"log line 1");
+    let random: Vec<u8> = (0..SECTOR_LEN).map(|_| rand::random()).collect();
+    manager
+        .write(1, &(SectorVec(sparse.clone()), 1, 1))
+        .await
+        .unwrap();
+    manager
+        .write(2, &(SectorVec(random.clone()), 1, 1))
+        .await
+        .unwrap();
+    drop(manager);
+
+    let len = |idx| {
+        let path = path.join(encode_filename((idx, 1, 1)));
+        async move { fs::metadata(path).await.unwrap().len() as usize }
+    };
+    assert!(len(1).await < SECTOR_LEN / 10);
+    assert_eq!(len(2).await, SECTOR_LEN + CHECKSUM_LEN);
+
+    let manager = build_sectors_manager(path.clone()).await.unwrap();
+    assert_eq!(manager.read_data(1).await.unwrap(), SectorVec(sparse));
+    assert_eq!(manager.read_data(2).await.unwrap(), SectorVec(random));
+}
+
+#[tokio::test]
+async fn test_superseded_versions_are_kept_and_trimmed() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().to_path_buf();
+    let mut config = StorageConfiguration {
+        history_versions: 2,
+        ..Default::default()
+    };
+    let manager = build_sectors_manager_with_config(path.clone(), 8, &config)
+        .await
+        .unwrap();
+    for logical_timestamp in 1..=4 {
+        manager
+            .write(
+                2,
+                &(
+                    SectorVec(vec![logical_timestamp as u8; SECTOR_LEN]),
+                    logical_timestamp,
+                    1,
+                ),
+            )
             .await
             .unwrap();
+    }
+    assert_eq!(
+        manager.list_versions(2).await.unwrap(),
+        vec![(4, 1), (3, 1), (2, 1)]
+    );
+    assert_eq!(
+        manager.read_version(2, (2, 1)).await.unwrap(),
+        Some(SectorVec(vec![2; SECTOR_LEN]))
+    );
+    assert_eq!(manager.read_version(2, (1, 1)).await.unwrap(), None);
+    assert_eq!(
+        manager.read_data(2).await.unwrap(),
+        SectorVec(vec![4; SECTOR_LEN])
+    );
+    drop(manager);
 
-        map.insert(idx, (*logical_timestamp, *write_rank));
+    let manager = build_sectors_manager_with_config(path.clone(), 8, &config)
+        .await
+        .unwrap();
+    assert_eq!(
+        manager.list_versions(2).await.unwrap(),
+        vec![(4, 1), (3, 1), (2, 1)]
+    );
+    drop(manager);
+
+    // Fewer versions kept after a restart.
+    config.history_versions = 1;
+    let manager = build_sectors_manager_with_config(path.clone(), 8, &config)
+        .await
+        .unwrap();
+    assert_eq!(
+        manager.list_versions(2).await.unwrap(),
+        vec![(4, 1), (3, 1)]
+    );
+    assert_eq!(manager.read_version(2, (2, 1)).await.unwrap(), None);
+    assert_eq!(
+        manager.read_version(2, (3, 1)).await.unwrap(),
+        Some(SectorVec(vec![3; SECTOR_LEN]))
+    );
+    assert_eq!(manager.list_versions(5).await.unwrap(), vec![]);
+}
+
+#[tokio::test]
+async fn test_snapshots_keep_versions_of_their_time() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().to_path_buf();
+    let manager = build_sectors_manager(path.clone()).await.unwrap();
+    for idx in [1, 2] {
+        manager
+            .write(idx, &(SectorVec(vec![idx as u8; SECTOR_LEN]), 1, 1))
+            .await
+            .unwrap();
     }
+    manager.create_snapshot("nightly").await.unwrap();
+    assert!(manager.create_snapshot("nightly").await.is_err());
+    assert!(manager.create_snapshot("../escape").await.is_err());
+    manager
+        .write(1, &(SectorVec(vec![10; SECTOR_LEN]), 2, 1))
+        .await
+        .unwrap();
+    manager
+        .write(3, &(SectorVec(vec![3; SECTOR_LEN]), 1, 1))
+        .await
+        .unwrap();
+
+    assert_eq!(
+        manager.read_snapshot("nightly", 1).await.unwrap(),
+        (SectorVec(vec![1; SECTOR_LEN]), 1, 1)
+    );
+    assert_eq!(
+        manager.read_snapshot("nightly", 3).await.unwrap(),
+        (SectorVec(vec![0; SECTOR_LEN]), 0, 0)
+    );
+    assert_eq!(
+        manager.read_data(1).await.unwrap(),
+        SectorVec(vec![10; SECTOR_LEN])
+    );
+    drop(manager);
+
+    // A snapshot interrupted before its marker was written.
+    fs::create_dir(snapshots::dir(&path).join("partial"))
+        .await
+        .unwrap();
+    let manager = build_sectors_manager(path.clone()).await.unwrap();
+    assert_eq!(manager.list_snapshots().await.unwrap(), vec!["nightly"]);
+    assert!(fs::metadata(snapshots::dir(&path).join("partial"))
+        .await
+        .is_err());
+    assert_eq!(
+        manager.read_snapshot("nightly", 2).await.unwrap(),
+        (SectorVec(vec![2; SECTOR_LEN]), 1, 1)
+    );
+
+    manager.delete_snapshot("nightly").await.unwrap();
+    assert!(manager.list_snapshots().await.unwrap().is_empty());
+    assert!(manager.read_snapshot("nightly", 1).await.is_err());
+    assert_eq!(
+        manager.read_data(2).await.unwrap(),
+        SectorVec(vec![2; SECTOR_LEN])
+    );
 }
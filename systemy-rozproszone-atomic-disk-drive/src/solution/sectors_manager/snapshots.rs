@@ -0,0 +1,210 @@
+//! Named point-in-time snapshots of all sectors, not to be confused with the
+//! snapshot of the index. Sector files are never modified once written, so a
+//! snapshot is a directory of hard links to the current versions, one in the
+//! `snapshots` directory of every stripe. A snapshot is complete once its
+//! marker is written to the `snapshots` directory of the index directory,
+//! incomplete ones are removed on startup.
+use super::cipher::{real_meta, stored_meta, SectorsCipher};
+use super::stripes::Stripes;
+use super::{decode_filename, encode_filename, index};
+use crate::StorageError;
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::fs::{self, File};
+
+const SNAPSHOTS_DIR: &str = "snapshots";
+const MARKER_SUFFIX: &str = ".done";
+const MAX_NAME_LEN: usize = 64;
+
+pub(super) fn is_snapshots_dir(filename: &str) -> bool {
+    filename == SNAPSHOTS_DIR
+}
+
+/// Directory of snapshots of the stripe, or of their markers, in `path`.
+pub(super) fn dir(path: &Path) -> PathBuf {
+    path.join(SNAPSHOTS_DIR)
+}
+
+/// Names are used as directory names, so they are restricted to a safe set
+/// of characters, excluding the dot of markers.
+fn check_name(name: &str) -> Result<(), StorageError> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && !name.starts_with("tmpfile")
+        && (name.bytes()).all(|c| c.is_ascii_alphanumeric() || c == b'-' || c == b'_');
+    match valid {
+        true => Ok(()),
+        false => Err(StorageError::InvalidInput(
+            "snapshot names consist of up to 64 letters, digits, '-' and '_'",
+        )),
+    }
+}
+
+async fn sync_dir(path: &Path) -> io::Result<()> {
+    File::open(path).await?.sync_data().await
+}
+
+async fn remove_dir(path: &Path) -> io::Result<()> {
+    match fs::remove_dir_all(path).await {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
+pub(super) struct Snapshots {
+    /// Metadata of the sectors of every complete snapshot.
+    snapshots: Mutex<HashMap<String, Arc<index::Entries>>>,
+    /// Held while a snapshot is created or deleted.
+    changing: tokio::sync::Mutex<()>,
+}
+
+impl Snapshots {
+    /// Finds the complete snapshots, and removes the others.
+    pub(super) async fn load(
+        path: &Path,
+        stripes: &Stripes,
+        cipher: Option<&SectorsCipher>,
+    ) -> io::Result<Self> {
+        let mut snapshots = HashMap::new();
+        if let Ok(mut entries) = fs::read_dir(dir(path)).await {
+            while let Some(entry) = entries.next_entry().await? {
+                if let Some(name) = (entry.file_name().to_str())
+                    .and_then(|filename| filename.strip_suffix(MARKER_SUFFIX))
+                {
+                    snapshots.insert(name.to_string(), index::Entries::new());
+                }
+            }
+        }
+        for stripe in stripes.dirs() {
+            let mut entries = match fs::read_dir(dir(stripe)).await {
+                Ok(entries) => entries,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err),
+            };
+            while let Some(entry) = entries.next_entry().await? {
+                if !entry.file_type().await?.is_dir() {
+                    continue;
+                }
+                let sectors = (entry.file_name().to_str()).and_then(|name| snapshots.get_mut(name));
+                match sectors {
+                    Some(sectors) => load_dir(&entry.path(), cipher, sectors).await?,
+                    None => remove_dir(&entry.path()).await?,
+                }
+            }
+        }
+        let snapshots = (snapshots.into_iter())
+            .map(|(name, sectors)| (name, Arc::new(sectors)))
+            .collect();
+        Ok(Snapshots {
+            snapshots: Mutex::new(snapshots),
+            changing: tokio::sync::Mutex::new(()),
+        })
+    }
+
+    pub(super) fn names(&self) -> Vec<String> {
+        let mut names: Vec<_> = self.snapshots.lock().unwrap().keys().cloned().collect();
+        names.sort_unstable();
+        names
+    }
+
+    /// Metadata of the sectors of the snapshot, `None` if there is none.
+    pub(super) fn get(&self, name: &str) -> Option<Arc<index::Entries>> {
+        self.snapshots.lock().unwrap().get(name).cloned()
+    }
+
+    /// Links the current versions of sectors, given by `current`, into a new
+    /// snapshot. No write may be in progress.
+    pub(super) async fn create(
+        &self,
+        path: &Path,
+        stripes: &Stripes,
+        cipher: Option<&SectorsCipher>,
+        name: &str,
+        current: index::Entries,
+    ) -> Result<(), StorageError> {
+        check_name(name)?;
+        let _changing = self.changing.lock().await;
+        if self.get(name).is_some() {
+            return Err(StorageError::InvalidInput("snapshot already exists"));
+        }
+        let result = link_all(path, stripes, cipher, name, &current).await;
+        if let Err(err) = result {
+            for stripe in stripes.dirs() {
+                let _ = remove_dir(&dir(stripe).join(name)).await;
+            }
+            return Err(err.into());
+        }
+        (self.snapshots.lock().unwrap()).insert(name.to_string(), Arc::new(current));
+        Ok(())
+    }
+
+    pub(super) async fn delete(
+        &self,
+        path: &Path,
+        stripes: &Stripes,
+        name: &str,
+    ) -> Result<(), StorageError> {
+        let _changing = self.changing.lock().await;
+        if self.snapshots.lock().unwrap().remove(name).is_none() {
+            return Err(StorageError::InvalidInput("no such snapshot"));
+        }
+        fs::remove_file(dir(path).join(format!("{}{}", name, MARKER_SUFFIX))).await?;
+        sync_dir(&dir(path)).await?;
+        for stripe in stripes.dirs() {
+            remove_dir(&dir(stripe).join(name)).await?;
+        }
+        Ok(())
+    }
+}
+
+async fn load_dir(
+    path: &Path,
+    cipher: Option<&SectorsCipher>,
+    sectors: &mut index::Entries,
+) -> io::Result<()> {
+    let mut entries = fs::read_dir(path).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        if let Some(Ok((idx, logical_timestamp, write_rank))) =
+            (entry.file_name().into_string().ok()).map(decode_filename)
+        {
+            sectors.insert(idx, real_meta(cipher, idx, (logical_timestamp, write_rank)));
+        }
+    }
+    Ok(())
+}
+
+async fn link_all(
+    path: &Path,
+    stripes: &Stripes,
+    cipher: Option<&SectorsCipher>,
+    name: &str,
+    current: &index::Entries,
+) -> io::Result<()> {
+    for stripe in stripes.dirs() {
+        fs::create_dir_all(dir(stripe)).await?;
+        // Left by a failed attempt.
+        remove_dir(&dir(stripe).join(name)).await?;
+        fs::create_dir(dir(stripe).join(name)).await?;
+    }
+    for (idx, meta) in current {
+        let (stored_timestamp, stored_rank) = stored_meta(cipher, *idx, *meta);
+        let filename = encode_filename((*idx, stored_timestamp, stored_rank));
+        let stripe = stripes.dir(*idx);
+        fs::hard_link(
+            stripe.join(&filename),
+            dir(stripe).join(name).join(filename),
+        )
+        .await?;
+    }
+    for stripe in stripes.dirs() {
+        sync_dir(&dir(stripe).join(name)).await?;
+        sync_dir(&dir(stripe)).await?;
+    }
+
+    fs::create_dir_all(dir(path)).await?;
+    let marker = File::create(dir(path).join(format!("{}{}", name, MARKER_SUFFIX))).await?;
+    marker.sync_all().await?;
+    sync_dir(&dir(path)).await
+}
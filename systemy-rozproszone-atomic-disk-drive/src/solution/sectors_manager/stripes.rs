@@ -0,0 +1,140 @@
+//! Striping of sector files across data directories, typically on separate
+//! disks. Sector `idx` lives in stripe `idx % n`, while the index stays in
+//! the directory of the sectors manager. With stripes, every directory holds
+//! a marker of its role, so a changed list of directories is refused instead
+//! of making sectors disappear.
+use super::decode_filename;
+use crate::SectorIdx;
+use std::io;
+use std::path::{Path, PathBuf};
+use tokio::fs::{self, File};
+use tokio::io::AsyncWriteExt;
+
+const MARKER_FILENAME: &str = "stripe";
+
+pub(super) fn is_marker(filename: &str) -> bool {
+    filename == MARKER_FILENAME
+}
+
+#[derive(Clone)]
+pub(super) struct Stripes {
+    dirs: Vec<PathBuf>,
+}
+
+impl Stripes {
+    /// Sectors are striped across `dirs`, or kept in `path` if it is empty.
+    pub(super) fn new(path: &Path, dirs: &[PathBuf]) -> Self {
+        let dirs = match dirs.is_empty() {
+            true => vec![path.to_path_buf()],
+            false => dirs.to_vec(),
+        };
+        Stripes { dirs }
+    }
+
+    pub(super) fn dir(&self, idx: SectorIdx) -> &Path {
+        &self.dirs[(idx % self.dirs.len() as u64) as usize]
+    }
+
+    pub(super) fn dirs(&self) -> &[PathBuf] {
+        &self.dirs
+    }
+
+    /// Directories which may hold files of the sectors manager, the stripes
+    /// and the index directory `path`.
+    pub(super) fn all_dirs(&self, path: &Path) -> Vec<PathBuf> {
+        let mut dirs = self.dirs.clone();
+        if !dirs.iter().any(|dir| dir == path) {
+            dirs.push(path.to_path_buf());
+        }
+        dirs
+    }
+
+    /// Whether any stripe holds sector files.
+    pub(super) async fn hold_sectors(&self) -> io::Result<bool> {
+        for dir in &self.dirs {
+            if holds_sectors(dir).await? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn markers(&self, path: &Path) -> Vec<(PathBuf, String)> {
+        let n = self.dirs.len();
+        let mut markers: Vec<_> = (self.dirs.iter().enumerate())
+            .map(|(i, dir)| (dir.clone(), format!("{}/{}", i, n)))
+            .collect();
+        if !self.dirs.iter().any(|dir| dir == path) {
+            markers.push((path.to_path_buf(), format!("index/{}", n)));
+        }
+        markers
+    }
+
+    /// Checks that the directories have the same roles as before, and marks
+    /// new ones. Without stripes no markers are written, and directories
+    /// without one can't hold sectors of other stripes.
+    pub(super) async fn check(&self, path: &Path) -> io::Result<()> {
+        let not_striped = self.dirs.len() == 1 && self.dirs[0] == path;
+        for (dir, expected) in self.markers(path) {
+            fs::create_dir_all(&dir).await?;
+            match fs::read_to_string(dir.join(MARKER_FILENAME)).await {
+                Ok(marker) if marker.trim_end() == expected => continue,
+                Ok(marker) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "{} was used as stripe {}, not {}, data directories have changed",
+                            dir.display(),
+                            marker.trim_end(),
+                            expected
+                        ),
+                    ))
+                }
+                Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                Err(_) => {}
+            }
+            if not_striped {
+                continue;
+            }
+            if holds_sectors(&dir).await? {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{} holds sectors of unknown stripes", dir.display()),
+                ));
+            }
+            write_marker(&dir, &expected).await?;
+        }
+        Ok(())
+    }
+}
+
+async fn holds_sectors(dir: &Path) -> io::Result<bool> {
+    let mut entries = fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        if let Ok(filename) = entry.file_name().into_string() {
+            if decode_filename(filename).is_ok() {
+                return Ok(true);
+            }
+        }
+    }
+    Ok(false)
+}
+
+async fn write_marker(dir: &Path, marker: &str) -> io::Result<()> {
+    let tmp_path = dir.join(format!("tmpfile{}", MARKER_FILENAME));
+    let mut tmp = File::create(&tmp_path).await?;
+    tmp.write_all(format!("{}\n", marker).as_bytes()).await?;
+    tmp.sync_data().await?;
+    fs::rename(&tmp_path, dir.join(MARKER_FILENAME)).await?;
+    File::open(dir).await?.sync_data().await
+}
+
+/// Whether sectors of the manager with the index in `path` are striped
+/// across other directories.
+pub(super) async fn is_striped(path: &Path) -> io::Result<bool> {
+    match fs::read_to_string(path.join(MARKER_FILENAME)).await {
+        Ok(_) => Ok(true),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(err) => Err(err),
+    }
+}
@@ -1,13 +1,76 @@
-use crate::StableStorage;
+use crate::{StableStorage, StorageError};
 use sha2::{Digest, Sha256};
-use std::path::PathBuf;
+use std::collections::HashMap;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock, Weak};
 use tokio::fs;
 use tokio::fs::File;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+/// Prefix of the temporary files `put` writes values to before renaming
+/// them. Every put uses its own.
+pub(crate) const TMPFILE_PREFIX: &str = "tmpfile";
 
 /// Creates a new instance of stable storage.
+///
+/// Concurrent operations on instances sharing `root_storage_dir` are safe
+/// only within one process. Processes sharing the directory aren't
+/// synchronized with each other, so a put and a remove of the same key may
+/// take effect in any order.
 pub async fn build_stable_storage(root_storage_dir: PathBuf) -> Box<dyn StableStorage> {
-    Box::new(POSIXFileSystemStableStorage::new(root_storage_dir))
+    // Instances sharing the directory have to find the same locks.
+    let dir = fs::canonicalize(&root_storage_dir)
+        .await
+        .unwrap_or(root_storage_dir);
+    Box::new(POSIXFileSystemStableStorage::new(dir))
+}
+
+/// Removes temporary files of puts interrupted by a crash. No put may be in
+/// progress in `dir`.
+pub(crate) async fn remove_tmpfiles(dir: &Path) -> io::Result<()> {
+    let mut removed = false;
+    let mut entries = fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        if (entry.file_name().to_str()).is_some_and(|name| name.starts_with(TMPFILE_PREFIX)) {
+            fs::remove_file(entry.path()).await?;
+            removed = true;
+        }
+    }
+    if removed {
+        File::open(dir).await?.sync_data().await?;
+    }
+    Ok(())
+}
+
+/// Lock of the value file at `path`, shared by all instances in the process,
+/// so puts and removes of a key are applied in the order they started. It is
+/// not a file lock, other processes don't see it.
+fn key_lock(path: &Path) -> Arc<Mutex<()>> {
+    static LOCKS: OnceLock<std::sync::Mutex<HashMap<PathBuf, Weak<Mutex<()>>>>> = OnceLock::new();
+    let mut locks = LOCKS.get_or_init(Default::default).lock().unwrap();
+    if let Some(lock) = locks.get(path).and_then(Weak::upgrade) {
+        return lock;
+    }
+    locks.retain(|_, lock| lock.strong_count() > 0);
+    let lock = Arc::new(Mutex::new(()));
+    locks.insert(path.to_path_buf(), Arc::downgrade(&lock));
+    lock
+}
+
+/// Name of the file holding the value of `key`, the key itself can be too
+/// long or contain characters not allowed in file names.
+pub(crate) fn key_filename(key: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(key);
+    base64::encode_config(hasher.finalize(), base64::URL_SAFE_NO_PAD)
+}
+
+/// Values are stored base64 encoded, `None` if the content isn't.
+pub(crate) fn decode_value(content: &[u8]) -> Option<Vec<u8>> {
+    base64::decode(content).ok()
 }
 
 struct POSIXFileSystemStableStorage {
@@ -25,118 +88,157 @@ impl StableStorage for POSIXFileSystemStableStorage {
     /// Stores `value` under `key`.
     ///
     /// Detailed requirements are specified in the description of the assignment.
-    async fn put(&mut self, key: &str, value: &[u8]) -> Result<(), String> {
+    async fn put(&mut self, key: &str, value: &[u8]) -> Result<(), StorageError> {
         if key.len() > 255 {
-            return Err("key too long".to_string());
+            return Err(StorageError::InvalidInput("key too long"));
         }
         if value.len() > 65535 {
-            return Err("value too long".to_string());
+            return Err(StorageError::InvalidInput("value too long"));
         }
-        let mut hasher = Sha256::new();
-
-        hasher.update(key);
-
-        let result = hasher.finalize();
-
-        let filename = base64::encode_config(result, base64::URL_SAFE_NO_PAD);
-
-        let mut filepath = self.dir.clone();
-        filepath.push(filename);
-
-        let mut tmppath = self.dir.clone();
-        tmppath.push("tmpfile");
-
-        let mut tmpfile = File::create(&tmppath).await.unwrap();
+        let filepath = self.dir.join(key_filename(key));
+        let tmppath = (self.dir).join(format!("{}-{}", TMPFILE_PREFIX, Uuid::new_v4().simple()));
 
         let contents = base64::encode(value);
+        let lock = key_lock(&filepath);
+        let _lock = lock.lock().await;
+
+        let written = async {
+            let mut tmpfile = File::create(&tmppath).await?;
+            tmpfile.write_all(contents.as_ref()).await?;
+            tmpfile.sync_data().await?;
+            fs::rename(&tmppath, &filepath).await
+        }
+        .await;
+        if let Err(err) = written {
+            let _ = fs::remove_file(&tmppath).await;
+            return Err(err.into());
+        }
 
-        tmpfile.write_all(contents.as_ref()).await.unwrap();
-
-        tmpfile.sync_data().await.unwrap();
-
-        fs::rename(tmppath, filepath).await.unwrap();
-
-        File::open(&self.dir)
-            .await
-            .unwrap()
-            .sync_data()
-            .await
-            .unwrap();
+        File::open(&self.dir).await?.sync_data().await?;
 
         Ok(())
     }
 
-    /// Retrieves value stored under `key`.
+    /// Retrieves value stored under `key`. A value which can't be decoded
+    /// is reported as `ErrorKind::InvalidData`.
     ///
     /// Detailed requirements are specified in the description of the assignment.
-    async fn get(&self, key: &str) -> Option<Vec<u8>> {
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, StorageError> {
         if key.len() > 255 {
-            return None;
+            return Ok(None);
         }
-        // create a Sha256 object
-        let mut hasher = Sha256::new();
-
-        // write input message
-        hasher.update(key);
-
-        // read hash digest and consume hasher
-        let result = hasher.finalize();
-
-        let filename = base64::encode_config(result, base64::URL_SAFE_NO_PAD);
-
-        let mut filepath = self.dir.clone();
-        filepath.push(filename);
+        let filepath = self.dir.join(key_filename(key));
 
-        let maybe_file = File::open(&filepath).await;
-
-        let mut file = match maybe_file {
+        let mut file = match File::open(&filepath).await {
             Ok(file) => file,
-            Err(error) => match error.kind() {
-                tokio::io::ErrorKind::NotFound => {
-                    return None;
-                }
-                other_error => {
-                    panic!("Problem opening the file: {:?}", other_error);
-                }
-            },
+            Err(error) if error.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error.into()),
         };
 
         let mut content = vec![];
-        file.read_to_end(&mut content).await.unwrap();
+        file.read_to_end(&mut content).await?;
 
-        Some(content)
+        match decode_value(&content) {
+            Some(value) => Ok(Some(value)),
+            None => Err(io::Error::new(ErrorKind::InvalidData, "stored value isn't base64").into()),
+        }
     }
 
     /// Removes `key` and the value stored under it.
     ///
     /// Detailed requirements are specified in the description of the assignment.
-    async fn remove(&mut self, key: &str) -> bool {
+    async fn remove(&mut self, key: &str) -> Result<bool, StorageError> {
         if key.len() > 255 {
-            return false;
+            return Ok(false);
         }
-        // create a Sha256 object
-        let mut hasher = Sha256::new();
-
-        // write input message
-        hasher.update(key);
-
-        // read hash digest and consume hasher
-        let result = hasher.finalize();
+        let filepath = self.dir.join(key_filename(key));
+        let lock = key_lock(&filepath);
+        let _lock = lock.lock().await;
+
+        let removed = match fs::remove_file(filepath).await {
+            Ok(()) => true,
+            Err(error) if error.kind() == ErrorKind::NotFound => false,
+            Err(error) => return Err(error.into()),
+        };
 
-        let filename = base64::encode_config(result, base64::URL_SAFE_NO_PAD);
+        File::open(&self.dir).await?.sync_data().await?;
 
-        let mut filepath = self.dir.clone();
-        filepath.push(filename);
+        Ok(removed)
+    }
+}
 
-        let result = fs::remove_file(filepath).await.is_ok();
+#[tokio::test]
+async fn test_values_survive_reopening() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut storage = build_stable_storage(dir.path().to_path_buf()).await;
+    storage.put("rid", &7u64.to_be_bytes()).await.unwrap();
+    drop(storage);
+
+    let mut storage = build_stable_storage(dir.path().to_path_buf()).await;
+    assert_eq!(
+        storage.get("rid").await.unwrap(),
+        Some(7u64.to_be_bytes().to_vec())
+    );
+    assert!(storage.remove("rid").await.unwrap());
+    assert_eq!(storage.get("rid").await.unwrap(), None);
+}
 
-        File::open(&self.dir)
-            .await
-            .unwrap()
-            .sync_data()
-            .await
-            .unwrap();
+#[tokio::test]
+async fn test_interleaved_operations_of_instances_sharing_a_dir() {
+    const TASKS: u8 = 8;
+    const KEYS: u8 = 4;
+
+    // A value of `key` is `key * 16 + task` repeated, a torn or mixed up
+    // value isn't.
+    let is_valid = |key: u8, value: &[u8]| {
+        !value.is_empty() && value.iter().all(|b| *b == value[0]) && value[0] / 16 == key
+    };
+
+    let dir = tempfile::tempdir().unwrap();
+    let mut tasks = vec![];
+    for task in 0..TASKS {
+        let mut storage = build_stable_storage(dir.path().to_path_buf()).await;
+        tasks.push(tokio::spawn(async move {
+            for i in 0..200usize {
+                let key = (i % KEYS as usize) as u8;
+                let name = format!("key{}", key);
+                match (i + task as usize) % 5 {
+                    0 => {
+                        storage.remove(&name).await.unwrap();
+                    }
+                    1 | 2 => {
+                        let value = vec![key * 16 + task; 1 + (i * 37 + task as usize) % 300];
+                        storage.put(&name, &value).await.unwrap();
+                    }
+                    _ => {
+                        if let Some(value) = storage.get(&name).await.unwrap() {
+                            assert!(is_valid(key, &value), "{} holds {:?}", name, value);
+                        }
+                    }
+                }
+            }
+        }));
+    }
+    for task in tasks {
+        task.await.unwrap();
+    }
 
-        return result;
+    let mut storage = build_stable_storage(dir.path().to_path_buf()).await;
+    for key in 0..KEYS {
+        let name = format!("key{}", key);
+        if let Some(value) = storage.get(&name).await.unwrap() {
+            assert!(is_valid(key, &value));
+        }
+        storage.put(&name, &[key * 16; 3]).await.unwrap();
+        assert_eq!(storage.get(&name).await.unwrap(), Some(vec![key * 16; 3]));
+    }
+    let mut entries = fs::read_dir(dir.path()).await.unwrap();
+    while let Some(entry) = entries.next_entry().await.unwrap() {
+        let name = entry.file_name().into_string().unwrap();
+        assert!(
+            !name.starts_with(TMPFILE_PREFIX),
+            "{} was left behind",
+            name
+        );
     }
 }
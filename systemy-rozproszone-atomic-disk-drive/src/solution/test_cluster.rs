@@ -0,0 +1,193 @@
+//! Cluster of register processes connected by an in-memory network, for tests
+//! of whole processes, and a client speaking to them.
+use crate::solution::transfer;
+use crate::*;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::{self, Duration};
+
+const CLIENT_KEY: [u8; 32] = [7; 32];
+/// Commands taking longer are considered stuck.
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(20);
+
+pub(crate) fn location(rank: u8) -> Location {
+    Location::Tcp(format!("node-{}", rank), 5000)
+}
+
+fn client_location() -> Location {
+    Location::from(("client", 0))
+}
+
+/// Configuration of process `self_rank` of a cluster of `n` processes, with
+/// timings short enough for tests.
+pub(crate) fn configuration(storage_dir: &Path, self_rank: u8, n: u8) -> Configuration {
+    Configuration {
+        hmac_system_key: [3; 64],
+        hmac_client_key: CLIENT_KEY,
+        sectors_key: None,
+        public: PublicConfiguration {
+            storage_dir: storage_dir.to_path_buf(),
+            tcp_locations: (1..=n).map(location).collect(),
+            self_rank,
+            n_sectors: 64,
+            network: NetworkConfiguration {
+                resend_interval: Duration::from_millis(50),
+                max_resend_interval: Duration::from_millis(200),
+                heartbeat_interval: Duration::from_millis(20),
+                anti_entropy_interval: Some(Duration::from_millis(50)),
+                ..Default::default()
+            },
+            storage: Default::default(),
+        },
+    }
+}
+
+pub(crate) struct Cluster {
+    pub(crate) network: MemoryNetwork,
+    pub(crate) dirs: Vec<tempfile::TempDir>,
+    pub(crate) handles: Vec<RegisterProcessHandle>,
+}
+
+impl Cluster {
+    /// Starts `n` processes with default options, faults are drawn from
+    /// `seed`.
+    pub(crate) async fn start(seed: u64, n: u8) -> Self {
+        Self::start_with_options(seed, n, RegisterProcessOptions::default).await
+    }
+
+    /// Options other than the transport are taken from `options`.
+    pub(crate) async fn start_with_options(
+        seed: u64,
+        n: u8,
+        options: impl Fn() -> RegisterProcessOptions,
+    ) -> Self {
+        let network = MemoryNetwork::with_seed(seed);
+        let dirs: Vec<_> = (0..n).map(|_| tempfile::tempdir().unwrap()).collect();
+        let mut handles = vec![];
+        for (i, dir) in dirs.iter().enumerate() {
+            let config = configuration(dir.path(), (i + 1) as u8, n);
+            let options = RegisterProcessOptions {
+                transport: Arc::new(network.transport(location((i + 1) as u8))),
+                ..options()
+            };
+            handles.push(
+                start_register_process_with_options(config, options)
+                    .await
+                    .unwrap(),
+            );
+        }
+        Cluster {
+            network,
+            dirs,
+            handles,
+        }
+    }
+
+    /// Connection of a client to process `rank`, over a link without faults.
+    pub(crate) async fn connect(&self, rank: u8) -> Box<dyn TransportStream> {
+        let client = client_location();
+        (self.network).set_link_faults(&client, &location(rank), FaultConfig::default());
+        self.network
+            .transport(client)
+            .connect(&location(rank))
+            .await
+            .unwrap()
+    }
+
+    /// Path of a sector file of process `rank`, if it has a single sector
+    /// written.
+    pub(crate) fn sector_file(&self, rank: u8) -> Option<PathBuf> {
+        let dir = self.dirs[rank as usize - 1].path().join("sectors_manager");
+        let mut sector_files = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| !path.to_string_lossy().contains("tmpfile"))
+            // Content followed by its checksum.
+            .filter(|path| std::fs::metadata(path).is_ok_and(|m| m.len() == 4096 + 4));
+        let sector_file = sector_files.next();
+        assert!(sector_files.next().is_none());
+        sector_file
+    }
+}
+
+pub(crate) fn write(
+    request_identifier: u64,
+    sector_idx: SectorIdx,
+    data: &SectorVec,
+) -> ClientRegisterCommand {
+    ClientRegisterCommand {
+        header: ClientCommandHeader {
+            request_identifier,
+            sector_idx,
+        },
+        content: ClientRegisterCommandContent::Write { data: data.clone() },
+    }
+}
+
+pub(crate) fn read(request_identifier: u64, sector_idx: SectorIdx) -> ClientRegisterCommand {
+    ClientRegisterCommand {
+        header: ClientCommandHeader {
+            request_identifier,
+            sector_idx,
+        },
+        content: ClientRegisterCommandContent::Read,
+    }
+}
+
+/// Sends a client command and returns the data of the response.
+pub(crate) async fn execute(
+    stream: &mut Box<dyn TransportStream>,
+    cmd: ClientRegisterCommand,
+) -> Option<SectorVec> {
+    try_execute(stream, cmd).await.unwrap()
+}
+
+/// Like `execute`, but returns the status code of a failed command.
+pub(crate) async fn try_execute(
+    stream: &mut Box<dyn TransportStream>,
+    cmd: ClientRegisterCommand,
+) -> Result<Option<SectorVec>, u8> {
+    time::timeout(COMMAND_TIMEOUT, send_command(stream, cmd))
+        .await
+        .expect("Command got stuck")
+}
+
+async fn send_command(
+    stream: &mut Box<dyn TransportStream>,
+    cmd: ClientRegisterCommand,
+) -> Result<Option<SectorVec>, u8> {
+    let is_read = matches!(
+        cmd.content,
+        ClientRegisterCommandContent::Read | ClientRegisterCommandContent::ReadAt { .. }
+    );
+    let request_identifier = cmd.header.request_identifier;
+    let mut buf = vec![];
+    transfer::serialize_register_command(&RegisterCommand::Client(cmd), &mut buf, &CLIENT_KEY)
+        .await
+        .unwrap();
+    stream.write_all(&buf).await.unwrap();
+
+    let mut response = vec![0; 16];
+    stream.read_exact(&mut response).await.unwrap();
+    let status = response[6];
+    if status == StatusCode::Ok as u8 && is_read {
+        response.resize(16 + 4096, 0);
+        stream.read_exact(&mut response[16..]).await.unwrap();
+    }
+    let mut mac = [0; 32];
+    stream.read_exact(&mut mac).await.unwrap();
+    let mut expected = Hmac::<Sha256>::new_from_slice(&CLIENT_KEY).unwrap();
+    expected.update(&response);
+    assert!(expected.verify_slice(&mac).is_ok());
+    assert_eq!(&response[8..16], &request_identifier.to_be_bytes());
+    if status != StatusCode::Ok as u8 {
+        Err(status)
+    } else if is_read {
+        Ok(Some(SectorVec(response[16..].to_vec())))
+    } else {
+        Ok(None)
+    }
+}
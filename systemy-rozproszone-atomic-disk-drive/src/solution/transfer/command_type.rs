@@ -13,6 +13,7 @@ pub(crate) enum CommandType {
 pub(crate) enum ClientCommandType {
     Read = 0x01,
     Write = 0x02,
+    ReadAt = 0x0a,
 }
 
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -21,14 +22,18 @@ pub(crate) enum SystemCommandType {
     Value = 0x04,
     WriteProc = 0x05,
     Ack = 0x06,
+    Heartbeat = 0x07,
+    RangeDigest = 0x08,
+    RangeVersions = 0x09,
 }
 
 impl ClientCommandType {
     pub(crate) fn try_new(value: u8) -> Option<ClientCommandType> {
-        type CCT = ClientCommandType;
+        type Cct = ClientCommandType;
         match value {
-            x if x == (CCT::Read as u8) => Some(CCT::Read),
-            x if x == (CCT::Write as u8) => Some(CCT::Write),
+            x if x == (Cct::Read as u8) => Some(Cct::Read),
+            x if x == (Cct::Write as u8) => Some(Cct::Write),
+            x if x == (Cct::ReadAt as u8) => Some(Cct::ReadAt),
             _ => None,
         }
     }
@@ -37,18 +42,22 @@ impl ClientCommandType {
         match command.content {
             ClientRegisterCommandContent::Read => ClientCommandType::Read,
             ClientRegisterCommandContent::Write { .. } => ClientCommandType::Write,
+            ClientRegisterCommandContent::ReadAt { .. } => ClientCommandType::ReadAt,
         }
     }
 }
 
 impl SystemCommandType {
     pub(crate) fn try_new(value: u8) -> Option<SystemCommandType> {
-        type SCT = SystemCommandType;
+        type Sct = SystemCommandType;
         match value {
-            x if x == (SCT::ReadProc as u8) => Some(SCT::ReadProc),
-            x if x == (SCT::Value as u8) => Some(SCT::Value),
-            x if x == (SCT::WriteProc as u8) => Some(SCT::WriteProc),
-            x if x == (SCT::Ack as u8) => Some(SCT::Ack),
+            x if x == (Sct::ReadProc as u8) => Some(Sct::ReadProc),
+            x if x == (Sct::Value as u8) => Some(Sct::Value),
+            x if x == (Sct::WriteProc as u8) => Some(Sct::WriteProc),
+            x if x == (Sct::Ack as u8) => Some(Sct::Ack),
+            x if x == (Sct::Heartbeat as u8) => Some(Sct::Heartbeat),
+            x if x == (Sct::RangeDigest as u8) => Some(Sct::RangeDigest),
+            x if x == (Sct::RangeVersions as u8) => Some(Sct::RangeVersions),
             _ => None,
         }
     }
@@ -58,26 +67,28 @@ impl CommandType {
     pub(crate) fn try_new(value: u8) -> Option<CommandType> {
         if let Some(cct) = ClientCommandType::try_new(value) {
             Some(CommandType::Client(cct))
-        } else if let Some(sct) = SystemCommandType::try_new(value) {
-            Some(CommandType::System(sct))
         } else {
-            None
+            SystemCommandType::try_new(value).map(CommandType::System)
         }
     }
 
     pub(crate) fn new_from_command(command: &RegisterCommand) -> CommandType {
-        type CRCC = ClientRegisterCommandContent;
-        type SRCC = SystemRegisterCommandContent;
+        type Crcc = ClientRegisterCommandContent;
+        type Srcc = SystemRegisterCommandContent;
         match command {
             RegisterCommand::Client(crc) => match crc.content {
-                CRCC::Read => CommandType::Client(ClientCommandType::Read),
-                CRCC::Write { .. } => CommandType::Client(ClientCommandType::Write),
+                Crcc::Read => CommandType::Client(ClientCommandType::Read),
+                Crcc::Write { .. } => CommandType::Client(ClientCommandType::Write),
+                Crcc::ReadAt { .. } => CommandType::Client(ClientCommandType::ReadAt),
             },
             RegisterCommand::System(src) => match src.content {
-                SRCC::ReadProc => CommandType::System(SystemCommandType::ReadProc),
-                SRCC::Value { .. } => CommandType::System(SystemCommandType::Value),
-                SRCC::WriteProc { .. } => CommandType::System(SystemCommandType::WriteProc),
-                SRCC::Ack => CommandType::System(SystemCommandType::Ack),
+                Srcc::ReadProc => CommandType::System(SystemCommandType::ReadProc),
+                Srcc::Value { .. } => CommandType::System(SystemCommandType::Value),
+                Srcc::WriteProc { .. } => CommandType::System(SystemCommandType::WriteProc),
+                Srcc::Ack => CommandType::System(SystemCommandType::Ack),
+                Srcc::Heartbeat => CommandType::System(SystemCommandType::Heartbeat),
+                Srcc::RangeDigest { .. } => CommandType::System(SystemCommandType::RangeDigest),
+                Srcc::RangeVersions { .. } => CommandType::System(SystemCommandType::RangeVersions),
             },
         }
     }
@@ -86,6 +97,7 @@ impl CommandType {
         match opret {
             OperationReturn::Read(..) => ClientCommandType::Read,
             OperationReturn::Write => ClientCommandType::Write,
+            OperationReturn::ReadAt(..) => ClientCommandType::ReadAt,
         }
     }
 
@@ -124,7 +136,23 @@ fn test_command_type_constructor() {
         Some(CommandType::System(SystemCommandType::Ack)),
         CommandType::try_new(0x06)
     );
-    assert_eq!(None, CommandType::try_new(0x07));
+    assert_eq!(
+        Some(CommandType::System(SystemCommandType::Heartbeat)),
+        CommandType::try_new(0x07)
+    );
+    assert_eq!(
+        Some(CommandType::System(SystemCommandType::RangeDigest)),
+        CommandType::try_new(0x08)
+    );
+    assert_eq!(
+        Some(CommandType::System(SystemCommandType::RangeVersions)),
+        CommandType::try_new(0x09)
+    );
+    assert_eq!(
+        Some(CommandType::Client(ClientCommandType::ReadAt)),
+        CommandType::try_new(0x0a)
+    );
+    assert_eq!(None, CommandType::try_new(0x0b));
 
     assert_eq!(None, CommandType::try_new(0x41));
 }
@@ -37,7 +37,7 @@ impl MessageHeader {
     ) -> Self {
         MessageHeader {
             padding: 0,
-            auxiliary: (status_code.clone() as u8),
+            auxiliary: (*status_code as u8),
             message_type: (client_command_type.clone() as u8) + RESPONSE_BIT,
         }
     }
@@ -1,3 +1,6 @@
+//! De/serialization of messages. Every message is written to the stream with a
+//! single write, so transports working on messages (like `MemoryNetwork`) can
+//! rely on write boundaries.
 pub(crate) mod command_type;
 pub(crate) mod message_header;
 pub(crate) mod utils;
@@ -14,6 +17,8 @@ use uuid::Uuid;
 type HmacSha256 = Hmac<Sha256>;
 
 pub(crate) const SECTOR_LEN: usize = 4096;
+/// Maximal number of sectors covered by a single anti-entropy message.
+pub(crate) const MAX_RANGE_LEN: u64 = 4096;
 
 pub(crate) async fn deserialize_register_command(
     data: &mut (dyn AsyncRead + std::marker::Send + Unpin),
@@ -35,7 +40,7 @@ pub(crate) async fn deserialize_register_command(
     mac.update(&byte_view);
     let mut buf = [0; 32];
     data.read_exact(&mut buf).await?;
-    return Ok((command, mac.verify_slice(&buf).is_ok()));
+    Ok((command, mac.verify_slice(&buf).is_ok()))
 }
 
 pub(crate) async fn serialize_register_command(
@@ -53,8 +58,8 @@ pub(crate) async fn serialize_register_command(
     }
     let mut mac = HmacSha256::new_from_slice(hmac_key).unwrap();
     mac.update(&byte_view);
+    byte_view.extend_from_slice(&mac.finalize().into_bytes());
     writer.write_all(&byte_view).await?;
-    writer.write_all(&mac.finalize().into_bytes()).await?;
     Ok(())
 }
 
@@ -72,13 +77,12 @@ pub(crate) async fn deserialize_response_failure(
     {
         let writer: &mut (dyn AsyncWrite + std::marker::Send + Unpin) = &mut byte_view;
         writer.write_all(&MAGIC_NUMBER).await?;
-        writer.write(&header.to_be_bytes().await).await?;
+        writer.write_all(&header.to_be_bytes().await).await?;
         writer.write_u64(request_number).await?;
     }
     mac.update(&byte_view);
-    let result = mac.finalize().into_bytes();
+    byte_view.extend_from_slice(&mac.finalize().into_bytes());
     writer.write_all(&byte_view).await?;
-    writer.write_all(&result).await?;
     Ok(())
 }
 
@@ -95,19 +99,21 @@ pub(crate) async fn deserialize_response_success(
     {
         let writer: &mut (dyn AsyncWrite + std::marker::Send + Unpin) = &mut byte_view;
         writer.write_all(&MAGIC_NUMBER).await?;
-        writer.write(&header.to_be_bytes().await).await?;
+        writer.write_all(&header.to_be_bytes().await).await?;
         writer.write_u64(request_number).await?;
         if let OperationReturn::Read(ReadReturn {
             read_data: SectorVec(data),
+        })
+        | OperationReturn::ReadAt(ReadReturn {
+            read_data: SectorVec(data),
         }) = &opret
         {
             writer.write_all(data).await?;
         }
     }
     mac.update(&byte_view);
-    let result = mac.finalize().into_bytes();
-    writer.write(&byte_view).await?;
-    writer.write_all(&result).await?;
+    byte_view.extend_from_slice(&mac.finalize().into_bytes());
+    writer.write_all(&byte_view).await?;
     Ok(())
 }
 
@@ -115,8 +121,8 @@ async fn read_command(
     data: &mut (dyn AsyncRead + std::marker::Send + Unpin),
     header: &MessageHeader,
 ) -> Result<RegisterCommand, Error> {
-    type CRCC = ClientRegisterCommandContent;
-    type SRCC = SystemRegisterCommandContent;
+    type Crcc = ClientRegisterCommandContent;
+    type Srcc = SystemRegisterCommandContent;
     match header.command_type().unwrap() {
         CommandType::Client(cct) => Ok(RegisterCommand::Client(ClientRegisterCommand {
             header: ClientCommandHeader {
@@ -124,10 +130,13 @@ async fn read_command(
                 sector_idx: data.read_u64().await?,
             },
             content: match cct {
-                ClientCommandType::Read => CRCC::Read,
-                ClientCommandType::Write => CRCC::Write {
+                ClientCommandType::Read => Crcc::Read,
+                ClientCommandType::Write => Crcc::Write {
                     data: read_sector_vec(data).await?,
                 },
+                ClientCommandType::ReadAt => Crcc::ReadAt {
+                    timestamp: data.read_u64().await?,
+                },
             },
         })),
         CommandType::System(sct) => Ok(RegisterCommand::System(SystemRegisterCommand {
@@ -138,18 +147,26 @@ async fn read_command(
                 sector_idx: data.read_u64().await?,
             },
             content: match sct {
-                SystemCommandType::ReadProc => SRCC::ReadProc,
-                SystemCommandType::Value => SRCC::Value {
+                SystemCommandType::ReadProc => Srcc::ReadProc,
+                SystemCommandType::Value => Srcc::Value {
                     timestamp: data.read_u64().await?,
                     write_rank: (data.read_u64().await? as u8),
                     sector_data: read_sector_vec(data).await?,
                 },
-                SystemCommandType::WriteProc => SRCC::WriteProc {
+                SystemCommandType::WriteProc => Srcc::WriteProc {
                     timestamp: data.read_u64().await?,
                     write_rank: (data.read_u64().await? as u8),
                     data_to_write: read_sector_vec(data).await?,
                 },
-                SystemCommandType::Ack => SRCC::Ack,
+                SystemCommandType::Ack => Srcc::Ack,
+                SystemCommandType::Heartbeat => Srcc::Heartbeat,
+                SystemCommandType::RangeDigest => Srcc::RangeDigest {
+                    range_len: data.read_u64().await?,
+                    digest: data.read_u64().await?,
+                },
+                SystemCommandType::RangeVersions => Srcc::RangeVersions {
+                    versions: read_versions(data).await?,
+                },
             },
         })),
     }
@@ -163,6 +180,27 @@ async fn read_sector_vec(
     Ok(SectorVec(sector_data))
 }
 
+async fn read_versions(
+    data: &mut (dyn AsyncRead + std::marker::Send + Unpin),
+) -> Result<Vec<(SectorIdx, u64, u8)>, Error> {
+    let count = data.read_u64().await?;
+    if count > MAX_RANGE_LEN {
+        return Err(Error::new(
+            std::io::ErrorKind::InvalidData,
+            "Too many sector versions",
+        ));
+    }
+    let mut versions = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        versions.push((
+            data.read_u64().await?,
+            data.read_u64().await?,
+            data.read_u64().await? as u8,
+        ));
+    }
+    Ok(versions)
+}
+
 async fn read_uuid(data: &mut (dyn AsyncRead + std::marker::Send + Unpin)) -> Result<Uuid, Error> {
     let mut buf = [0; 16];
     data.read_exact(&mut buf).await?;
@@ -173,17 +211,20 @@ async fn write_command(
     writer: &mut (dyn AsyncWrite + std::marker::Send + Unpin),
     cmd: &RegisterCommand,
 ) -> Result<(), Error> {
-    type SRCC = SystemRegisterCommandContent;
-    type CRCC = ClientRegisterCommandContent;
+    type Srcc = SystemRegisterCommandContent;
+    type Crcc = ClientRegisterCommandContent;
     match cmd {
         RegisterCommand::Client(ClientRegisterCommand { header, content }) => {
             writer.write_u64(header.request_identifier).await?;
             writer.write_u64(header.sector_idx).await?;
             match content {
-                CRCC::Read => {}
-                CRCC::Write { data } => {
+                Crcc::Read => {}
+                Crcc::Write { data } => {
                     write_sector_vec(writer, data).await?;
                 }
+                Crcc::ReadAt { timestamp } => {
+                    writer.write_u64(*timestamp).await?;
+                }
             }
         }
         RegisterCommand::System(SystemRegisterCommand { header, content }) => {
@@ -191,8 +232,8 @@ async fn write_command(
             writer.write_u64(header.read_ident).await?;
             writer.write_u64(header.sector_idx).await?;
             match content {
-                SRCC::ReadProc => {}
-                SRCC::Value {
+                Srcc::ReadProc => {}
+                Srcc::Value {
                     timestamp,
                     write_rank,
                     sector_data,
@@ -201,7 +242,7 @@ async fn write_command(
                     writer.write_u64((*write_rank) as u64).await?;
                     write_sector_vec(writer, sector_data).await?;
                 }
-                SRCC::WriteProc {
+                Srcc::WriteProc {
                     timestamp,
                     write_rank,
                     data_to_write,
@@ -210,7 +251,19 @@ async fn write_command(
                     writer.write_u64((*write_rank) as u64).await?;
                     write_sector_vec(writer, data_to_write).await?;
                 }
-                SRCC::Ack => {}
+                Srcc::Ack | Srcc::Heartbeat => {}
+                Srcc::RangeDigest { range_len, digest } => {
+                    writer.write_u64(*range_len).await?;
+                    writer.write_u64(*digest).await?;
+                }
+                Srcc::RangeVersions { versions } => {
+                    writer.write_u64(versions.len() as u64).await?;
+                    for (sector_idx, timestamp, write_rank) in versions {
+                        writer.write_u64(*sector_idx).await?;
+                        writer.write_u64(*timestamp).await?;
+                        writer.write_u64(*write_rank as u64).await?;
+                    }
+                }
             }
         }
     }
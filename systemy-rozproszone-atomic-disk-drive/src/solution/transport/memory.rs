@@ -0,0 +1,365 @@
+//! In-process network, meant for tests. Every write to a stream is treated as
+//! a single message, and messages can be dropped, delayed, duplicated and
+//! reordered, or links can be partitioned, under control of the test. Built
+//! for tests of this crate, and for other crates with the `testing` feature.
+use crate::*;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::time::{self, Duration};
+
+/// Faults applied to every message sent over a link.
+#[derive(Clone, Debug, Default)]
+pub struct FaultConfig {
+    /// Probability that a message is lost.
+    pub drop_probability: f64,
+    /// Probability that a message is delivered twice.
+    pub duplicate_probability: f64,
+    /// Probability that a message is held back, so later messages overtake it.
+    pub reorder_probability: f64,
+    /// Every message is delayed by a random duration between those two.
+    pub min_delay: Duration,
+    pub max_delay: Duration,
+}
+
+struct NetworkState {
+    listeners: HashMap<Location, UnboundedSender<Box<dyn TransportStream>>>,
+    faults: FaultConfig,
+    /// Keyed by links as returned by `link`, override `faults`.
+    link_faults: HashMap<(Location, Location), FaultConfig>,
+    partitioned: HashSet<(Location, Location)>,
+    rng: StdRng,
+}
+
+/// Links are undirected, so both directions share faults and partitions.
+fn link(a: &Location, b: &Location) -> (Location, Location) {
+    if a <= b {
+        (a.clone(), b.clone())
+    } else {
+        (b.clone(), a.clone())
+    }
+}
+
+impl NetworkState {
+    /// Delays after which copies of a message sent from `src` to `dst` are
+    /// delivered. Empty if the message is lost.
+    fn deliveries(&mut self, src: &Location, dst: &Location) -> Vec<Duration> {
+        let link = link(src, dst);
+        if self.partitioned.contains(&link) {
+            return vec![];
+        }
+        let faults = self.link_faults.get(&link).unwrap_or(&self.faults).clone();
+        let rng = &mut self.rng;
+        if rng.gen_bool(faults.drop_probability.clamp(0.0, 1.0)) {
+            return vec![];
+        }
+        let copies = if rng.gen_bool(faults.duplicate_probability.clamp(0.0, 1.0)) {
+            2
+        } else {
+            1
+        };
+        (0..copies)
+            .map(|_| {
+                let mut delay = if faults.max_delay > faults.min_delay {
+                    rng.gen_range(faults.min_delay..=faults.max_delay)
+                } else {
+                    faults.min_delay
+                };
+                if rng.gen_bool(faults.reorder_probability.clamp(0.0, 1.0)) {
+                    delay += faults.max_delay.max(Duration::from_millis(1)) * 2;
+                }
+                delay
+            })
+            .collect()
+    }
+}
+
+/// Shared by all transports created from it, cheap to clone.
+#[derive(Clone)]
+pub struct MemoryNetwork {
+    state: Arc<Mutex<NetworkState>>,
+}
+
+impl Default for MemoryNetwork {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryNetwork {
+    pub fn new() -> Self {
+        Self::with_seed(rand::random())
+    }
+
+    /// Faults are drawn from a generator seeded with `seed`.
+    pub fn with_seed(seed: u64) -> Self {
+        MemoryNetwork {
+            state: Arc::new(Mutex::new(NetworkState {
+                listeners: HashMap::new(),
+                faults: FaultConfig::default(),
+                link_faults: HashMap::new(),
+                partitioned: HashSet::new(),
+                rng: StdRng::seed_from_u64(seed),
+            })),
+        }
+    }
+
+    /// Transport for the process (or client) at `location`.
+    pub fn transport(&self, location: impl Into<Location>) -> MemoryTransport {
+        MemoryTransport {
+            network: self.clone(),
+            location: location.into(),
+        }
+    }
+
+    /// Sets faults of all links without their own faults.
+    pub fn set_faults(&self, faults: FaultConfig) {
+        self.state.lock().unwrap().faults = faults;
+    }
+
+    /// Sets faults of the link between `a` and `b`.
+    pub fn set_link_faults(&self, a: &Location, b: &Location, faults: FaultConfig) {
+        self.state
+            .lock()
+            .unwrap()
+            .link_faults
+            .insert(link(a, b), faults);
+    }
+
+    /// Cuts every link between a location of `side_a` and one of `side_b`.
+    pub fn partition(&self, side_a: &[Location], side_b: &[Location]) {
+        let mut state = self.state.lock().unwrap();
+        for a in side_a {
+            for b in side_b {
+                state.partitioned.insert(link(a, b));
+            }
+        }
+    }
+
+    /// Restores all links cut by `partition`.
+    pub fn heal(&self) {
+        self.state.lock().unwrap().partitioned.clear();
+    }
+
+    fn is_partitioned(&self, a: &Location, b: &Location) -> bool {
+        self.state.lock().unwrap().partitioned.contains(&link(a, b))
+    }
+
+    fn open_stream(&self, src: &Location, dst: &Location) -> (MemoryStream, MemoryStream) {
+        let (src_out_tx, src_out_rx) = mpsc::unbounded_channel();
+        let (src_in_tx, src_in_rx) = mpsc::unbounded_channel();
+        let (dst_out_tx, dst_out_rx) = mpsc::unbounded_channel();
+        let (dst_in_tx, dst_in_rx) = mpsc::unbounded_channel();
+        tokio::spawn(run_pump(
+            self.clone(),
+            src.clone(),
+            dst.clone(),
+            src_out_rx,
+            dst_in_tx,
+        ));
+        tokio::spawn(run_pump(
+            self.clone(),
+            dst.clone(),
+            src.clone(),
+            dst_out_rx,
+            src_in_tx,
+        ));
+        (
+            MemoryStream::new(src_in_rx, src_out_tx),
+            MemoryStream::new(dst_in_rx, dst_out_tx),
+        )
+    }
+}
+
+/// Moves messages written on one end of a stream to the other end.
+async fn run_pump(
+    network: MemoryNetwork,
+    src: Location,
+    dst: Location,
+    mut rx: UnboundedReceiver<Vec<u8>>,
+    tx: UnboundedSender<Vec<u8>>,
+) {
+    while let Some(message) = rx.recv().await {
+        let deliveries = network.state.lock().unwrap().deliveries(&src, &dst);
+        for delay in deliveries {
+            if delay.is_zero() {
+                let _ = tx.send(message.clone());
+            } else {
+                let tx = tx.clone();
+                let message = message.clone();
+                tokio::spawn(async move {
+                    time::sleep(delay).await;
+                    let _ = tx.send(message);
+                });
+            }
+        }
+    }
+}
+
+pub struct MemoryTransport {
+    network: MemoryNetwork,
+    location: Location,
+}
+
+struct MemoryListener {
+    rx: UnboundedReceiver<Box<dyn TransportStream>>,
+}
+
+#[async_trait::async_trait]
+impl Transport for MemoryTransport {
+    async fn connect(&self, location: &Location) -> io::Result<Box<dyn TransportStream>> {
+        let refused = || io::Error::from(io::ErrorKind::ConnectionRefused);
+        let listener = self
+            .network
+            .state
+            .lock()
+            .unwrap()
+            .listeners
+            .get(location)
+            .cloned()
+            .ok_or_else(refused)?;
+        if self.network.is_partitioned(&self.location, location) {
+            return Err(refused());
+        }
+        let (local, remote) = self.network.open_stream(&self.location, location);
+        listener.send(Box::new(remote)).map_err(|_| refused())?;
+        Ok(Box::new(local))
+    }
+
+    async fn bind(&self, location: &Location) -> io::Result<Box<dyn TransportListener>> {
+        let mut state = self.network.state.lock().unwrap();
+        if let Some(listener) = state.listeners.get(location) {
+            if !listener.is_closed() {
+                return Err(io::ErrorKind::AddrInUse.into());
+            }
+        }
+        let (tx, rx) = mpsc::unbounded_channel();
+        state.listeners.insert(location.clone(), tx);
+        Ok(Box::new(MemoryListener { rx }))
+    }
+}
+
+#[async_trait::async_trait]
+impl TransportListener for MemoryListener {
+    async fn accept(&mut self) -> io::Result<Box<dyn TransportStream>> {
+        self.rx
+            .recv()
+            .await
+            .ok_or_else(|| io::ErrorKind::NotConnected.into())
+    }
+}
+
+struct MemoryStream {
+    incoming: UnboundedReceiver<Vec<u8>>,
+    /// Message being read and the position of the first unread byte.
+    buffer: Vec<u8>,
+    position: usize,
+    outgoing: Option<UnboundedSender<Vec<u8>>>,
+}
+
+impl MemoryStream {
+    fn new(incoming: UnboundedReceiver<Vec<u8>>, outgoing: UnboundedSender<Vec<u8>>) -> Self {
+        MemoryStream {
+            incoming,
+            buffer: vec![],
+            position: 0,
+            outgoing: Some(outgoing),
+        }
+    }
+}
+
+impl AsyncRead for MemoryStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            if self.position < self.buffer.len() {
+                let len = buf.remaining().min(self.buffer.len() - self.position);
+                buf.put_slice(&self.buffer[self.position..self.position + len]);
+                self.position += len;
+                return Poll::Ready(Ok(()));
+            }
+            match self.incoming.poll_recv(cx) {
+                Poll::Ready(Some(message)) => {
+                    self.buffer = message;
+                    self.position = 0;
+                }
+                // End of stream.
+                Poll::Ready(None) => return Poll::Ready(Ok(())),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl AsyncWrite for MemoryStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match &self.outgoing {
+            Some(tx) if tx.send(buf.to_vec()).is_ok() => Poll::Ready(Ok(buf.len())),
+            _ => Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.outgoing = None;
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[tokio::test]
+async fn test_chaos_lossy_network_keeps_register_atomic() {
+    use crate::solution::test_cluster::*;
+
+    let cluster = Cluster::start(2137, 3).await;
+    cluster.network.set_faults(FaultConfig {
+        drop_probability: 0.2,
+        duplicate_probability: 0.2,
+        reorder_probability: 0.2,
+        min_delay: Duration::from_millis(0),
+        max_delay: Duration::from_millis(5),
+    });
+    let mut writer = cluster.connect(1).await;
+    let mut reader = cluster.connect(3).await;
+
+    for i in 0..10u8 {
+        let sector_idx = (i % 4) as u64;
+        let data = SectorVec(vec![i; 4096]);
+        execute(&mut writer, write(i as u64, sector_idx, &data)).await;
+        let read_data = execute(&mut reader, read(i as u64, sector_idx)).await;
+        assert_eq!(read_data, Some(data));
+    }
+}
+
+#[tokio::test]
+async fn test_chaos_minority_partition_does_not_block_majority() {
+    use crate::solution::test_cluster::*;
+
+    let cluster = Cluster::start(7, 3).await;
+    (cluster.network).partition(&[location(3)], &[location(1), location(2)]);
+
+    let mut stream = cluster.connect(1).await;
+    let data = SectorVec(vec![42; 4096]);
+    execute(&mut stream, write(1, 5, &data)).await;
+    time::sleep(Duration::from_millis(500)).await;
+    assert_eq!(cluster.handles[0].suspected_processes(), vec![3]);
+
+    cluster.network.heal();
+    let mut stream = cluster.connect(3).await;
+    assert_eq!(execute(&mut stream, read(2, 5)).await, Some(data));
+}
@@ -0,0 +1,7 @@
+#[cfg(any(test, feature = "testing"))]
+mod memory;
+mod socket;
+
+#[cfg(any(test, feature = "testing"))]
+pub use memory::{FaultConfig, MemoryNetwork, MemoryTransport};
+pub use socket::SocketTransport;
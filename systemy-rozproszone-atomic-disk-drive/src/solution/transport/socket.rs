@@ -0,0 +1,133 @@
+use crate::*;
+use std::io;
+use tokio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+
+/// Transport over TCP and Unix domain sockets of the operating system.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SocketTransport;
+
+enum SocketListener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+#[cfg(not(unix))]
+fn unix_unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Unix domain sockets are not supported on this platform",
+    )
+}
+
+/// A socket file left by a process which is not running anymore makes `bind`
+/// fail, so it is removed when nobody listens on it. Any other file at `path`
+/// is left alone, and `bind` fails.
+#[cfg(unix)]
+async fn bind_unix(path: &std::path::Path) -> io::Result<UnixListener> {
+    use std::os::unix::fs::FileTypeExt;
+
+    match UnixListener::bind(path) {
+        Err(err) if err.kind() == io::ErrorKind::AddrInUse => {
+            let is_socket = tokio::fs::symlink_metadata(path)
+                .await
+                .is_ok_and(|metadata| metadata.file_type().is_socket());
+            if !is_socket {
+                return Err(err);
+            }
+            match UnixStream::connect(path).await {
+                Err(probe) if probe.kind() == io::ErrorKind::ConnectionRefused => {
+                    tokio::fs::remove_file(path).await?;
+                    UnixListener::bind(path)
+                }
+                _ => Err(err),
+            }
+        }
+        result => result,
+    }
+}
+
+#[async_trait::async_trait]
+impl Transport for SocketTransport {
+    async fn connect(&self, location: &Location) -> io::Result<Box<dyn TransportStream>> {
+        match location {
+            Location::Tcp(host, port) => {
+                Ok(Box::new(TcpStream::connect((host.as_str(), *port)).await?))
+            }
+            #[cfg(unix)]
+            Location::Unix(path) => Ok(Box::new(UnixStream::connect(path).await?)),
+            #[cfg(not(unix))]
+            Location::Unix(_) => Err(unix_unsupported()),
+        }
+    }
+
+    async fn bind(&self, location: &Location) -> io::Result<Box<dyn TransportListener>> {
+        match location {
+            Location::Tcp(host, port) => Ok(Box::new(SocketListener::Tcp(
+                TcpListener::bind((host.as_str(), *port)).await?,
+            ))),
+            #[cfg(unix)]
+            Location::Unix(path) => Ok(Box::new(SocketListener::Unix(bind_unix(path).await?))),
+            #[cfg(not(unix))]
+            Location::Unix(_) => Err(unix_unsupported()),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl TransportListener for SocketListener {
+    async fn accept(&mut self) -> io::Result<Box<dyn TransportStream>> {
+        match self {
+            SocketListener::Tcp(listener) => {
+                let (stream, _) = listener.accept().await?;
+                Ok(Box::new(stream))
+            }
+            #[cfg(unix)]
+            SocketListener::Unix(listener) => {
+                let (stream, _) = listener.accept().await?;
+                Ok(Box::new(stream))
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_unix_socket_rebinds_over_stale_socket() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let dir = tempfile::tempdir().unwrap();
+    let location = Location::Unix(dir.path().join("node.sock"));
+    let transport = SocketTransport;
+
+    // Dropping the listener leaves the socket file behind.
+    drop(transport.bind(&location).await.unwrap());
+    let mut listener = transport.bind(&location).await.unwrap();
+
+    let mut client = transport.connect(&location).await.unwrap();
+    let mut server = listener.accept().await.unwrap();
+    client.write_all(b"atdd").await.unwrap();
+    let mut buf = [0; 4];
+    server.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"atdd");
+    // The socket of a running process is not taken over.
+    assert!(transport.bind(&location).await.is_err());
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_unix_socket_does_not_replace_other_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("config.toml");
+    std::fs::write(&path, b"not a socket").unwrap();
+
+    let err = SocketTransport
+        .bind(&Location::Unix(path.clone()))
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+    assert_eq!(std::fs::read(&path).unwrap(), b"not a socket");
+}